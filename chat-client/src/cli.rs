@@ -8,6 +8,9 @@ pub(crate) enum Cli {
     Login,
     Register,
     Handshake,
+    /// Starts a session with the given user, who may be offline.
+    Session(Arc<str>),
+    Ping,
     Text(Arc<str>),
 }
 
@@ -17,6 +20,8 @@ pub(crate) fn ask_for_command() -> Result<Cli> {
     println!("          ':login'");
     println!("          ':register'");
     println!("          ':handshake'");
+    println!("          ':session <username>'");
+    println!("          ':ping'");
 
     let cmd = process_input()?;
     if let Cli::Text(_) = cmd {
@@ -38,7 +43,13 @@ pub(crate) fn process_input() -> Result<Cli> {
         ":login" => Ok(Cli::Login),
         ":register" => Ok(Cli::Register),
         ":handshake" => Ok(Cli::Handshake),
-        _ => Err(Error::generic("Wrong command")),
+        ":ping" => Ok(Cli::Ping),
+        _ => match input.strip_prefix(":session ") {
+            Some(username) if !username.trim().is_empty() => {
+                Ok(Cli::Session(Arc::from(username.trim())))
+            }
+            _ => Err(Error::generic("Wrong command")),
+        },
     }
 }
 
@@ -0,0 +1,186 @@
+//! Identity keys of servers connected to before, trusted on first use
+//!
+//! Every line of the file is `<address> <identity key>`, much like
+//! `~/.ssh/known_hosts`.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use chat_core::prelude::*;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::network::Address;
+
+/// Used when `KNOWN_SERVERS` isn't set.
+pub(crate) const DEFAULT_PATH: &str = "known_servers";
+
+#[derive(Debug, Clone)]
+pub(crate) struct KnownServers {
+    path: PathBuf,
+}
+
+impl KnownServers {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Checks `identity` against the key pinned for `addr`, or pins it if the
+    /// server is new.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if another key is pinned for `addr`,
+    /// i.e. the server changed its key or someone is in the middle.
+    pub(crate) fn verify(&self, addr: &Address, identity: &PublicKey) -> Result<()> {
+        match self.lookup(addr)? {
+            Some(pinned) if pinned == *identity => {
+                debug!("Identity of {} matches the pinned one", addr);
+                Ok(())
+            }
+            Some(pinned) => {
+                error!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                error!("@    WARNING: SERVER IDENTITY HAS CHANGED!                @");
+                error!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                error!("Someone could be eavesdropping on you right now!");
+                error!("Pinned identity of {}: {}", addr, pinned);
+                error!("Identity it presented now:  {}", identity);
+                error!(
+                    "If the server's key was replaced on purpose, remove its line from {}",
+                    self.path.display()
+                );
+                Err(Error::crypto(format!("Identity of {addr} has changed")))
+            }
+            None => {
+                self.pin(addr, identity)?;
+                warn!(
+                    "{} is not known yet, trusting it on first use; identity = {}",
+                    addr, identity
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Identity pinned for `addr`, if the server was seen before.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be read.
+    pub(crate) fn lookup(&self, addr: &Address) -> Result<Option<PublicKey>> {
+        let addr = addr.to_string();
+        let known = match fs::read_to_string(&self.path) {
+            Ok(known) => known,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::io(err)),
+        };
+        // Keys never contain spaces, addresses (Unix socket paths) might.
+        known
+            .lines()
+            .filter_map(|line| line.trim().rsplit_once(' '))
+            .find(|(known, _)| *known == addr)
+            .map(|(_, key)| PublicKey::try_decode(key))
+            .transpose()
+    }
+
+    fn pin(&self, addr: &Address, identity: &PublicKey) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(Error::io)?;
+        writeln!(file, "{addr} {identity}").map_err(Error::io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Known servers in a file of their own, removed again when dropped.
+    struct Known(KnownServers);
+
+    impl Known {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "chat-client-known-servers-{name}-{}",
+                std::process::id()
+            ));
+            let _ = fs::remove_file(&path);
+            Self(KnownServers::new(path))
+        }
+    }
+
+    impl Drop for Known {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(self.0.path());
+        }
+    }
+
+    fn identity() -> PublicKey {
+        *KeyPair::new_signing().public()
+    }
+
+    #[test]
+    fn trust_on_first_use() {
+        let known = Known::new("first-use");
+        let addr: Address = "127.0.0.1:8080".parse().unwrap();
+        let identity = identity();
+        assert_eq!(None, known.0.lookup(&addr).unwrap());
+
+        known.0.verify(&addr, &identity).unwrap();
+        assert_eq!(Some(identity), known.0.lookup(&addr).unwrap());
+        known.0.verify(&addr, &identity).unwrap();
+        assert_eq!(
+            1,
+            fs::read_to_string(known.0.path()).unwrap().lines().count()
+        );
+    }
+
+    #[test]
+    fn changed_identity() {
+        let known = Known::new("changed");
+        let addr: Address = "127.0.0.1:8080".parse().unwrap();
+        let pinned = identity();
+        known.0.verify(&addr, &pinned).unwrap();
+
+        assert!(matches!(
+            known.0.verify(&addr, &identity()),
+            Err(Error::Crypto(_))
+        ));
+        // The pinned key stays until it's removed by hand.
+        assert_eq!(Some(pinned), known.0.lookup(&addr).unwrap());
+    }
+
+    #[test]
+    fn addresses() {
+        let known = Known::new("addresses");
+        let tcp: Address = "127.0.0.1:8080".parse().unwrap();
+        let quic: Address = "quic:127.0.0.1:8080".parse().unwrap();
+        let unix: Address = "unix:/tmp/chat server.sock".parse().unwrap();
+        let (tcp_identity, unix_identity) = (identity(), identity());
+        known.0.verify(&tcp, &tcp_identity).unwrap();
+        known.0.verify(&unix, &unix_identity).unwrap();
+
+        // Every transport of a host is pinned on its own.
+        assert_eq!(None, known.0.lookup(&quic).unwrap());
+        assert_eq!(Some(tcp_identity), known.0.lookup(&tcp).unwrap());
+        assert_eq!(Some(unix_identity), known.0.lookup(&unix).unwrap());
+    }
+
+    #[test]
+    fn malformed() {
+        let known = Known::new("malformed");
+        let addr: Address = "127.0.0.1:8080".parse().unwrap();
+        fs::write(known.0.path(), "\nno-key\n127.0.0.1:8080 not-a-key\n").unwrap();
+        assert!(known.0.lookup(&addr).is_err());
+        assert!(known.0.verify(&addr, &identity()).is_err());
+    }
+}
@@ -8,29 +8,100 @@
     unreachable_pub
 )]
 
-use std::net::SocketAddr;
-
-use color_eyre::Result;
+use chat_core::{
+    crypto::{self, KeyPair},
+    event::DynSchema,
+    keepalive::Keepalive,
+    protocol::Capabilities,
+    tls::{self, ServerName},
+    Then,
+};
+use color_eyre::{eyre::bail, Result};
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 
 mod cli;
+mod known_servers;
 mod network;
+mod prekeys;
 mod types;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     setup()?;
 
-    let addr: SocketAddr = std::env::var("ADDRESS")
+    let addr: network::Address = std::env::var("ADDRESS")
         .expect("Environment variable `ADDRESS` must be set.")
         .parse()?;
 
-    network::handle_connection(&addr).await?;
+    // Restricts the serialization backend offered to the server, every other
+    // feature is still offered.
+    let capabilities = match std::env::var("EVENT_SCHEMA") {
+        Ok(schema) => {
+            Capabilities::supported().with_schema(schema.parse::<DynSchema>()?.capability())
+        }
+        Err(_) => Capabilities::supported(),
+    };
+
+    let known_servers = std::env::var("KNOWN_SERVERS")
+        .unwrap_or_else(|_| known_servers::DEFAULT_PATH.to_owned())
+        .then(known_servers::KnownServers::new);
+    let prekeys = std::env::var("CLIENT_PREKEYS")
+        .unwrap_or_else(|_| prekeys::DEFAULT_PATH.to_owned())
+        .then(prekeys::Prekeys::new);
+
+    network::handle_connection(
+        &addr,
+        capabilities,
+        Keepalive::from_env()?,
+        tls()?,
+        identity()?,
+        &known_servers,
+        prekeys,
+    )
+    .await?;
 
     Ok(())
 }
 
+/// Reads `TLS_CA` or `TLS_PIN`, TLS is off if neither is set.
+///
+/// `TLS_CA` verifies the server against a CA, `TLS_PIN` accepts exactly the
+/// given certificate. `TLS_SERVER_NAME` defaults to the IP the client connects to.
+fn tls() -> Result<Option<network::Tls>> {
+    let config = match (std::env::var("TLS_CA"), std::env::var("TLS_PIN")) {
+        (Ok(_), Ok(_)) => bail!("Only one of `TLS_CA` and `TLS_PIN` may be set."),
+        (Ok(ca), Err(_)) => tls::client_config_with_ca(tls::load_certs(ca)?)?,
+        (Err(_), Ok(pin)) => {
+            let pinned = tls::load_certs(pin)?.swap_remove(0);
+            tls::client_config_with_pinned(pinned)?
+        }
+        (Err(_), Err(_)) => return Ok(None),
+    };
+    let server_name = match std::env::var("TLS_SERVER_NAME") {
+        Ok(name) => Some(ServerName::try_from(name)?),
+        Err(_) => None,
+    };
+    Ok(Some(network::Tls {
+        config,
+        server_name,
+    }))
+}
+
+/// Reads the identity key at `CLIENT_IDENTITY_KEY`, generates one on the first
+/// start.
+fn identity() -> Result<KeyPair> {
+    let path =
+        std::env::var("CLIENT_IDENTITY_KEY").unwrap_or_else(|_| "client_identity.key".to_owned());
+    match crypto::load_identity(&path)? {
+        Some(identity) => Ok(identity),
+        None => {
+            info!("Generating a new identity key at {}", path);
+            Ok(crypto::generate_identity(&path)?)
+        }
+    }
+}
+
 fn setup() -> Result<()> {
     dotenvy::dotenv().expect(".env file not found");
     color_eyre::install()?;
@@ -1,37 +1,184 @@
-use std::net::SocketAddr;
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
 
 use futures::{future, SinkExt};
-use tokio::net::TcpStream;
-use tokio_util::codec::{BytesCodec, Framed, FramedRead, FramedWrite};
+use tokio::net::{TcpStream, UnixStream};
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 
 use chat_core::{
-    event::{AuthenticationStatus, RegistrationStatus},
+    event::{AuthenticationStatus, PrekeyBundle, RegistrationStatus},
+    handshake,
+    keepalive::Keepalive,
     prelude::*,
+    protocol::Capabilities,
+    quic,
+    tls::{ClientConfig, ServerName, TlsConnector},
+    transport::framed,
+    websocket::{self, Uri},
 };
 
 use crate::{
     cli::{ask_for_command, ask_for_credentials, Cli},
+    known_servers::KnownServers,
+    prekeys::{Prekeys, ONE_TIME_PREKEYS},
     types::{Client, ThreadCommunication},
 };
 
 mod recieve;
 mod send;
 
-pub(crate) async fn handle_connection(addr: &SocketAddr) -> Result<()> {
-    let tcp_stream = TcpStream::connect(addr).await.map_err(Error::io)?;
+/// Where the server listens: `host:port`, `unix:/path/to/socket`,
+/// `quic:host:port` or a `ws://` / `wss://` URL.
+#[derive(Debug, Clone)]
+pub(crate) enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    WebSocket(Uri),
+    Quic(SocketAddr),
+}
+
+/// TLS settings for TCP and QUIC connections.
+#[derive(Debug, Clone)]
+pub(crate) struct Tls {
+    pub(crate) config: ClientConfig,
+    /// Name the server certificate is checked against.
+    pub(crate) server_name: Option<ServerName<'static>>,
+}
+
+impl Tls {
+    async fn connect(
+        &self,
+        stream: TcpStream,
+        default_name: ServerName<'static>,
+    ) -> Result<Box<dyn Transport>> {
+        let name = self.server_name.clone().unwrap_or(default_name);
+        let connector = TlsConnector::from(Arc::new(self.config.clone()));
+        let stream = connector.connect(name, stream).await.map_err(Error::io)?;
+        Ok(Box::new(stream))
+    }
+}
+
+impl Address {
+    /// Plain TCP connections are wrapped in `tls` if it is set, WebSocket ones
+    /// only for `wss://`.
+    async fn connect(&self, tls: Option<&Tls>) -> Result<Box<dyn Frames>> {
+        let frames = match self {
+            Self::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await.map_err(Error::io)?;
+                match tls {
+                    Some(tls) => framed(tls.connect(stream, addr.ip().into()).await?),
+                    None => framed(stream),
+                }
+            }
+            Self::Unix(path) => framed(UnixStream::connect(path).await.map_err(Error::io)?),
+            Self::WebSocket(uri) => {
+                let host = uri.host().unwrap_or_default();
+                let secure = uri.scheme_str() == Some("wss");
+                let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+                let stream = TcpStream::connect((host, port)).await.map_err(Error::io)?;
+                let transport = match (secure, tls) {
+                    (false, _) => Box::new(stream),
+                    (true, Some(tls)) => {
+                        let name = ServerName::try_from(host.to_owned()).map_err(Error::generic)?;
+                        tls.connect(stream, name).await?
+                    }
+                    (true, None) => return Err(Error::generic("wss:// needs TLS_CA or TLS_PIN")),
+                };
+                Box::new(websocket::connect(uri, transport).await?)
+            }
+            Self::Quic(addr) => {
+                let tls = tls.ok_or_else(|| Error::generic("QUIC needs TLS_CA or TLS_PIN"))?;
+                let name = match tls.server_name {
+                    Some(ref name) => name.to_str().into_owned(),
+                    None => addr.ip().to_string(),
+                };
+                Box::new(quic::connect(*addr, &name, tls.config.clone()).await?)
+            }
+        };
+        Ok(frames)
+    }
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(path.into()));
+        }
+        if let Some(addr) = s.strip_prefix("quic:") {
+            return addr.parse().map(Self::Quic).map_err(Error::generic);
+        }
+        if s.starts_with("ws://") || s.starts_with("wss://") {
+            let uri: Uri = s.parse().map_err(Error::generic)?;
+            if uri.host().is_none() {
+                return Err(Error::generic("WebSocket URL is missing a host"));
+            }
+            return Ok(Self::WebSocket(uri));
+        }
+        s.parse().map(Self::Tcp).map_err(Error::generic)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::WebSocket(uri) => write!(f, "{uri}"),
+            Self::Quic(addr) => write!(f, "quic:{addr}"),
+        }
+    }
+}
+
+pub(crate) async fn handle_connection(
+    addr: &Address,
+    capabilities: Capabilities,
+    keepalive: Keepalive,
+    tls: Option<Tls>,
+    identity: KeyPair,
+    known_servers: &KnownServers,
+    prekeys: Prekeys,
+) -> Result<()> {
+    let mut stream = addr.connect(tls.as_ref()).await?;
     info!("Connected to {}", addr);
-    let mut stream = Framed::new(tcp_stream, BytesCodec::new());
 
     let (username, password) = ask_for_credentials()?;
-    let mut client = Client::new(username, password);
+    let mut client = Client::new(username, password, keepalive, identity, prekeys);
 
-    let shared_key =
-        chat_core::key_exchange(&mut stream, client.event().clone(), client.crypto()).await?;
-    info!("Shared secret with server was negotiated");
-    debug!(SharedSecret = chat_core::crypto::key_to_emojies(&shared_key));
-    client.set_shared_secret(shared_key);
+    // A known server has to prove it still owns the pinned identity.
+    let pinned = known_servers.lookup(addr)?;
+    let negotiated = handshake::initiate(
+        &mut stream,
+        capabilities,
+        client.crypto(),
+        client.identity(),
+        pinned.as_ref(),
+    )
+    .await
+    .on_err(|_| {
+        if pinned.is_some() {
+            error!(
+                "The handshake with {} failed, the server may have changed its identity",
+                addr
+            );
+            error!(
+                "If it did so on purpose, remove its line from {}",
+                known_servers.path().display()
+            );
+        }
+    })?;
+    info!(
+        "Keys with server were negotiated; protocol version = {}, event schema = {:?}",
+        negotiated.version(),
+        negotiated.event()
+    );
+    debug!(capabilities = ?negotiated.capabilities());
+    // Nothing secret is sent before the server proved it is the one we know.
+    known_servers.verify(addr, negotiated.remote_identity())?;
+
+    client.set_server_keys(negotiated.keys().clone());
+    client.set_event(negotiated.event().clone());
 
     let cmd = ask_for_command()?;
     match cmd {
@@ -41,11 +188,10 @@ pub(crate) async fn handle_connection(addr: &SocketAddr) -> Result<()> {
         _ => return Err(Error::generic("expected :login or :register")),
     }
     info!("Authenticated");
+    let hybrid = negotiated.capabilities().contains(Capabilities::HYBRID);
+    publish_prekeys(&mut stream, &client, hybrid).await?;
 
-    let tcp_stream = stream.into_inner();
-    let (r, w) = tcp_stream.into_split();
-    let stream = FramedRead::new(r, BytesCodec::new());
-    let sink = FramedWrite::new(w, BytesCodec::new());
+    let (sink, stream) = Frames::split(stream);
 
     let (comm1, comm2) = ThreadCommunication::new();
     future::try_join(
@@ -56,19 +202,43 @@ pub(crate) async fn handle_connection(addr: &SocketAddr) -> Result<()> {
     Ok(())
 }
 
-async fn register(stream: &mut Framed<TcpStream, BytesCodec>, client: &Client) -> Result<()> {
+/// Lets other clients start sessions with this one while it's offline, with
+/// an ML-KEM prekey if `hybrid`.
+async fn publish_prekeys<F: Frames>(stream: &mut F, client: &Client, hybrid: bool) -> Result<()> {
+    let (signed, one_time, kem) = client.prekeys().generate(ONE_TIME_PREKEYS, hybrid)?;
+    let bundle = PrekeyBundle::signed(
+        &client.crypto(),
+        client.identity(),
+        *signed.public(),
+        one_time.iter().map(|prekey| *prekey.public()).collect(),
+        kem.map(|kem| kem.public().clone()),
+    );
+    let event = EventBuilder::construct(client.event().clone(), client.crypto())
+        .prekey_upload(bundle)
+        .seal(&mut client.server_keys())?
+        .then(|e| bytes::BytesMut::from(e.as_slice()));
+
+    stream.send(event).await.map_err(Error::io)?;
+    debug!("Published {} one-time prekeys", one_time.len());
+    Ok(())
+}
+
+async fn register<F: Frames>(stream: &mut F, client: &Client) -> Result<()> {
     trace!("Initiating registration");
     let event = EventBuilder::construct(client.event().clone(), client.crypto())
         .registration_request(client.username(), client.password())
-        .encrypt(client.shared_secret())?
+        .seal(&mut client.server_keys())?
         .then(|e| bytes::BytesMut::from(e.as_slice()));
 
     stream.send(event).await.map_err(Error::io)?;
     let recieved = chat_core::recieve(stream).await?;
 
     let deconstructed = EventBuilder::deconstruct(client.event().clone(), client.crypto())
-        .decrypt(client.shared_secret(), &recieved)?;
+        .open(&mut client.server_keys(), &recieved)?;
     let deserialized = deconstructed.deserialize()?;
+    if let Ok(err) = deserialized.expect_error() {
+        return Err(err.into());
+    }
     let response = deserialized.expect_registration_response()?;
 
     let status = response.status();
@@ -79,19 +249,22 @@ async fn register(stream: &mut Framed<TcpStream, BytesCodec>, client: &Client) -
     Ok(())
 }
 
-async fn authenticate(stream: &mut Framed<TcpStream, BytesCodec>, client: &Client) -> Result<()> {
+async fn authenticate<F: Frames>(stream: &mut F, client: &Client) -> Result<()> {
     trace!("Initiating authentication");
     let event = EventBuilder::construct(client.event().clone(), client.crypto())
         .authentication_request(client.username(), client.password())
-        .encrypt(client.shared_secret())?
+        .seal(&mut client.server_keys())?
         .then(|e| bytes::BytesMut::from(e.as_slice()));
 
     stream.send(event).await.map_err(Error::io)?;
     let recieved = chat_core::recieve(stream).await?;
 
     let deconstructed = EventBuilder::deconstruct(client.event().clone(), client.crypto())
-        .decrypt(client.shared_secret(), &recieved)?;
+        .open(&mut client.server_keys(), &recieved)?;
     let deserialized = deconstructed.deserialize()?;
+    if let Ok(err) = deserialized.expect_error() {
+        return Err(err.into());
+    }
     let response = deserialized.expect_authentication_response()?;
 
     let status = response.status();
@@ -1,16 +1,22 @@
 use std::borrow::Cow;
 
 use futures::StreamExt;
-use tokio::net::tcp::OwnedReadHalf;
-use tokio_util::codec::{BytesCodec, FramedRead};
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 
-use chat_core::prelude::*;
+use chat_core::{
+    crypto::{x3dh, DoubleRatchet, SenderChain, SenderKeyDistribution},
+    event::{
+        Entity, ErrorEvent, Handshake, KeyDistribution, Message, Ping, Pong, PrekeyResponse,
+        Presence, SessionInit,
+    },
+    prelude::*,
+    transport::FrameStream,
+};
 
-use crate::types::{Client, SessionSecret, ThreadCommunication};
+use crate::types::{Client, Session, SessionSecret, ThreadCommunication};
 
-type Stream = FramedRead<OwnedReadHalf, BytesCodec>;
+type Stream = Box<dyn FrameStream>;
 
 pub(crate) async fn recieve(
     mut stream: Stream,
@@ -55,18 +61,70 @@ fn process_recieved(
     comm: &ThreadCommunication,
 ) -> Result<()> {
     let deconstructed = EventBuilder::deconstruct(client.event().clone(), client.crypto())
-        .decrypt(client.shared_secret(), recieved)?;
+        .open(&mut client.server_keys(), recieved)?;
     let deserialized = deconstructed.deserialize()?;
 
-    let timestamp = deserialized.timestamp();
-    match deserialized.kind() {
-        EventKind::Handshake(kind) => process_handshake(client, comm, *kind.pub_key())?,
-        EventKind::Registration(_) => todo!(),
-        EventKind::Authentication(_) => todo!(),
-        EventKind::Message(kind) => process_message(client, *timestamp, kind)?,
+    Recieved { client, comm }.dispatch(&deserialized)
+}
+
+/// Events other clients send once this one has logged in.
+struct Recieved<'a> {
+    client: &'a mut Client,
+    comm: &'a ThreadCommunication,
+}
+
+impl EventHandler for Recieved<'_> {
+    type Output = ();
+
+    fn on_handshake(&mut self, _: &Entity<'_>, event: &Handshake) -> Result<()> {
+        process_handshake(self.client, self.comm, *event.pub_key())
     }
 
-    Ok(())
+    fn on_message(&mut self, entity: &Entity<'_>, event: &Message<'_>) -> Result<()> {
+        process_message(self.client, *entity.timestamp(), event)
+    }
+
+    fn on_ping(&mut self, _: &Entity<'_>, event: &Ping) -> Result<()> {
+        let pongs = self.client.liveness().pongs_tx();
+        pongs.send(*event.nonce()).map_err(Error::generic)
+    }
+
+    fn on_pong(&mut self, _: &Entity<'_>, event: &Pong) -> Result<()> {
+        let latency = self.client.liveness().keepalive().pong(*event.nonce());
+        if let Some(latency) = latency {
+            debug!("Round-trip latency: {:?}", latency);
+        }
+        Ok(())
+    }
+
+    fn on_prekey_response(&mut self, _: &Entity<'_>, event: &PrekeyResponse<'_>) -> Result<()> {
+        initiate_session(self.client, self.comm, event)
+    }
+
+    fn on_session_init(&mut self, _: &Entity<'_>, event: &SessionInit<'_>) -> Result<()> {
+        join_session(self.client, event)
+    }
+
+    fn on_key_distribution(&mut self, _: &Entity<'_>, event: &KeyDistribution<'_>) -> Result<()> {
+        process_key_distribution(self.client, event)
+    }
+
+    fn on_presence(&mut self, _: &Entity<'_>, event: &Presence<'_>) -> Result<()> {
+        process_presence(self.client, event)
+    }
+
+    fn on_error(&mut self, _: &Entity<'_>, event: &ErrorEvent<'_>) -> Result<()> {
+        Err(event.into())
+    }
+
+    chat_core::unexpected_events!(
+        on_registration_request,
+        on_registration_response,
+        on_authentication_request,
+        on_authentication_response,
+        on_prekey_upload,
+        on_prekey_request,
+    );
 }
 
 fn process_handshake(
@@ -76,55 +134,198 @@ fn process_handshake(
 ) -> Result<()> {
     info!("Another client wants to contribute to a new encryption key for this session");
     match client.session_secret() {
-        SessionSecret::None => {
-            let (my_sec, my_pub) = KeyPair::new_dh().into_split();
-            let pending = SessionSecret::PendingToSend(my_pub);
+        SessionSecret::None | SessionSecret::Established(_) => {
+            if matches!(client.session_secret(), SessionSecret::Established(_)) {
+                info!("The current session is going to be replaced");
+            }
+            let own = KeyPair::new_dh();
+            let pending = SessionSecret::PendingToSend(*own.public());
             comm.tx.send(pending).map_err(Error::generic)?;
 
-            let established = make_established_and_share(client, comm, &my_sec, &public_key)?;
+            let shared_secret = client.crypto().compute_dh(own.secret(), &public_key);
+            let ratchet = DoubleRatchet::responder(&shared_secret, own);
+            let established = make_established_and_share(comm, &shared_secret, ratchet)?;
             client.set_session_secret(established);
         }
         SessionSecret::PendingForShared(secret_key) => {
-            let established = make_established_and_share(client, comm, secret_key, &public_key)?;
+            let shared_secret = client.crypto().compute_dh(secret_key, &public_key);
+            let ratchet = DoubleRatchet::initiator(&client.crypto(), &shared_secret, public_key);
+            let established = make_established_and_share(comm, &shared_secret, ratchet)?;
             client.set_session_secret(established);
         }
-        SessionSecret::PendingToSend(_) => unreachable!(),
-        SessionSecret::Established(_) => todo!(),
+        SessionSecret::PendingToSend(_) | SessionSecret::PendingToInit(_) => unreachable!(),
     }
 
     Ok(())
 }
 
-fn make_established_and_share(
+/// Starts a session from the prekey bundle of another client, which may be
+/// offline, and sends it the keys it needs to join.
+fn initiate_session(
+    client: &mut Client,
+    comm: &ThreadCommunication,
+    event: &PrekeyResponse<'_>,
+) -> Result<()> {
+    let Some(bundle) = event.bundle() else {
+        warn!("{} hasn't published any prekeys", event.username());
+        return Ok(());
+    };
+    let one_time_prekey = bundle.one_time_prekeys().first();
+    if one_time_prekey.is_none() {
+        warn!("{} has run out of one-time prekeys", event.username());
+    }
+    let (shared_secret, ephemeral, kem_ciphertext) = x3dh::initiate(
+        &client.crypto(),
+        client.identity(),
+        bundle.identity_key(),
+        bundle.signed_prekey(),
+        bundle.signature(),
+        one_time_prekey,
+        bundle
+            .kem_prekey()
+            .map(|prekey| (prekey.key(), prekey.signature())),
+    )?;
+    info!(
+        "Starting a session with {}; identity = {}",
+        event.username(),
+        bundle.identity_key()
+    );
+
+    let init = SessionInit::new(
+        client.username().to_owned().into(),
+        event.username().to_owned().into(),
+        *client.identity().public(),
+        ephemeral,
+        *bundle.signed_prekey(),
+        one_time_prekey.copied(),
+        kem_ciphertext,
+    );
+    let ratchet =
+        DoubleRatchet::initiator(&client.crypto(), &shared_secret, *bundle.signed_prekey());
+    join_group(client, event.username(), &shared_secret, ratchet);
+
+    // The sending thread hands out the sender key once the init is sent.
+    comm.tx
+        .send(SessionSecret::PendingToInit(init))
+        .map_err(Error::generic)
+}
+
+/// Joins a session another client started from the prekeys of this one.
+fn join_session(client: &Client, event: &SessionInit<'_>) -> Result<()> {
+    info!(
+        "{} started a session with this client; identity = {}",
+        event.sender(),
+        event.identity_key()
+    );
+    let (signed_prekey, one_time_prekey, kem) = client.prekeys().take(
+        event.signed_prekey(),
+        event.one_time_prekey(),
+        event.kem_ciphertext().is_some(),
+    )?;
+    let shared_secret = x3dh::respond(
+        &client.crypto(),
+        client.identity(),
+        &signed_prekey,
+        one_time_prekey.as_ref(),
+        kem.as_ref().zip(event.kem_ciphertext()),
+        event.identity_key(),
+        event.ephemeral_key(),
+    )?;
+
+    let ratchet = DoubleRatchet::responder(&shared_secret, signed_prekey);
+    join_group(client, event.sender(), &shared_secret, ratchet);
+    client.group().distribute()
+}
+
+fn join_group(
     client: &Client,
+    username: &str,
+    shared_secret: &SharedSecret,
+    ratchet: DoubleRatchet,
+) {
+    info!("{} joined the group, rotating the sender key", username);
+    debug!(SessionSecret = chat_core::crypto::key_to_emojies(shared_secret));
+    client
+        .group()
+        .members()
+        .join(username.to_owned(), Session::new(ratchet));
+}
+
+/// Stores the sender key of another member.
+fn process_key_distribution(client: &Client, event: &KeyDistribution<'_>) -> Result<()> {
+    if event.recipient() != client.username() {
+        return Err(Error::unexpected("sender key of someone else"));
+    }
+    let mut members = client.group().members();
+    let session = members
+        .session(event.sender())
+        .ok_or_else(|| Error::crypto(format!("No session with {}", event.sender())))?
+        .clone();
+    let decoded = chat_core::crypto::base64_decode(event.ciphertext())?;
+    let decrypted = session.ratchet().decrypt(&client.crypto(), &decoded)?;
+    let chain = SenderChain::new(SenderKeyDistribution::from_bytes(&decrypted)?);
+
+    info!(
+        "Got the sender key of {}; generation = {}",
+        event.sender(),
+        chain.generation()
+    );
+    members.set_chain(event.sender().to_owned(), chain);
+    Ok(())
+}
+
+fn process_presence(client: &Client, event: &Presence<'_>) -> Result<()> {
+    let group = client.group();
+    if *event.online() {
+        // It may have been offline when the sender key was handed out.
+        if group.members().session(event.username()).is_some() {
+            group
+                .distributions_tx()
+                .send(event.username().to_owned())
+                .map_err(Error::generic)?;
+        }
+        return Ok(());
+    }
+    if group.members().leave(event.username()) {
+        info!(
+            "{} left the group, rotating the sender key",
+            event.username()
+        );
+        group.distribute()?;
+    }
+    Ok(())
+}
+
+fn make_established_and_share(
     comm: &ThreadCommunication,
-    secret_key: &SecretKey,
-    public_key: &PublicKey,
+    shared_secret: &SharedSecret,
+    ratchet: DoubleRatchet,
 ) -> Result<SessionSecret> {
-    let shared_secret = client.crypto().compute_dh(secret_key, public_key);
     info!("Shared secret for this session was negotiated");
-    debug!(SessionSecret = chat_core::crypto::key_to_emojies(&shared_secret));
+    debug!(SessionSecret = chat_core::crypto::key_to_emojies(shared_secret));
 
-    let established = SessionSecret::Established(shared_secret);
+    let established = SessionSecret::Established(Session::new(ratchet));
     comm.tx.send(established.clone()).map_err(Error::generic)?;
     Ok(established)
 }
 
-fn process_message(
-    client: &Client,
-    timestamp: i64,
-    event: &chat_core::event::Message<'_>,
-) -> Result<()> {
-    let text: Cow<'_, str> =
-        if let SessionSecret::Established(shared_secret) = client.session_secret() {
-            let decoded = chat_core::crypto::base64_decode(event.text())?;
-            let decrypted_text = client.crypto().decrypt(shared_secret, &decoded)?;
-            String::from_utf8(decrypted_text)
-                .map_err(Error::generic)?
-                .into()
-        } else {
-            event.text().into()
-        };
+fn process_message(client: &Client, timestamp: i64, event: &Message<'_>) -> Result<()> {
+    let mut members = client.group().members();
+    let text: Cow<'_, str> = if let Some(chain) = members.chain_mut(event.sender()) {
+        let decoded = chat_core::crypto::base64_decode(event.text())?;
+        let decrypted_text = chain.decrypt(&client.crypto(), &decoded)?;
+        String::from_utf8(decrypted_text)
+            .map_err(Error::generic)?
+            .into()
+    } else if let SessionSecret::Established(session) = client.session_secret() {
+        let decoded = chat_core::crypto::base64_decode(event.text())?;
+        let decrypted_text = session.ratchet().decrypt(&client.crypto(), &decoded)?;
+        String::from_utf8(decrypted_text)
+            .map_err(Error::generic)?
+            .into()
+    } else {
+        event.text().into()
+    };
 
     let timestamp = from_timestamp(timestamp)?;
     println!("{}: {}: {}", timestamp, event.sender(), text);
@@ -134,7 +335,9 @@ fn process_message(
 fn from_timestamp(timestamp: i64) -> Result<String> {
     use chrono::prelude::*;
 
-    let time = DateTime::from_timestamp(timestamp, 0)
+    let secs = timestamp.div_euclid(1000);
+    let nsecs = timestamp.rem_euclid(1000) as u32 * 1_000_000;
+    let time = DateTime::from_timestamp(secs, nsecs)
         .ok_or_else(|| Error::generic("Timestamp is out of range"))?;
     Ok(format!("{}", time.format("%H:%M")))
 }
@@ -1,19 +1,21 @@
 use std::borrow::Cow;
 
 use futures::{FutureExt, SinkExt};
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio_util::codec::{BytesCodec, FramedWrite};
+use tokio::time::{self, Instant, Interval};
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 
-use chat_core::prelude::*;
+use chat_core::{
+    prelude::*,
+    transport::{FrameSink, Lane, LOBBY},
+};
 
 use crate::{
     cli::{self, Cli},
     types::{Client, SessionSecret, ThreadCommunication},
 };
 
-type Stream = FramedWrite<OwnedWriteHalf, BytesCodec>;
+type Stream = Box<dyn FrameSink>;
 
 pub(crate) async fn send(
     mut sink: Stream,
@@ -21,10 +23,16 @@ pub(crate) async fn send(
     comm: ThreadCommunication,
 ) -> Result<()> {
     let thread = tokio::spawn(async move {
+        let period = client.liveness().keepalive().interval();
+        let mut pings = time::interval_at(Instant::now() + period, period);
         loop {
-            match select(&mut sink, &mut client, &comm).await {
+            match select(&mut sink, &mut client, &comm, &mut pings).await {
                 Ok(()) => (),
                 Err(Error::Shutdown) => return Ok(()),
+                Err(Error::Timeout) => {
+                    error!("Server stopped answering pings");
+                    return Err(Error::Timeout);
+                }
                 Err(err) => warn!("{}", err),
             }
         }
@@ -36,6 +44,7 @@ async fn select(
     stream: &mut Stream,
     client: &mut Client,
     comm: &ThreadCommunication,
+    pings: &mut Interval,
 ) -> Result<()> {
     let mut thread = tokio::task::spawn_blocking(cli::process_input).fuse();
     loop {
@@ -48,6 +57,11 @@ async fn select(
             // can be safely re-iterated over.
             Ok(session_secret) = comm.rx.recv_async() =>
                 on_recieve_from_recieve_thread(stream, client, session_secret).await?,
+            Ok(nonce) = client.liveness().pongs_rx().recv_async() =>
+                send_pong(stream, client, nonce).await?,
+            Ok(username) = client.group().distributions_rx().recv_async() =>
+                send_sender_key(stream, client, &username).await?,
+            _ = pings.tick() => send_ping(stream, client).await?,
         }
     }
     debug!("Event sent");
@@ -60,23 +74,43 @@ async fn process_input(
     cli: Cli,
     comm: &ThreadCommunication,
 ) -> Result<()> {
-    let event = match cli {
+    let (lane, event) = match cli {
         Cli::Quit => return Err(Error::Shutdown),
         Cli::Text(text) => {
             let text = construct_text(client, text.as_ref())?;
-            EventBuilder::construct(client.event().clone(), client.crypto())
+            let event = EventBuilder::construct(client.event().clone(), client.crypto())
                 .message(client.username(), &text)
-                .encrypt(client.shared_secret())?
+                .seal(&mut client.server_keys())?;
+            (Lane::Conversation(LOBBY.to_owned()), event)
         }
         Cli::Handshake => {
             let key = create_keys(client, comm)?;
-            EventBuilder::construct(client.event().clone(), client.crypto())
+            let event = EventBuilder::construct(client.event().clone(), client.crypto())
                 .handshake(&key)
-                .encrypt(client.shared_secret())?
+                .seal(&mut client.server_keys())?;
+            (Lane::Control, event)
+        }
+        Cli::Session(username) => {
+            let event = EventBuilder::construct(client.event().clone(), client.crypto())
+                .prekey_request(&username)
+                .seal(&mut client.server_keys())?;
+            (Lane::Control, event)
+        }
+        Cli::Ping => {
+            match client.liveness().keepalive().latency() {
+                Some(latency) => println!("Round-trip latency: {:?}", latency),
+                None => println!("Round-trip latency is not measured yet"),
+            }
+            return Ok(());
+        }
+        _ => {
+            return Err(Error::generic(
+                "expected only text, :handshake, :session, :ping or :q",
+            ))
         }
-        _ => return Err(Error::generic("expected only text, :handshake or :q")),
     };
     let event = bytes::BytesMut::from(event.as_slice());
+    stream.route(lane);
     stream.send(event).await.map_err(Error::io)?;
     Ok(())
 }
@@ -91,10 +125,21 @@ async fn on_recieve_from_recieve_thread(
         SessionSecret::PendingToSend(public_key) => {
             EventBuilder::construct(client.event().clone(), client.crypto())
                 .handshake(&public_key)
-                .encrypt(client.shared_secret())?
+                .seal(&mut client.server_keys())?
+        }
+        SessionSecret::PendingToInit(init) => {
+            let lane = Lane::direct(init.sender(), init.recipient());
+            let event = EventBuilder::construct(client.event().clone(), client.crypto())
+                .session_init(init)
+                .seal(&mut client.server_keys())?;
+            let event = bytes::BytesMut::from(event.as_slice());
+            stream.route(lane);
+            stream.send(event).await.map_err(Error::io)?;
+            // The new member can only read the sender key once it has joined.
+            return client.group().distribute();
         }
-        SessionSecret::Established(session_secret) => {
-            client.set_session_secret(SessionSecret::Established(session_secret));
+        SessionSecret::Established(session) => {
+            client.set_session_secret(SessionSecret::Established(session));
             return Ok(());
         }
     };
@@ -103,28 +148,77 @@ async fn on_recieve_from_recieve_thread(
     Ok(())
 }
 
-fn construct_text<'a>(client: &'a Client, text: &'a str) -> Result<Cow<'a, str>> {
-    let text: Cow<'_, str> =
-        if let SessionSecret::Established(shared_secret) = client.session_secret() {
-            let encrypted_text = client.crypto().encrypt(shared_secret, text.as_bytes())?;
-            chat_core::crypto::base64_encode(encrypted_text).into()
-        } else {
-            text.into()
+/// Hands the sender key of this client to `username` over their session.
+async fn send_sender_key(stream: &mut Stream, client: &Client, username: &str) -> Result<()> {
+    let ciphertext = {
+        let mut members = client.group().members();
+        let Some(session) = members.session(username).cloned() else {
+            debug!("{} has left before it got the sender key", username);
+            return Ok(());
         };
+        let distribution = members.own_mut().distribution();
+        trace!(?distribution, "Sending the sender key to {}", username);
+        let encrypted = session
+            .ratchet()
+            .encrypt(&client.crypto(), &distribution.to_bytes())?;
+        chat_core::crypto::base64_encode(encrypted)
+    };
+    let event = EventBuilder::construct(client.event().clone(), client.crypto())
+        .key_distribution(client.username(), username, &ciphertext)
+        .seal(&mut client.server_keys())?;
+    let event = bytes::BytesMut::from(event.as_slice());
+    stream.route(Lane::direct(client.username(), username));
+    stream.send(event).await.map_err(Error::io)
+}
+
+async fn send_ping(stream: &mut Stream, client: &Client) -> Result<()> {
+    let nonce = client.liveness().keepalive().ping()?;
+    let event = EventBuilder::construct(client.event().clone(), client.crypto())
+        .ping(nonce)
+        .seal(&mut client.server_keys())?;
+    let event = bytes::BytesMut::from(event.as_slice());
+    stream.send(event).await.map_err(Error::io)
+}
+
+async fn send_pong(stream: &mut Stream, client: &Client, nonce: u64) -> Result<()> {
+    let event = EventBuilder::construct(client.event().clone(), client.crypto())
+        .pong(nonce)
+        .seal(&mut client.server_keys())?;
+    let event = bytes::BytesMut::from(event.as_slice());
+    stream.send(event).await.map_err(Error::io)
+}
+
+fn construct_text<'a>(client: &'a Client, text: &'a str) -> Result<Cow<'a, str>> {
+    let mut members = client.group().members();
+    let text: Cow<'_, str> = if !members.is_empty() {
+        let encrypted_text = members
+            .own_mut()
+            .encrypt(&client.crypto(), text.as_bytes())?;
+        chat_core::crypto::base64_encode(encrypted_text).into()
+    } else if let SessionSecret::Established(session) = client.session_secret() {
+        let encrypted_text = session
+            .ratchet()
+            .encrypt(&client.crypto(), text.as_bytes())?;
+        chat_core::crypto::base64_encode(encrypted_text).into()
+    } else {
+        text.into()
+    };
     Ok(text)
 }
 
-fn create_keys(client: &Client, comm: &ThreadCommunication) -> Result<CryptoKey> {
+fn create_keys(client: &Client, comm: &ThreadCommunication) -> Result<PublicKey> {
     let event = match client.session_secret() {
-        SessionSecret::None => {
+        // A new handshake replaces the established session.
+        SessionSecret::None | SessionSecret::Established(_) => {
             let (secret_key, public_key) = KeyPair::new_dh().into_split();
             let pending = SessionSecret::PendingForShared(secret_key);
 
             comm.tx.send(pending).map_err(Error::generic)?;
             public_key
         }
-        SessionSecret::PendingForShared(_) | SessionSecret::PendingToSend(_) => unreachable!(),
-        SessionSecret::Established(_) => todo!(),
+        SessionSecret::PendingForShared(_)
+        | SessionSecret::PendingToSend(_)
+        | SessionSecret::PendingToInit(_) => unreachable!(),
     };
     Ok(event)
 }
@@ -0,0 +1,292 @@
+//! Secret halves of the prekeys this client published, see
+//! [`chat_core::crypto::x3dh`]
+//!
+//! Every line of the file is `<kind> <secret key>`, where kind is `signed` or
+//! `one-time`, each signed prekey followed by the one-time prekeys published
+//! with it. An ML-KEM prekey published along with a signed prekey is stored as
+//! `kem <signed prekey> <seed>` and kept just as long.
+//!
+//! One-time prekeys are removed once used. Publishing a new bundle keeps the
+//! previous one, sessions may have been started from it while this client was
+//! offline, and forgets everything older.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use chat_core::{
+    crypto::{base64_decode, base64_encode, ml_kem::SEED_LENGTH, KemKeyPair},
+    prelude::*,
+};
+
+/// Used when `CLIENT_PREKEYS` isn't set.
+pub(crate) const DEFAULT_PATH: &str = "client_prekeys";
+
+/// How many one-time prekeys are published on every login.
+pub(crate) const ONE_TIME_PREKEYS: usize = 10;
+
+/// How many of the latest published bundles are kept, including the current one.
+const KEPT_BUNDLES: usize = 2;
+
+const SIGNED: &str = "signed";
+const ONE_TIME: &str = "one-time";
+const KEM: &str = "kem";
+
+#[derive(Debug, Clone)]
+pub(crate) struct Prekeys {
+    path: PathBuf,
+}
+
+impl Prekeys {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Generates a signed prekey, `count` one-time prekeys and, if `hybrid`,
+    /// an ML-KEM prekey and stores their secret halves, readable by the owner
+    /// only. Prekeys of bundles older than the previous one are forgotten.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be read or
+    /// written.
+    pub(crate) fn generate(
+        &self,
+        count: usize,
+        hybrid: bool,
+    ) -> Result<(KeyPair, Vec<KeyPair>, Option<KemKeyPair>)> {
+        let signed = KeyPair::new_dh();
+        let one_time: Vec<_> = (0..count).map(|_| KeyPair::new_dh()).collect();
+        let kem = hybrid.then(KemKeyPair::new);
+
+        let stored = self.read()?;
+        let lines: Vec<_> = stored.lines().collect();
+        // Every bundle starts with its signed prekey.
+        let bundles: Vec<_> = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| {
+                line.trim()
+                    .split_once(' ')
+                    .is_some_and(|(kind, _)| kind == SIGNED)
+            })
+            .map(|(index, _)| index)
+            .collect();
+        let first_kept = bundles
+            .get(bundles.len().saturating_sub(KEPT_BUNDLES - 1))
+            .map_or(lines.len(), |&index| index);
+
+        let mut kept: Vec<_> = lines[first_kept..]
+            .iter()
+            .map(|&line| line.to_owned())
+            .collect();
+        kept.push(format!("{SIGNED} {}", signed.secret().encode()));
+        for prekey in &one_time {
+            kept.push(format!("{ONE_TIME} {}", prekey.secret().encode()));
+        }
+        if let Some(kem) = &kem {
+            let seed = base64_encode(kem.seed());
+            kept.push(format!("{KEM} {} {seed}", signed.public().encode()));
+        }
+        self.write(&(kept.join("\n") + "\n"))?;
+        Ok((signed, one_time, kem))
+    }
+
+    /// Key pairs of the given public prekeys, along with the ML-KEM prekey of
+    /// the signed one if `kem` is set. The one-time prekey is forgotten.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any prekey isn't stored, e.g. a
+    /// one-time prekey was already used, or the file can't be rewritten.
+    pub(crate) fn take(
+        &self,
+        signed: &PublicKey,
+        one_time: Option<&PublicKey>,
+        kem: bool,
+    ) -> Result<(KeyPair, Option<KeyPair>, Option<KemKeyPair>)> {
+        let stored = self.read()?;
+        let mut found_signed = None;
+        let mut found_one_time = None;
+        let mut found_kem = None;
+        let mut kept = String::with_capacity(stored.len());
+        for line in stored.lines() {
+            let Some((kind, rest)) = line.trim().split_once(' ') else {
+                continue;
+            };
+            match kind {
+                SIGNED | ONE_TIME => {
+                    let pair = KeyPair::from_dh_secret(SecretKey::try_decode(rest)?);
+                    if kind == SIGNED && pair.public() == signed {
+                        found_signed = Some(pair);
+                    } else if kind == ONE_TIME && Some(pair.public()) == one_time {
+                        found_one_time = Some(pair);
+                        continue;
+                    }
+                }
+                KEM => {
+                    let Some((prekey, seed)) = rest.split_once(' ') else {
+                        continue;
+                    };
+                    if kem && PublicKey::try_decode(prekey)? == *signed {
+                        let seed: [u8; SEED_LENGTH] = base64_decode(seed)?
+                            .try_into()
+                            .map_err(|_| Error::decode("Malformed ML-KEM seed"))?;
+                        found_kem = Some(KemKeyPair::from_seed(seed));
+                    }
+                }
+                _ => (),
+            }
+            kept.push_str(line);
+            kept.push('\n');
+        }
+
+        let signed = found_signed.ok_or_else(|| Error::crypto("Unknown signed prekey"))?;
+        if one_time.is_some() && found_one_time.is_none() {
+            return Err(Error::crypto("Unknown or already used one-time prekey"));
+        }
+        if kem && found_kem.is_none() {
+            return Err(Error::crypto("Unknown ML-KEM prekey"));
+        }
+        if found_one_time.is_some() {
+            self.write(&kept)?;
+        }
+        Ok((signed, found_one_time, found_kem))
+    }
+
+    /// Contents of the file, empty if nothing was published yet.
+    fn read(&self) -> Result<String> {
+        match fs::read_to_string(&self.path) {
+            Ok(stored) => Ok(stored),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(err) => Err(Error::io(err)),
+        }
+    }
+
+    /// Replaces the contents of the file, which is readable by the owner only.
+    fn write(&self, contents: &str) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.create(true).write(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&self.path).map_err(Error::io)?;
+        file.write_all(contents.as_bytes()).map_err(Error::io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Prekeys in a file of their own, removed again when dropped.
+    struct Stored(Prekeys);
+
+    impl Stored {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("chat-client-prekeys-{name}-{}", std::process::id()));
+            let _ = fs::remove_file(&path);
+            Self(Prekeys::new(path))
+        }
+    }
+
+    impl Drop for Stored {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0.path);
+        }
+    }
+
+    #[test]
+    fn take_one_time() {
+        let stored = Stored::new("one-time");
+        let prekeys = &stored.0;
+        let (signed, one_time, kem) = prekeys.generate(2, false).unwrap();
+        assert!(kem.is_none());
+        let (first, second) = (one_time[0].public(), one_time[1].public());
+
+        let (taken, taken_one_time, _) = prekeys.take(signed.public(), Some(first), false).unwrap();
+        assert_eq!(signed.public(), taken.public());
+        assert_eq!(Some(first), taken_one_time.as_ref().map(KeyPair::public));
+
+        // The used one-time prekey is gone from the file, the rest is still there.
+        assert!(prekeys.take(signed.public(), Some(first), false).is_err());
+        assert!(prekeys.take(signed.public(), Some(second), false).is_ok());
+        assert!(prekeys.take(signed.public(), None, false).is_ok());
+        assert_eq!(
+            1,
+            fs::read_to_string(&prekeys.path).unwrap().lines().count()
+        );
+    }
+
+    #[test]
+    fn take_kem() {
+        let stored = Stored::new("kem");
+        let prekeys = &stored.0;
+        let (signed, _, kem) = prekeys.generate(1, true).unwrap();
+
+        let (_, one_time, taken) = prekeys.take(signed.public(), None, true).unwrap();
+        assert!(one_time.is_none());
+        assert_eq!(kem.unwrap().public(), taken.unwrap().public());
+        // Taking the ML-KEM prekey doesn't use it up.
+        assert!(prekeys.take(signed.public(), None, true).is_ok());
+
+        let (signed, _, _) = prekeys.generate(1, false).unwrap();
+        assert!(prekeys.take(signed.public(), None, true).is_err());
+    }
+
+    #[test]
+    fn take_unknown() {
+        let stored = Stored::new("unknown");
+        let prekeys = &stored.0;
+        let unknown = KeyPair::new_dh();
+        assert!(prekeys.take(unknown.public(), None, false).is_err());
+
+        let (signed, _, _) = prekeys.generate(1, false).unwrap();
+        let before = fs::read_to_string(&prekeys.path).unwrap();
+        assert!(prekeys
+            .take(signed.public(), Some(unknown.public()), false)
+            .is_err());
+        assert_eq!(before, fs::read_to_string(&prekeys.path).unwrap());
+    }
+
+    #[test]
+    fn generate_prunes() {
+        let stored = Stored::new("prunes");
+        let prekeys = &stored.0;
+        let (oldest, oldest_one_time, _) = prekeys.generate(3, true).unwrap();
+        let (previous, previous_one_time, _) = prekeys.generate(3, true).unwrap();
+        let (current, _, _) = prekeys.generate(3, true).unwrap();
+
+        assert!(prekeys.take(oldest.public(), None, false).is_err());
+        let oldest_one_time = Some(oldest_one_time[0].public());
+        assert!(prekeys
+            .take(current.public(), oldest_one_time, false)
+            .is_err());
+        // Sessions may still be started from the previous bundle.
+        let previous_one_time = Some(previous_one_time[0].public());
+        assert!(prekeys
+            .take(previous.public(), previous_one_time, true)
+            .is_ok());
+        assert!(prekeys.take(current.public(), None, true).is_ok());
+
+        for _ in 0..5 {
+            prekeys.generate(3, true).unwrap();
+        }
+        // A signed, three one-time and an ML-KEM prekey for each kept bundle.
+        let lines = fs::read_to_string(&prekeys.path).unwrap().lines().count();
+        assert_eq!(KEPT_BUNDLES * 5, lines);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let stored = Stored::new("mode");
+        stored.0.generate(1, false).unwrap();
+        let mode = fs::metadata(&stored.0.path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+    }
+}
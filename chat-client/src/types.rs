@@ -1,28 +1,57 @@
-use chat_core::prelude::*;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use chat_core::{
+    crypto::{DoubleRatchet, SenderChain, SenderKey, TransportKeys},
+    event::SessionInit,
+    keepalive::Keepalive,
+    prelude::*,
+};
+
+use crate::prekeys::Prekeys;
 
 #[derive(Clone)]
 pub(crate) struct Client {
     username: String,
     password: String,
-    event: Capnp,
+    event: DynSchema,
     crypto: Crypto,
-    /// Shared secret between this client and a server.
-    /// Needs to encrypt all communicataions between the client and the server.
-    server_secret: Option<SharedSecret>,
-    /// Shared secret between this client and another client inside their session.
+    /// Vouches for the prekeys of this client.
+    identity: Arc<KeyPair>,
+    prekeys: Prekeys,
+    /// Keys negotiated with the server, one for each direction. Shared by
+    /// the sending and the receiving threads, which number their frames.
+    server_keys: Option<Arc<Mutex<TransportKeys>>>,
+    /// Session between this client and another one.
     /// Used to encrypt data between this client and the other one.
     session_secret: SessionSecret,
+    /// Clients this one started a session with from their prekeys, or the
+    /// other way around.
+    group: Group,
+    liveness: Liveness,
 }
 
 impl Client {
-    pub(crate) fn new(username: String, password: String) -> Self {
+    pub(crate) fn new(
+        username: String,
+        password: String,
+        keepalive: Keepalive,
+        identity: KeyPair,
+        prekeys: Prekeys,
+    ) -> Self {
         Self {
             username,
             password,
-            event: Capnp::default(),
+            event: DynSchema::default(),
             crypto: Crypto::default(),
-            server_secret: None,
+            identity: Arc::new(identity),
+            prekeys,
+            server_keys: None,
             session_secret: SessionSecret::None,
+            group: Group::new(),
+            liveness: Liveness::new(keepalive),
         }
     }
 
@@ -32,17 +61,26 @@ impl Client {
     pub(crate) fn password(&self) -> &str {
         self.password.as_ref()
     }
-    pub(crate) const fn event(&self) -> &Capnp {
+    pub(crate) const fn event(&self) -> &DynSchema {
         &self.event
     }
+    pub(crate) fn set_event(&mut self, event: DynSchema) {
+        self.event = event;
+    }
     pub(crate) const fn crypto(&self) -> Crypto {
         self.crypto
     }
-    pub(crate) fn shared_secret(&self) -> &SharedSecret {
-        self.server_secret.as_ref().unwrap()
+    pub(crate) fn identity(&self) -> &KeyPair {
+        &self.identity
+    }
+    pub(crate) const fn prekeys(&self) -> &Prekeys {
+        &self.prekeys
     }
-    pub(crate) fn set_shared_secret(&mut self, shared_secret: SharedSecret) {
-        self.server_secret = Some(shared_secret);
+    pub(crate) fn server_keys(&self) -> MutexGuard<'_, TransportKeys> {
+        lock(self.server_keys.as_ref().unwrap())
+    }
+    pub(crate) fn set_server_keys(&mut self, keys: TransportKeys) {
+        self.server_keys = Some(Arc::new(Mutex::new(keys)));
     }
     pub(crate) const fn session_secret(&self) -> &SessionSecret {
         &self.session_secret
@@ -50,6 +88,134 @@ impl Client {
     pub(crate) fn set_session_secret(&mut self, state: SessionSecret) {
         self.session_secret = state;
     }
+    pub(crate) const fn group(&self) -> &Group {
+        &self.group
+    }
+    pub(crate) const fn liveness(&self) -> &Liveness {
+        &self.liveness
+    }
+}
+
+/// Liveness of the connection to the server, shared by the sending and the
+/// receiving threads.
+#[derive(Clone)]
+pub(crate) struct Liveness {
+    keepalive: Arc<Mutex<Keepalive>>,
+    /// Nonces of pings from the server which are waiting for a pong.
+    pongs_tx: flume::Sender<u64>,
+    pongs_rx: flume::Receiver<u64>,
+}
+
+impl Liveness {
+    fn new(keepalive: Keepalive) -> Self {
+        let (pongs_tx, pongs_rx) = flume::unbounded();
+        Self {
+            keepalive: Arc::new(Mutex::new(keepalive)),
+            pongs_tx,
+            pongs_rx,
+        }
+    }
+
+    pub(crate) fn keepalive(&self) -> MutexGuard<'_, Keepalive> {
+        lock(&self.keepalive)
+    }
+    pub(crate) const fn pongs_tx(&self) -> &flume::Sender<u64> {
+        &self.pongs_tx
+    }
+    pub(crate) const fn pongs_rx(&self) -> &flume::Receiver<u64> {
+        &self.pongs_rx
+    }
+}
+
+/// Members of the room every client on the server is in, shared by the
+/// sending and the receiving threads.
+#[derive(Clone)]
+pub(crate) struct Group {
+    members: Arc<Mutex<Members>>,
+    /// Members the sender key of this client is due for.
+    distributions_tx: flume::Sender<String>,
+    distributions_rx: flume::Receiver<String>,
+}
+
+impl Group {
+    fn new() -> Self {
+        let (distributions_tx, distributions_rx) = flume::unbounded();
+        Self {
+            members: Arc::new(Mutex::new(Members::default())),
+            distributions_tx,
+            distributions_rx,
+        }
+    }
+
+    pub(crate) fn members(&self) -> MutexGuard<'_, Members> {
+        lock(&self.members)
+    }
+    pub(crate) const fn distributions_tx(&self) -> &flume::Sender<String> {
+        &self.distributions_tx
+    }
+    pub(crate) const fn distributions_rx(&self) -> &flume::Receiver<String> {
+        &self.distributions_rx
+    }
+
+    /// Hands the sender key of this client to every member.
+    pub(crate) fn distribute(&self) -> Result<()> {
+        for username in self.members().usernames() {
+            self.distributions_tx
+                .send(username)
+                .map_err(Error::generic)?;
+        }
+        Ok(())
+    }
+}
+
+/// Group messages are encrypted with the sender key of this client, which
+/// travels to the members over the pairwise sessions with them.
+#[derive(Default)]
+pub(crate) struct Members {
+    own: SenderKey,
+    sessions: BTreeMap<String, Session>,
+    /// Sender keys of the members.
+    chains: HashMap<String, SenderChain>,
+}
+
+impl Members {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+    pub(crate) fn usernames(&self) -> Vec<String> {
+        self.sessions.keys().cloned().collect()
+    }
+    pub(crate) fn own_mut(&mut self) -> &mut SenderKey {
+        &mut self.own
+    }
+    pub(crate) fn session(&self, username: &str) -> Option<&Session> {
+        self.sessions.get(username)
+    }
+    pub(crate) fn chain_mut(&mut self, username: &str) -> Option<&mut SenderChain> {
+        self.chains.get_mut(username)
+    }
+    pub(crate) fn set_chain(&mut self, username: String, chain: SenderChain) {
+        self.chains.insert(username, chain);
+    }
+
+    /// Adds `username`, or replaces its session. The sender key is rotated,
+    /// so that the new member can't read what was sent before.
+    pub(crate) fn join(&mut self, username: String, session: Session) {
+        self.chains.remove(&username);
+        self.sessions.insert(username, session);
+        self.own = self.own.rotate();
+    }
+
+    /// Removes `username` and rotates the sender key, so that it can't read
+    /// what is sent from now on. Returns `false` if it wasn't a member.
+    pub(crate) fn leave(&mut self, username: &str) -> bool {
+        self.chains.remove(username);
+        if self.sessions.remove(username).is_none() {
+            return false;
+        }
+        self.own = self.own.rotate();
+        true
+    }
 }
 
 #[derive(Clone)]
@@ -57,16 +223,35 @@ pub(crate) enum SessionSecret {
     None,
     PendingForShared(SecretKey),
     PendingToSend(PublicKey),
-    Established(SharedSecret),
+    /// A session was started from the prekeys of an offline client, which
+    /// needs these keys to join it.
+    PendingToInit(SessionInit<'static>),
+    Established(Session),
+}
+
+/// Double Ratchet of an established session, shared by the sending and the
+/// receiving threads.
+#[derive(Clone)]
+pub(crate) struct Session(Arc<Mutex<DoubleRatchet>>);
+
+impl Session {
+    pub(crate) fn new(ratchet: DoubleRatchet) -> Self {
+        Self(Arc::new(Mutex::new(ratchet)))
+    }
+
+    pub(crate) fn ratchet(&self) -> MutexGuard<'_, DoubleRatchet> {
+        lock(&self.0)
+    }
 }
 
 impl std::fmt::Display for SessionSecret {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::None => write!(f, "None"),
-            Self::PendingForShared(key) => write!(f, "PendingForSecret({})", key.encode()),
+            Self::PendingForShared(key) => write!(f, "PendingForSecret({key:?})"),
             Self::PendingToSend(key) => write!(f, "PendingToSend({})", key.encode()),
-            Self::Established(key) => write!(f, "Established({})", key.encode()),
+            Self::PendingToInit(init) => write!(f, "PendingToInit({})", init.recipient()),
+            Self::Established(session) => write!(f, "Established({:?})", *session.ratchet()),
         }
     }
 }
@@ -86,3 +271,9 @@ impl ThreadCommunication {
         (s1, s2)
     }
 }
+
+/// Locks `mutex`, taking the guard over even if another thread panicked while
+/// holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,207 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+/// Default upper bound for a single frame (1 MiB).
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
+/// Splits a byte stream into frames prefixed with their length.
+///
+/// Wire layout of a frame:
+/// ```text
+/// +----------------+-------------------+-----------------+
+/// | length: u32 BE | type: u8 (if set) | payload         |
+/// +----------------+-------------------+-----------------+
+/// ```
+/// The length covers the type byte and the payload. Frames which exceed the
+/// maximum length or carry an unexpected type are rejected.
+#[derive(Debug, Clone)]
+pub struct FrameCodec {
+    inner: LengthDelimitedCodec,
+    frame_type: Option<u8>,
+}
+
+impl FrameCodec {
+    pub fn new() -> Self {
+        Self::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+    }
+
+    pub fn with_max_frame_length(max_frame_length: usize) -> Self {
+        let inner = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .max_frame_length(max_frame_length)
+            .new_codec();
+        Self {
+            inner,
+            frame_type: None,
+        }
+    }
+
+    /// Every frame will carry `frame_type` right after its length.
+    #[must_use]
+    pub const fn frame_type(mut self, frame_type: u8) -> Self {
+        self.frame_type = Some(frame_type);
+        self
+    }
+
+    pub fn max_frame_length(&self) -> usize {
+        self.inner.max_frame_length()
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(mut frame) = self.inner.decode(src)? else {
+            return Ok(None);
+        };
+
+        if let Some(expected) = self.frame_type {
+            if !frame.has_remaining() {
+                return Err(invalid_data("frame is missing its type"));
+            }
+            let frame_type = frame.get_u8();
+            if frame_type != expected {
+                return Err(invalid_data(format!(
+                    "unexpected frame type {frame_type}, expected {expected}"
+                )));
+            }
+        }
+
+        Ok(Some(frame))
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, data: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let Some(frame_type) = self.frame_type else {
+            return self.inner.encode(data, dst);
+        };
+
+        let mut frame = BytesMut::with_capacity(data.len() + 1);
+        frame.put_u8(frame_type);
+        frame.put(data);
+        self.inner.encode(frame.freeze(), dst)
+    }
+}
+
+impl Encoder<BytesMut> for FrameCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, data: BytesMut, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode(data.freeze(), dst)
+    }
+}
+
+fn invalid_data<E>(err: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static FIRST: &[u8] = b"Lorem ipsum dolor sit amet";
+    static SECOND: &[u8] = b"qui minim labore adipisicing minim sint cillum sint";
+
+    fn encode(codec: &mut FrameCodec, frames: &[&[u8]]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for frame in frames {
+            codec.encode(Bytes::from(frame.to_vec()), &mut buf).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn single() {
+        let mut codec = FrameCodec::new();
+        let mut buf = encode(&mut codec, &[FIRST]);
+        assert_eq!(buf.len(), 4 + FIRST.len());
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(FIRST, &decoded[..]);
+        assert!(buf.is_empty());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn merged() {
+        let mut codec = FrameCodec::new();
+        let mut buf = encode(&mut codec, &[FIRST, SECOND]);
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(FIRST, &decoded[..]);
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(SECOND, &decoded[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn split() {
+        let mut codec = FrameCodec::new();
+        let encoded = encode(&mut codec, &[FIRST, SECOND]);
+
+        // Feed the stream byte by byte, like the slowest possible socket.
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded {
+            buf.put_u8(byte);
+            if let Some(frame) = codec.decode(&mut buf).unwrap() {
+                decoded.push(frame);
+            }
+        }
+
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(FIRST, &decoded[0][..]);
+        assert_eq!(SECOND, &decoded[1][..]);
+    }
+
+    #[test]
+    fn empty() {
+        let mut codec = FrameCodec::new();
+        let mut buf = encode(&mut codec, &[&[]]);
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(decoded.is_empty());
+    }
+
+    #[test]
+    fn too_long() {
+        let mut codec = FrameCodec::with_max_frame_length(FIRST.len() - 1);
+        let mut buf = BytesMut::new();
+        assert!(codec.encode(Bytes::from_static(FIRST), &mut buf).is_err());
+
+        let mut buf = encode(&mut FrameCodec::new(), &[FIRST]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn frame_type() {
+        let mut codec = FrameCodec::new().frame_type(7);
+        let mut buf = encode(&mut codec, &[FIRST, SECOND]);
+        assert_eq!(buf.len(), 2 * 5 + FIRST.len() + SECOND.len());
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(FIRST, &decoded[..]);
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(SECOND, &decoded[..]);
+
+        let mut buf = encode(&mut FrameCodec::new().frame_type(8), &[FIRST]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = encode(&mut FrameCodec::new(), &[&[]]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
@@ -1,21 +1,30 @@
-use aead::{Aead, AeadCore, KeyInit};
+use aead::{Aead, AeadCore, KeyInit, Payload};
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
 use rand_core::OsRng;
 
-use super::{CryptoSchema, Error, PublicKey, Result, SecretKey, SharedSecret, Then};
+use super::{
+    CryptoSchema, EncapsulationKey, Error, KemCiphertext, KemKeyPair, PublicKey, Result, SecretKey,
+    SharedSecret, Signature, Then,
+};
+
+const NONCE_LENGTH: usize = 24;
+/// Bytes of a sequenced nonce which come before the sequence number.
+const SEQUENCE_OFFSET: usize = NONCE_LENGTH - std::mem::size_of::<u64>();
 
 #[non_exhaustive]
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Crypto;
 
 impl CryptoSchema for Crypto {
-    fn encrypt(&self, key: &SecretKey, blob: &[u8]) -> Result<Vec<u8>> {
+    fn encrypt_with_aad(&self, key: &SecretKey, blob: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
         let key = Key::from_slice(&**key);
         let cipher: XChaCha20Poly1305 = XChaCha20Poly1305::new(key);
 
         let nonce: XNonce = XChaCha20Poly1305::generate_nonce(&mut OsRng); // 192-bits
-        let mut ciphertext = cipher.encrypt(&nonce, blob).map_err(Error::crypto)?;
+        let mut ciphertext = cipher
+            .encrypt(&nonce, Payload { msg: blob, aad })
+            .map_err(Error::crypto)?;
 
         let mut encrypted_text: Vec<u8> = Vec::with_capacity(nonce.len() + ciphertext.len());
         encrypted_text.append(&mut nonce.to_vec());
@@ -23,18 +32,62 @@ impl CryptoSchema for Crypto {
 
         Ok(encrypted_text)
     }
-    fn decrypt(&self, key: &SecretKey, blob: &[u8]) -> Result<Vec<u8>> {
+    fn decrypt_with_aad(&self, key: &SecretKey, blob: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
         let key = Key::from_slice(&**key);
         let cipher: XChaCha20Poly1305 = XChaCha20Poly1305::new(key);
 
-        let (nonce, ciphertext) = blob.split_at(24);
+        if blob.len() < NONCE_LENGTH {
+            return Err(Error::crypto("ciphertext is shorter than its nonce"));
+        }
+        let (nonce, ciphertext) = blob.split_at(NONCE_LENGTH);
         let nonce = XNonce::from_slice(nonce);
         let decrypted = cipher
-            .decrypt(nonce, ciphertext.as_ref())
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
             .map_err(Error::crypto)?;
 
         Ok(decrypted)
     }
+    fn encrypt_sequenced(
+        &self,
+        key: &SecretKey,
+        sequence: u64,
+        blob: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        let key = Key::from_slice(&**key);
+        let cipher: XChaCha20Poly1305 = XChaCha20Poly1305::new(key);
+
+        // Zeroes followed by the big-endian sequence number, so that the
+        // receiver can check the order before decrypting.
+        let mut nonce = XNonce::default();
+        nonce[SEQUENCE_OFFSET..].copy_from_slice(&sequence.to_be_bytes());
+        let mut ciphertext = cipher
+            .encrypt(&nonce, Payload { msg: blob, aad })
+            .map_err(Error::crypto)?;
+
+        let mut encrypted_text: Vec<u8> = Vec::with_capacity(nonce.len() + ciphertext.len());
+        encrypted_text.extend_from_slice(&nonce);
+        encrypted_text.append(&mut ciphertext);
+
+        Ok(encrypted_text)
+    }
+    fn sequence_of(&self, blob: &[u8]) -> Result<u64> {
+        let nonce = blob
+            .get(..NONCE_LENGTH)
+            .ok_or_else(|| Error::decode("frame is shorter than its nonce"))?;
+        let (prefix, sequence) = nonce.split_at(SEQUENCE_OFFSET);
+        if prefix.iter().any(|&byte| byte != 0) {
+            return Err(Error::decode("frame isn't sequenced"));
+        }
+        let sequence = sequence.try_into().expect("sequence is 8 bytes long");
+        Ok(u64::from_be_bytes(sequence))
+    }
 
     fn key_derivation(&self, pwd: &[u8], salt: &[u8]) -> Result<SecretKey> {
         if salt.len() != 32 {
@@ -94,11 +147,33 @@ impl CryptoSchema for Crypto {
 
         SharedSecret::new(*hashed.as_bytes())
     }
+
+    fn encapsulate(&self, public: &EncapsulationKey) -> (KemCiphertext, SharedSecret) {
+        public.encapsulate()
+    }
+
+    fn decapsulate(&self, pair: &KemKeyPair, ciphertext: &KemCiphertext) -> SharedSecret {
+        pair.decapsulate(ciphertext)
+    }
+
+    fn sign(&self, secret: &SecretKey, blob: &[u8]) -> Signature {
+        use ed25519_dalek::Signer;
+
+        let key = ed25519_dalek::SigningKey::from_bytes(secret);
+        Signature::new(key.sign(blob).to_bytes())
+    }
+
+    fn verify(&self, public: &PublicKey, blob: &[u8], signature: &Signature) -> Result<()> {
+        let key = ed25519_dalek::VerifyingKey::from_bytes(public).map_err(Error::crypto)?;
+        let signature = ed25519_dalek::Signature::from_bytes(signature);
+        key.verify_strict(blob, &signature).map_err(Error::crypto)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::KeyPair;
 
     #[test]
     fn test_send() {
@@ -110,4 +185,56 @@ mod tests {
         fn assert_sync<T: Sync>() {}
         assert_sync::<Crypto>();
     }
+
+    #[test]
+    fn associated_data() {
+        let key = SecretKey::new(rand::random());
+        let blob = Crypto
+            .encrypt_with_aad(&key, b"Lorem ipsum", b"header")
+            .unwrap();
+        assert_eq!(
+            Crypto.decrypt_with_aad(&key, &blob, b"header").unwrap(),
+            b"Lorem ipsum"
+        );
+        assert!(Crypto.decrypt_with_aad(&key, &blob, b"headex").is_err());
+        assert!(Crypto.decrypt(&key, &blob).is_err());
+    }
+
+    #[test]
+    fn sequenced() {
+        let key = SecretKey::new(rand::random());
+        let blob = Crypto
+            .encrypt_sequenced(&key, 42, b"Lorem ipsum", &[])
+            .unwrap();
+        assert_eq!(Crypto.sequence_of(&blob), Ok(42));
+        assert_eq!(Crypto.decrypt(&key, &blob).unwrap(), b"Lorem ipsum");
+
+        // Random nonces and truncated frames carry no sequence number.
+        let random = Crypto.encrypt(&key, b"Lorem ipsum").unwrap();
+        assert!(Crypto.sequence_of(&random).is_err());
+        assert!(Crypto.sequence_of(&blob[..10]).is_err());
+        assert!(Crypto.decrypt(&key, &blob[..10]).is_err());
+
+        // The sequence number is authenticated along with the nonce.
+        let mut forged = blob.clone();
+        forged[NONCE_LENGTH - 1] ^= 1;
+        assert_eq!(Crypto.sequence_of(&forged), Ok(43));
+        assert!(Crypto.decrypt(&key, &forged).is_err());
+    }
+
+    #[test]
+    fn sign_verify() {
+        let identity = KeyPair::new_signing();
+        let signature = Crypto.sign(identity.secret(), b"Lorem ipsum");
+        assert!(Crypto
+            .verify(identity.public(), b"Lorem ipsum", &signature)
+            .is_ok());
+        assert!(Crypto
+            .verify(identity.public(), b"Lorem ipsun", &signature)
+            .is_err());
+        let other = KeyPair::new_signing();
+        assert!(Crypto
+            .verify(other.public(), b"Lorem ipsum", &signature)
+            .is_err());
+    }
 }
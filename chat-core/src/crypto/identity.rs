@@ -0,0 +1,61 @@
+//! Long-term identity keys kept on disk
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use super::{Encodable, Error, KeyPair, Result, SecretKey};
+
+/// Reads the identity key stored at `path`, `None` if there is no such file.
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be read or doesn't
+/// hold a key.
+pub fn load_identity(path: impl AsRef<Path>) -> Result<Option<KeyPair>> {
+    match fs::read_to_string(path) {
+        Ok(encoded) => {
+            let secret = SecretKey::try_decode(encoded.trim())?;
+            Ok(Some(KeyPair::from_signing_secret(secret)))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(Error::io(err)),
+    }
+}
+
+/// Generates an identity key and stores it at `path`, readable by the owner
+/// only.
+///
+/// # Errors
+///
+/// This function will return an error if the file already exists or can't be
+/// written.
+pub fn generate_identity(path: impl AsRef<Path>) -> Result<KeyPair> {
+    let identity = KeyPair::new_signing();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(Error::io)?;
+    writeln!(file, "{}", identity.secret().encode()).map_err(Error::io)?;
+    Ok(identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_then_load() {
+        let path = std::env::temp_dir().join(format!("chat-core-identity-{}", std::process::id()));
+        assert!(load_identity(&path).unwrap().is_none());
+
+        let generated = generate_identity(&path).unwrap();
+        assert_eq!(Some(generated), load_identity(&path).unwrap());
+        assert!(generate_identity(&path).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+//! Deriving the keys of a connection from the secret a handshake agreed on
+//!
+//! Every key comes out of HKDF-SHA256 under its own label, so keys for
+//! different directions or generations never collide.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use super::{CryptoSchema, ReplayWindow, SecretKey, CRYPTO_KEY_LENGTH};
+use crate::prelude::*;
+
+const CLIENT_TO_SERVER: &[u8] = b"CORE_TRANSPORT_C2S";
+const SERVER_TO_CLIENT: &[u8] = b"CORE_TRANSPORT_S2C";
+const REKEY: &[u8] = b"CORE_REKEY";
+
+/// Expands the secret of a handshake into transport keys.
+#[derive(Clone)]
+pub struct KeySchedule {
+    hkdf: Hkdf<Sha256>,
+}
+
+impl KeySchedule {
+    /// `secret` is the output of the handshake, `salt` binds it to the
+    /// handshake it came from, e.g. its transcript hash.
+    pub fn new(secret: &[u8], salt: &[u8]) -> Self {
+        Self {
+            hkdf: Hkdf::new(Some(salt), secret),
+        }
+    }
+
+    /// Keys of the side which started the handshake, for frames of the
+    /// negotiated protocol `version`.
+    pub fn client(&self, version: u32) -> TransportKeys {
+        let (send, recv) = (self.expand(CLIENT_TO_SERVER), self.expand(SERVER_TO_CLIENT));
+        TransportKeys::new(send, recv, version)
+    }
+
+    /// Keys of the side which answered the handshake, for frames of the
+    /// negotiated protocol `version`.
+    pub fn server(&self, version: u32) -> TransportKeys {
+        let (send, recv) = (self.expand(SERVER_TO_CLIENT), self.expand(CLIENT_TO_SERVER));
+        TransportKeys::new(send, recv, version)
+    }
+
+    fn expand(&self, label: &[u8]) -> SecretKey {
+        let mut key = [0; CRYPTO_KEY_LENGTH];
+        self.hkdf
+            .expand(label, &mut key)
+            .expect("key length is valid for HKDF-SHA256");
+        SecretKey::new(key)
+    }
+}
+
+impl std::fmt::Debug for KeySchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeySchedule").finish_non_exhaustive()
+    }
+}
+
+/// Keys protecting one connection, one for each direction, along with the
+/// sequence numbers of the frames they protect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportKeys {
+    send: SecretKey,
+    recv: SecretKey,
+    /// Sequence number of the next frame we send.
+    sent: u64,
+    received: ReplayWindow,
+    version: u32,
+}
+
+impl TransportKeys {
+    pub fn new(send: SecretKey, recv: SecretKey, version: u32) -> Self {
+        Self {
+            send,
+            recv,
+            sent: 0,
+            received: ReplayWindow::new(),
+            version,
+        }
+    }
+    /// Encrypts everything we send.
+    pub const fn send(&self) -> &SecretKey {
+        &self.send
+    }
+    /// Decrypts everything the other side sends.
+    pub const fn recv(&self) -> &SecretKey {
+        &self.recv
+    }
+    /// Protocol version negotiated along with the keys.
+    pub const fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Encrypts the next frame we send, numbering it. Both `aad` and the
+    /// sequence number are authenticated along with the frame.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the encryption fails or the
+    /// sequence numbers ran out.
+    pub fn seal<C: CryptoSchema>(
+        &mut self,
+        crypto: &C,
+        blob: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        let sequence = self.sent;
+        self.sent = sequence
+            .checked_add(1)
+            .ok_or_else(|| Error::crypto("sequence numbers ran out"))?;
+        crypto.encrypt_sequenced(&self.send, sequence, blob, &sequenced(aad, sequence))
+    }
+
+    /// Decrypts a frame from the other side, unless it was seen before.
+    /// `aad` has to be the same the other side sealed it with.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`Error::Replayed`] or
+    /// [`Error::OutOfWindow`] for a frame which may have been replayed, or
+    /// an error if it can't be decrypted.
+    pub fn open<C: CryptoSchema>(
+        &mut self,
+        crypto: &C,
+        frame: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        let sequence = crypto.sequence_of(frame)?;
+        self.received.check(sequence)?;
+        let decrypted = crypto.decrypt_with_aad(&self.recv, frame, &sequenced(aad, sequence))?;
+        self.received.accept(sequence)?;
+        Ok(decrypted)
+    }
+
+    /// Moves both directions to the next generation of keys, so that a key
+    /// leaked later doesn't expose what was sent before.
+    ///
+    /// Both sides have to rekey at the same point of the conversation.
+    /// Sequence numbers carry on, so frames from before the rekey still
+    /// count as seen.
+    pub fn rekey(&mut self) {
+        self.send = next(&self.send);
+        self.recv = next(&self.recv);
+    }
+}
+
+fn sequenced(aad: &[u8], sequence: u64) -> Vec<u8> {
+    [aad, &sequence.to_be_bytes()].concat()
+}
+
+fn next(key: &SecretKey) -> SecretKey {
+    let hkdf = Hkdf::<Sha256>::from_prk(key.as_ref()).expect("key is as long as the hash");
+    let mut next = [0; CRYPTO_KEY_LENGTH];
+    hkdf.expand(REKEY, &mut next)
+        .expect("key length is valid for HKDF-SHA256");
+    SecretKey::new(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Crypto;
+    use crate::protocol::PROTOCOL_VERSION;
+
+    #[test]
+    fn directions() {
+        let schedule = KeySchedule::new(b"secret", b"transcript");
+        let (client, server) = (
+            schedule.client(PROTOCOL_VERSION),
+            schedule.server(PROTOCOL_VERSION),
+        );
+        assert_eq!(client.send(), server.recv());
+        assert_eq!(client.recv(), server.send());
+        assert_ne!(client.send(), client.recv());
+    }
+
+    #[test]
+    fn bound_to_salt() {
+        let one = KeySchedule::new(b"secret", b"transcript").client(PROTOCOL_VERSION);
+        let other = KeySchedule::new(b"secret", b"other transcript").client(PROTOCOL_VERSION);
+        assert_ne!(one, other);
+    }
+
+    #[test]
+    fn seal_open() {
+        let schedule = KeySchedule::new(b"secret", b"transcript");
+        let (mut client, mut server) = (
+            schedule.client(PROTOCOL_VERSION),
+            schedule.server(PROTOCOL_VERSION),
+        );
+
+        let first = client.seal(&Crypto, b"first", b"").unwrap();
+        let second = client.seal(&Crypto, b"second", b"").unwrap();
+        assert_eq!(Crypto.sequence_of(&first), Ok(0));
+        assert_eq!(Crypto.sequence_of(&second), Ok(1));
+
+        // Reordered frames are fine, replayed ones aren't.
+        assert_eq!(server.open(&Crypto, &second, b"").unwrap(), b"second");
+        assert_eq!(server.open(&Crypto, &first, b"").unwrap(), b"first");
+        assert_eq!(server.open(&Crypto, &first, b""), Err(Error::Replayed(0)));
+        assert_eq!(server.open(&Crypto, &second, b""), Err(Error::Replayed(1)));
+    }
+
+    #[test]
+    fn forged_sequence() {
+        let schedule = KeySchedule::new(b"secret", b"transcript");
+        let (mut client, mut server) = (
+            schedule.client(PROTOCOL_VERSION),
+            schedule.server(PROTOCOL_VERSION),
+        );
+
+        let mut frame = client.seal(&Crypto, b"frame", b"").unwrap();
+        frame[23] ^= 1;
+        assert!(matches!(
+            server.open(&Crypto, &frame, b""),
+            Err(Error::Crypto(_))
+        ));
+        // A forgery doesn't burn the sequence number it claimed.
+        let next = client.seal(&Crypto, b"next", b"").unwrap();
+        assert_eq!(server.open(&Crypto, &next, b"").unwrap(), b"next");
+    }
+
+    #[test]
+    fn associated_data() {
+        let schedule = KeySchedule::new(b"secret", b"transcript");
+        let (mut client, mut server) = (
+            schedule.client(PROTOCOL_VERSION),
+            schedule.server(PROTOCOL_VERSION),
+        );
+
+        let frame = client.seal(&Crypto, b"frame", b"header").unwrap();
+        assert!(server.open(&Crypto, &frame, b"other header").is_err());
+        // A frame failing to open may be retried with the right header.
+        assert_eq!(server.open(&Crypto, &frame, b"header").unwrap(), b"frame");
+    }
+
+    #[test]
+    fn rekey() {
+        let schedule = KeySchedule::new(b"secret", b"transcript");
+        let (mut client, mut server) = (
+            schedule.client(PROTOCOL_VERSION),
+            schedule.server(PROTOCOL_VERSION),
+        );
+        let before = client.clone();
+
+        client.rekey();
+        server.rekey();
+        assert_ne!(before.send(), client.send());
+        assert_ne!(before.recv(), client.recv());
+        assert_eq!(client.send(), server.recv());
+        assert_eq!(client.recv(), server.send());
+    }
+}
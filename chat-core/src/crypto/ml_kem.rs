@@ -0,0 +1,341 @@
+//! ML-KEM-768, the post-quantum key encapsulation of FIPS 203
+//!
+//! X25519 falls to a large enough quantum computer, so traffic recorded today
+//! could be read later. Key exchanges run ML-KEM along with X25519 and mix
+//! both secrets, which stays safe as long as either of them holds, see
+//! [`crate::protocol::Capabilities::HYBRID`].
+//!
+//! The arithmetic is left to the constant-time [`ml_kem`] crate, these types
+//! only carry its keys over the wire. Key pairs are stored as their 64-byte
+//! seed, which FIPS 203 allows in place of the decapsulation key.
+
+use std::fmt;
+
+use ml_kem::{
+    array::Array,
+    kem::{Decapsulate, Encapsulate},
+    EncodedSizeUser, KemCore, MlKem768,
+};
+use rand_core::{OsRng, RngCore};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use super::{Encodable, SharedSecret};
+use crate::prelude::*;
+
+const Q: u32 = 3329;
+const K: usize = 3;
+/// Bytes of a polynomial encoded with 12 bits per coefficient.
+const POLY_BYTES: usize = 384;
+pub const ENCAPSULATION_KEY_LENGTH: usize = POLY_BYTES * K + 32;
+pub const CIPHERTEXT_LENGTH: usize = 1088;
+pub const SEED_LENGTH: usize = 64;
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+/// ML-KEM key pair, only the encapsulation key is ever sent.
+#[derive(Clone)]
+pub struct KemKeyPair {
+    seed: [u8; SEED_LENGTH],
+    /// Kept on the heap, it's over 3 KiB.
+    decapsulation: Box<DecapsulationKey>,
+    public: EncapsulationKey,
+}
+
+impl KemKeyPair {
+    pub fn new() -> Self {
+        let mut seed = [0; SEED_LENGTH];
+        OsRng.fill_bytes(&mut seed);
+        Self::from_seed(seed)
+    }
+
+    /// Key pair of a stored `seed`, the `d || z` of FIPS 203.
+    pub fn from_seed(seed: [u8; SEED_LENGTH]) -> Self {
+        let (d, z) = seed.split_at(32);
+        let d = Array::try_from(d).expect("halves are 32 bytes long");
+        let z = Array::try_from(z).expect("halves are 32 bytes long");
+        let (decapsulation, public) = MlKem768::generate_deterministic(&d, &z);
+        Self {
+            seed,
+            decapsulation: Box::new(decapsulation),
+            public: EncapsulationKey {
+                bytes: public.as_bytes().to_vec(),
+            },
+        }
+    }
+
+    pub const fn seed(&self) -> &[u8; SEED_LENGTH] {
+        &self.seed
+    }
+
+    pub const fn public(&self) -> &EncapsulationKey {
+        &self.public
+    }
+
+    /// Secret the other side encapsulated in `ciphertext`. A tampered
+    /// ciphertext yields an unrelated secret rather than an error, so the
+    /// failure only shows once that secret is used.
+    pub fn decapsulate(&self, ciphertext: &KemCiphertext) -> SharedSecret {
+        let ciphertext = Array::try_from(ciphertext.as_ref()).expect("length is checked");
+        let key = self
+            .decapsulation
+            .decapsulate(&ciphertext)
+            .expect("decapsulation never fails");
+        SharedSecret::new(key.into())
+    }
+}
+
+impl Default for KemKeyPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for KemKeyPair {
+    fn drop(&mut self) {
+        // The decapsulation key wipes itself.
+        self.seed.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for KemKeyPair {}
+
+impl fmt::Debug for KemKeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KemKeyPair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Public half of a [`KemKeyPair`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncapsulationKey {
+    bytes: Vec<u8>,
+}
+
+impl EncapsulationKey {
+    /// A fresh secret and the ciphertext only the owner of this key can get
+    /// it back from.
+    pub fn encapsulate(&self) -> (KemCiphertext, SharedSecret) {
+        let bytes = Array::try_from(self.bytes.as_slice()).expect("length is checked");
+        let key = <MlKem768 as KemCore>::EncapsulationKey::from_bytes(&bytes);
+        let (ciphertext, secret) = key
+            .encapsulate(&mut OsRng)
+            .expect("encapsulation never fails");
+        let ciphertext = KemCiphertext {
+            bytes: ciphertext.to_vec(),
+        };
+        (ciphertext, SharedSecret::new(secret.into()))
+    }
+}
+
+impl AsRef<[u8]> for EncapsulationKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl TryFrom<&[u8]> for EncapsulationKey {
+    type Error = Error;
+
+    /// Fails unless every coefficient is already reduced, the modulus check
+    /// of FIPS 203.
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() != ENCAPSULATION_KEY_LENGTH {
+            return Err(Error::generic(format!(
+                "Expected an encapsulation key of length {ENCAPSULATION_KEY_LENGTH} but it was {}",
+                value.len()
+            )));
+        }
+        let reduced = value[..POLY_BYTES * K].chunks_exact(3).all(|c| {
+            u32::from(c[0]) | u32::from(c[1] & 0x0f) << 8 < Q
+                && u32::from(c[1] >> 4) | u32::from(c[2]) << 4 < Q
+        });
+        if !reduced {
+            return Err(Error::crypto("Encapsulation key isn't reduced modulo q"));
+        }
+        Ok(Self {
+            bytes: value.to_vec(),
+        })
+    }
+}
+
+/// Sent to the owner of an [`EncapsulationKey`] to share a secret.
+#[derive(Clone, PartialEq, Eq)]
+pub struct KemCiphertext {
+    bytes: Vec<u8>,
+}
+
+impl AsRef<[u8]> for KemCiphertext {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl TryFrom<&[u8]> for KemCiphertext {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() != CIPHERTEXT_LENGTH {
+            return Err(Error::generic(format!(
+                "Expected a ciphertext of length {CIPHERTEXT_LENGTH} but it was {}",
+                value.len()
+            )));
+        }
+        Ok(Self {
+            bytes: value.to_vec(),
+        })
+    }
+}
+
+macro_rules! encodable {
+    ($name:ident) => {
+        impl Encodable for $name {
+            fn encode(&self) -> String {
+                super::base64_encode(self)
+            }
+
+            fn try_decode<T: AsRef<[u8]>>(bytes: T) -> Result<Self> {
+                let decoded = super::base64_decode(bytes)?;
+                Self::try_from(decoded.as_slice())
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.encode())
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                // Both are over a kilobyte, their hash tells them apart just as well.
+                let hash = blake3::hash(&self.bytes);
+                f.debug_struct(stringify!($name))
+                    .field("blake3", &super::base64_encode(&hash.as_bytes()[..8]))
+                    .finish()
+            }
+        }
+    };
+}
+
+encodable!(EncapsulationKey);
+encodable!(KemCiphertext);
+#[cfg(test)]
+mod tests {
+    use sha3::{Digest, Sha3_256};
+
+    use super::*;
+
+    /// Seed `00 01 .. 3f`; key, ciphertext and secrets come from OpenSSL 3.5.
+    const SEED: [u8; SEED_LENGTH] = {
+        let mut seed = [0; SEED_LENGTH];
+        let mut i = 0;
+        while i < SEED_LENGTH {
+            seed[i] = i as u8;
+            i += 1;
+        }
+        seed
+    };
+    const PUBLIC_SHA3: &str = "a24e16d8f8f9383a95b77050f4d9fd2f5733eec1d63ef3c23ebf9918173669a7";
+    const CIPHERTEXT: &str = concat!(
+        "d933d61265c72e70577783a37000f2298be1765afacbf89bec3ddb7c0de06c6d",
+        "0186ffedec1c02e7ad5df20916a13450e2cbbe6f8da3f731f2e210976cc37674",
+        "c3eb08c64c3d70559f78b671e1728bf2b6e22301a4cf5d4df3bb2c57cc209306",
+        "2df50214f921ea2ce8bec57dcd76ce363da92a4fafe06f0fa3d6b131ad07523c",
+        "f01ee27268fe6420712bdf4db589023a2d4645244d18de3b1356a923a97e6757",
+        "74be535ea4861081a3f9a2b20d85fa9cbd4d7b753acef95040624b74a2e758a2",
+        "09d3ac57f02cbbaf8bd425a2461f5c50b9e405982ea3efab198723ad9c8c65cb",
+        "16bac0028e4246ae6f173003951b38ab041093d0c175dda1ba9ecbfa3a943892",
+        "f02cf58d0fc014bf6c53351093218a6d09307f0c56d14dc30fa6709e14f84612",
+        "70e0eaec5cda298cb92550ce122d924027816fc4ee454b4339476e5bb3d9c812",
+        "85b65de780034276a929d9b60303a380470432801a9cfb195809cf587b32f274",
+        "24a5afde8ebef98592c0ddcb26255233802c7370d387c161a0f401c095f9f529",
+        "ae6b001652bdf5a944b9edfc379127c6ba1b8e1fd01d8eadd9b719e928527d6a",
+        "f7164c2eacfade7d4f01d42a38fb22d0cd724e95bc8c0c03b96ef6b63c43830a",
+        "862e5b7e9b39b2f3e8e1533b6da51e0f73825af36413cc03105f6e0cf3478ba9",
+        "f8e6dbd66e551bffe3b94b1fbb145284095f5a9720eb8ac24e4becf349e28b7c",
+        "971f3f1bcb3b361da65af1a51ee869b6c233791a6adf063f5c429198b5f6632c",
+        "8da2fc9c8f02b2ba707139bef82f9ace29253e3b82be71aaedd755488c78390c",
+        "d53ef2743b9c2ec029bbec671a23a3f258e8698028cc75e103aedaab27274aad",
+        "715a82f36779b67c3b7084d456394221656e10c579fc8227dbf70936dc2a18b4",
+        "b267c7a26342082af6a1bbddb9b19317adc78c86483fa5bd737a71ffb9bf5f09",
+        "9a99fbd747dfec11e2d0fcc56638d477ea2cb47428dfef4648813ced35860e7a",
+        "91b946fef685535879a8bd351f45fc6cb819e6ef37022354d9ab8c27b5769700",
+        "26faa5f57e08d3f6f9494dcb6753dce6f05b32f7069d6c5b3a5bff9b61829cbe",
+        "bdd0ebfe1f0fe195a869839a3630881aece849a69de674263ef99b723b4b307e",
+        "2c44ad9cf37393ac63c45683baa78533cef1101fd738f4098e88a4d57741fcb0",
+        "ee1224f495e85f7df34d72a29bf894523b1d696a7ca88362e2e4e4a7142f5a32",
+        "8268de0a2b55d11acbbb737b567faa2f6821986b85cee70cf4508f199c17b5d3",
+        "bbb0546a470cd9c0b7b3419719996dfdf4d97b687fc7a21c7171e4aebbe491e9",
+        "fb097737c6a91d37a58438b0af840fad987909f9a87ede6704d338268d5c1ab4",
+        "4b5c067aaf68f9ee28d462c6e22da2d65b713f488e5c310d09bf03b27314da8b",
+        "138f8cc013792a223f10a44ed6d4a1b92eaf075e70d1dd40e08c63c327501b0b",
+        "c7ce137ed8204ff4ed24f69d6e7d459679079cf9e7781bd55e82a23d9d75fbee",
+        "7980fc5c08fda7ce22c31291dfd5b056d3d245d0f902f24770eef0e1e3b132df",
+    );
+    const SECRET: &str = "3834cddfaa3331b7198412e2c600a0015b91d132edad630619366c1057161e50";
+    /// What the ciphertext with its 6th byte flipped decapsulates to.
+    const REJECTED: &str = "f2e0d475f1e798c9ac1e7b6310355564dc261d9183ac4700b24e1b423c2c9398";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn known_answer() {
+        let pair = KemKeyPair::from_seed(SEED);
+        assert_eq!(ENCAPSULATION_KEY_LENGTH, pair.public().as_ref().len());
+        assert_eq!(PUBLIC_SHA3, hex(&Sha3_256::digest(pair.public())));
+
+        let ciphertext = KemCiphertext::try_from(unhex(CIPHERTEXT).as_slice()).unwrap();
+        assert_eq!(SECRET, hex(&*pair.decapsulate(&ciphertext)));
+    }
+
+    #[test]
+    fn implicit_rejection() {
+        let pair = KemKeyPair::from_seed(SEED);
+        let mut tampered = unhex(CIPHERTEXT);
+        tampered[5] ^= 1;
+        let tampered = KemCiphertext::try_from(tampered.as_slice()).unwrap();
+        assert_eq!(REJECTED, hex(&*pair.decapsulate(&tampered)));
+    }
+
+    #[test]
+    fn agree() {
+        let pair = KemKeyPair::new();
+        let public = EncapsulationKey::try_decode(pair.public().encode()).unwrap();
+        let (ciphertext, secret) = public.encapsulate();
+        let ciphertext = KemCiphertext::try_decode(ciphertext.encode()).unwrap();
+        assert_eq!(CIPHERTEXT_LENGTH, ciphertext.as_ref().len());
+        assert_eq!(secret, pair.decapsulate(&ciphertext));
+
+        // Someone else's key pair gets nothing out of it.
+        assert_ne!(secret, KemKeyPair::new().decapsulate(&ciphertext));
+        assert_eq!(pair.public(), KemKeyPair::from_seed(*pair.seed()).public());
+    }
+
+    #[test]
+    fn encapsulation_key_checks() {
+        let pair = KemKeyPair::new();
+        let bytes = pair.public().as_ref();
+        assert!(EncapsulationKey::try_from(&bytes[1..]).is_err());
+        assert!(KemCiphertext::try_from(&bytes[..CIPHERTEXT_LENGTH - 1]).is_err());
+
+        // A coefficient of 0xfff isn't reduced modulo q.
+        let mut unreduced = bytes.to_vec();
+        unreduced[0] = 0xff;
+        unreduced[1] |= 0x0f;
+        assert!(matches!(
+            EncapsulationKey::try_from(unreduced.as_slice()),
+            Err(Error::Crypto(_))
+        ));
+    }
+}
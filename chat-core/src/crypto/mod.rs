@@ -1,9 +1,22 @@
 use crate::prelude::*;
 
 mod _crypto;
+mod identity;
+mod key_schedule;
+pub mod ml_kem;
+mod ratchet;
+mod replay;
+mod sender_key;
 mod types;
+pub mod x3dh;
 
 pub use _crypto::Crypto;
+pub use identity::{generate_identity, load_identity};
+pub use key_schedule::{KeySchedule, TransportKeys};
+pub use ml_kem::{EncapsulationKey, KemCiphertext, KemKeyPair};
+pub use ratchet::{DoubleRatchet, MAX_SKIP};
+pub use replay::{ReplayWindow, REPLAY_WINDOW};
+pub use sender_key::{SenderChain, SenderKey, SenderKeyDistribution};
 pub use types::*;
 
 pub trait Encodable
@@ -15,8 +28,28 @@ where
 }
 
 pub trait CryptoSchema {
-    fn encrypt(&self, key: &SecretKey, blob: &[u8]) -> Result<Vec<u8>>;
-    fn decrypt(&self, key: &SecretKey, blob: &[u8]) -> Result<Vec<u8>>;
+    fn encrypt(&self, key: &SecretKey, blob: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_with_aad(key, blob, &[])
+    }
+    fn decrypt(&self, key: &SecretKey, blob: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_with_aad(key, blob, &[])
+    }
+    /// Like [`Self::encrypt`], but `aad` is authenticated along with the blob
+    /// without being encrypted or sent, so the receiver has to know it.
+    fn encrypt_with_aad(&self, key: &SecretKey, blob: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+    fn decrypt_with_aad(&self, key: &SecretKey, blob: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+    /// Like [`Self::encrypt_with_aad`], but the nonce is derived from
+    /// `sequence`, so a key must never seal the same sequence number twice.
+    fn encrypt_sequenced(
+        &self,
+        key: &SecretKey,
+        sequence: u64,
+        blob: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>>;
+    /// Reads the sequence number of a blob from [`Self::encrypt_sequenced`]
+    /// without authenticating it.
+    fn sequence_of(&self, blob: &[u8]) -> Result<u64>;
 
     fn key_derivation(&self, pwd: &[u8], salt: &[u8]) -> Result<SecretKey>;
     fn hash_password(&self, plain_password: &[u8]) -> Result<String>;
@@ -24,6 +57,25 @@ pub trait CryptoSchema {
     fn hash(&self, blob: &[u8]) -> [u8; 32];
 
     fn compute_dh(&self, secret: &SecretKey, public: &PublicKey) -> SharedSecret;
+    /// A fresh secret for the owner of `public`, and the ciphertext they get
+    /// it back from, see [`ml_kem`].
+    fn encapsulate(&self, public: &EncapsulationKey) -> (KemCiphertext, SharedSecret);
+    fn decapsulate(&self, pair: &KemKeyPair, ciphertext: &KemCiphertext) -> SharedSecret;
+
+    /// Signs `blob` with the secret half of an identity key pair, see
+    /// [`KeyPair::new_signing`].
+    fn sign(&self, secret: &SecretKey, blob: &[u8]) -> Signature;
+    fn verify(&self, public: &PublicKey, blob: &[u8], signature: &Signature) -> Result<()>;
+}
+
+/// X25519 form of an Ed25519 identity key, see [`KeyPair::to_dh`].
+///
+/// # Errors
+///
+/// This function will return an error if `identity` isn't a valid Ed25519 key.
+pub fn identity_to_dh(identity: &PublicKey) -> Result<PublicKey> {
+    let key = ed25519_dalek::VerifyingKey::from_bytes(identity).map_err(Error::crypto)?;
+    Ok(key.to_montgomery().to_bytes().into())
 }
 
 pub fn base64_encode<T: AsRef<[u8]>>(blob: T) -> String {
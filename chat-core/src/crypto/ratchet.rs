@@ -0,0 +1,349 @@
+//! Double Ratchet for sessions between two clients
+//!
+//! Every message is encrypted with its own key. Keys move forward along a
+//! symmetric chain with each message and the chains are replaced with fresh
+//! Diffie-Hellman outputs whenever the conversation changes direction, so a
+//! leaked key exposes neither earlier nor later messages. See
+//! <https://signal.org/docs/specifications/doubleratchet/>.
+//!
+//! Both sides start from the secret of a handshake in which each learnt the
+//! other's key pair: the side which started it is the initiator, the other
+//! is the responder. Either side may send first.
+
+use std::collections::BTreeMap;
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use super::{CryptoSchema, KeyPair, PublicKey, SecretKey, SharedSecret, CRYPTO_KEY_LENGTH};
+use crate::prelude::*;
+
+const ROOT: &[u8] = b"CORE_RATCHET_ROOT";
+const CHAIN: &[u8] = b"CORE_RATCHET_CHAIN";
+const MESSAGE: &[u8] = b"CORE_RATCHET_MESSAGE";
+/// Chain for what the responder sends before it hears from the initiator.
+const RESPONDER: &[u8] = b"CORE_RATCHET_RESPONDER";
+
+/// How many keys of messages which haven't arrived yet may be kept.
+pub const MAX_SKIP: u32 = 1000;
+
+const HEADER_LENGTH: usize = CRYPTO_KEY_LENGTH + 2 * std::mem::size_of::<u32>();
+
+/// State of one side of a session.
+#[derive(Clone)]
+pub struct DoubleRatchet {
+    /// Our current ratchet key pair.
+    own: KeyPair,
+    /// The other side's current ratchet key.
+    remote: Option<PublicKey>,
+    root: SharedSecret,
+    send_chain: SharedSecret,
+    recv_chain: Option<SharedSecret>,
+    /// Number of the next message in the sending chain.
+    sent: u32,
+    /// Number of the next message in the receiving chain.
+    received: u32,
+    /// Length of the previous sending chain.
+    previous: u32,
+    /// Keys of messages which were skipped over, by their ratchet key and
+    /// number.
+    skipped: BTreeMap<(PublicKey, u32), SecretKey>,
+}
+
+impl DoubleRatchet {
+    /// State of the side which started the handshake and got `remote` in
+    /// reply.
+    pub fn initiator<C: CryptoSchema>(
+        crypto: &C,
+        secret: &SharedSecret,
+        remote: PublicKey,
+    ) -> Self {
+        let own = KeyPair::new_dh();
+        let (root, send_chain) = kdf_root(secret, &crypto.compute_dh(own.secret(), &remote));
+        Self {
+            own,
+            remote: Some(remote),
+            root,
+            send_chain,
+            recv_chain: Some(expand(secret, RESPONDER)),
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    /// State of the side which answered the handshake with `own`.
+    pub fn responder(secret: &SharedSecret, own: KeyPair) -> Self {
+        Self {
+            own,
+            remote: None,
+            root: secret.clone(),
+            send_chain: expand(secret, RESPONDER),
+            recv_chain: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    /// Encrypts the next message; the header it needs is part of the output.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the encryption fails.
+    pub fn encrypt<C: CryptoSchema>(&mut self, crypto: &C, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let (send_chain, key) = kdf_chain(&self.send_chain);
+        let header = Header {
+            key: *self.own.public(),
+            previous: self.previous,
+            number: self.sent,
+        }
+        .encode();
+
+        let ciphertext = crypto.encrypt_with_aad(&key, plaintext, &header)?;
+        self.send_chain = send_chain;
+        self.sent = self
+            .sent
+            .checked_add(1)
+            .ok_or_else(|| Error::crypto("sending chain is exhausted"))?;
+        Ok([header.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts a message from [`Self::encrypt`], which may arrive out of
+    /// order. The state is left untouched if it fails.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`Error::Replayed`] for a message which was
+    /// already decrypted, or an error if it can't be decrypted or too many
+    /// messages before it are missing.
+    pub fn decrypt<C: CryptoSchema>(&mut self, crypto: &C, message: &[u8]) -> Result<Vec<u8>> {
+        if message.len() < HEADER_LENGTH {
+            return Err(Error::decode("message is shorter than its header"));
+        }
+        let (header, ciphertext) = message.split_at(HEADER_LENGTH);
+
+        let mut next = self.clone();
+        let plaintext = next.decrypt_with(crypto, Header::decode(header)?, header, ciphertext)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_with<C: CryptoSchema>(
+        &mut self,
+        crypto: &C,
+        header: Header,
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>> {
+        if let Some(key) = self.skipped.remove(&(header.key, header.number)) {
+            return crypto.decrypt_with_aad(&key, ciphertext, aad);
+        }
+
+        if self.remote != Some(header.key) {
+            self.skip(header.previous)?;
+            self.step(crypto, header.key);
+        } else if header.number < self.received {
+            return Err(Error::Replayed(header.number.into()));
+        }
+        self.skip(header.number)?;
+
+        let recv_chain = self.recv_chain.as_ref().expect("chain exists after a step");
+        let (recv_chain, key) = kdf_chain(recv_chain);
+        self.recv_chain = Some(recv_chain);
+        self.received += 1;
+        crypto.decrypt_with_aad(&key, ciphertext, aad)
+    }
+
+    /// Stores the keys of the receiving chain up to message `until`.
+    fn skip(&mut self, until: u32) -> Result<()> {
+        let (Some(remote), Some(mut recv_chain)) = (self.remote, self.recv_chain.clone()) else {
+            return Ok(());
+        };
+        let missing = u64::from(until.saturating_sub(self.received));
+        if self.skipped.len() as u64 + missing > MAX_SKIP.into() {
+            return Err(Error::crypto("too many messages are missing"));
+        }
+        while self.received < until {
+            let (next, key) = kdf_chain(&recv_chain);
+            self.skipped.insert((remote, self.received), key);
+            recv_chain = next;
+            self.received += 1;
+        }
+        self.recv_chain = Some(recv_chain);
+        Ok(())
+    }
+
+    /// The other side has a new ratchet key: derive a receiving chain from
+    /// it and answer with a new key pair and sending chain.
+    fn step<C: CryptoSchema>(&mut self, crypto: &C, remote: PublicKey) {
+        self.previous = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.remote = Some(remote);
+
+        let (root, recv_chain) =
+            kdf_root(&self.root, &crypto.compute_dh(self.own.secret(), &remote));
+        self.own = KeyPair::new_dh();
+        let (root, send_chain) = kdf_root(&root, &crypto.compute_dh(self.own.secret(), &remote));
+        self.root = root;
+        self.recv_chain = Some(recv_chain);
+        self.send_chain = send_chain;
+    }
+}
+
+impl std::fmt::Debug for DoubleRatchet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DoubleRatchet")
+            .field("sent", &self.sent)
+            .field("received", &self.received)
+            .field("skipped", &self.skipped.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sent in the clear in front of every message, but authenticated with it.
+struct Header {
+    key: PublicKey,
+    previous: u32,
+    number: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LENGTH] {
+        let mut bytes = [0; HEADER_LENGTH];
+        bytes[..CRYPTO_KEY_LENGTH].copy_from_slice(self.key.as_ref());
+        bytes[CRYPTO_KEY_LENGTH..CRYPTO_KEY_LENGTH + 4]
+            .copy_from_slice(&self.previous.to_be_bytes());
+        bytes[CRYPTO_KEY_LENGTH + 4..].copy_from_slice(&self.number.to_be_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let (key, counters) = bytes.split_at(CRYPTO_KEY_LENGTH);
+        let (previous, number) = counters.split_at(4);
+        Ok(Self {
+            key: PublicKey::try_from(key)?,
+            previous: u32::from_be_bytes(previous.try_into().map_err(Error::decode)?),
+            number: u32::from_be_bytes(number.try_into().map_err(Error::decode)?),
+        })
+    }
+}
+
+/// Mixes a Diffie-Hellman output into the root key, giving the next root
+/// key and a new chain.
+fn kdf_root(root: &SharedSecret, dh: &SharedSecret) -> (SharedSecret, SharedSecret) {
+    let mut keys = [0; 2 * CRYPTO_KEY_LENGTH];
+    Hkdf::<Sha256>::new(Some(root.as_ref()), dh.as_ref())
+        .expand(ROOT, &mut keys)
+        .expect("key length is valid for HKDF-SHA256");
+    let (root, chain) = keys.split_at(CRYPTO_KEY_LENGTH);
+    (
+        SharedSecret::new(root.try_into().expect("halves are key long")),
+        SharedSecret::new(chain.try_into().expect("halves are key long")),
+    )
+}
+
+/// Moves a chain one message forward, giving its next key and the key of
+/// the message.
+fn kdf_chain(chain: &SharedSecret) -> (SharedSecret, SecretKey) {
+    (expand(chain, CHAIN), expand(chain, MESSAGE))
+}
+
+pub(super) fn expand<K: From<[u8; CRYPTO_KEY_LENGTH]>>(key: &SharedSecret, label: &[u8]) -> K {
+    let hkdf = Hkdf::<Sha256>::from_prk(key.as_ref()).expect("key is as long as the hash");
+    let mut expanded = [0; CRYPTO_KEY_LENGTH];
+    hkdf.expand(label, &mut expanded)
+        .expect("key length is valid for HKDF-SHA256");
+    K::from(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Crypto;
+
+    fn session() -> (DoubleRatchet, DoubleRatchet) {
+        let (initiator, responder) = (KeyPair::new_dh(), KeyPair::new_dh());
+        let secret = Crypto.compute_dh(initiator.secret(), responder.public());
+        (
+            DoubleRatchet::initiator(&Crypto, &secret, *responder.public()),
+            DoubleRatchet::responder(&secret, responder),
+        )
+    }
+
+    fn roundtrip(from: &mut DoubleRatchet, to: &mut DoubleRatchet, text: &[u8]) {
+        let message = from.encrypt(&Crypto, text).unwrap();
+        assert_eq!(to.decrypt(&Crypto, &message).unwrap(), text);
+    }
+
+    #[test]
+    fn conversation() {
+        let (mut alice, mut bob) = session();
+        roundtrip(&mut alice, &mut bob, b"one");
+        roundtrip(&mut alice, &mut bob, b"two");
+        roundtrip(&mut bob, &mut alice, b"three");
+        roundtrip(&mut alice, &mut bob, b"four");
+        roundtrip(&mut bob, &mut alice, b"five");
+        roundtrip(&mut bob, &mut alice, b"six");
+    }
+
+    #[test]
+    fn responder_first() {
+        let (mut alice, mut bob) = session();
+        roundtrip(&mut bob, &mut alice, b"one");
+        roundtrip(&mut alice, &mut bob, b"two");
+        roundtrip(&mut bob, &mut alice, b"three");
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (mut alice, mut bob) = session();
+        let one = alice.encrypt(&Crypto, b"one").unwrap();
+        let two = alice.encrypt(&Crypto, b"two").unwrap();
+        roundtrip(&mut bob, &mut alice, b"reply");
+        let three = alice.encrypt(&Crypto, b"three").unwrap();
+
+        // The last one arrives first, the earlier chain is skipped over.
+        assert_eq!(bob.decrypt(&Crypto, &three).unwrap(), b"three");
+        assert_eq!(bob.decrypt(&Crypto, &two).unwrap(), b"two");
+        assert_eq!(bob.decrypt(&Crypto, &one).unwrap(), b"one");
+        assert!(bob.skipped.is_empty());
+    }
+
+    #[test]
+    fn replayed() {
+        let (mut alice, mut bob) = session();
+        let one = alice.encrypt(&Crypto, b"one").unwrap();
+        let two = alice.encrypt(&Crypto, b"two").unwrap();
+        bob.decrypt(&Crypto, &one).unwrap();
+        bob.decrypt(&Crypto, &two).unwrap();
+        assert_eq!(bob.decrypt(&Crypto, &one), Err(Error::Replayed(0)));
+        assert_eq!(bob.decrypt(&Crypto, &two), Err(Error::Replayed(1)));
+    }
+
+    #[test]
+    fn tampered() {
+        let (mut alice, mut bob) = session();
+        let mut message = alice.encrypt(&Crypto, b"one").unwrap();
+        // Claim a later number, which would make Bob skip keys.
+        message[HEADER_LENGTH - 1] = 5;
+        assert!(bob.decrypt(&Crypto, &message).is_err());
+        assert!(bob.skipped.is_empty());
+        assert_eq!(bob.remote, None);
+        roundtrip(&mut alice, &mut bob, b"two");
+    }
+
+    #[test]
+    fn too_many_skipped() {
+        let (mut alice, mut bob) = session();
+        roundtrip(&mut alice, &mut bob, b"first");
+        for _ in 0..=MAX_SKIP {
+            alice.encrypt(&Crypto, b"lost").unwrap();
+        }
+        let last = alice.encrypt(&Crypto, b"last").unwrap();
+        assert!(matches!(bob.decrypt(&Crypto, &last), Err(Error::Crypto(_))));
+    }
+}
@@ -0,0 +1,107 @@
+//! Telling fresh frames from replayed ones
+//!
+//! Every frame of a connection carries its sequence number, see
+//! [`TransportKeys::seal`](super::TransportKeys::seal). Frames may arrive out
+//! of order, e.g. over different QUIC streams, so instead of demanding the
+//! next number the receiver remembers which of the last [`REPLAY_WINDOW`]
+//! numbers it has already seen.
+
+use std::collections::BTreeSet;
+
+use crate::prelude::*;
+
+/// How far behind the newest frame an older one may still arrive.
+pub const REPLAY_WINDOW: u64 = 1024;
+
+/// Sequence numbers accepted so far in one direction.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReplayWindow {
+    highest: Option<u64>,
+    seen: BTreeSet<u64>,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks `sequence` without remembering it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `sequence` was already accepted
+    /// or is too old to tell.
+    pub fn check(&self, sequence: u64) -> Result<()> {
+        let Some(highest) = self.highest else {
+            return Ok(());
+        };
+        if highest >= REPLAY_WINDOW && sequence <= highest - REPLAY_WINDOW {
+            return Err(Error::OutOfWindow(sequence));
+        }
+        if self.seen.contains(&sequence) {
+            return Err(Error::Replayed(sequence));
+        }
+        Ok(())
+    }
+
+    /// Remembers `sequence`; call it only once the frame was authenticated.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Self::check`].
+    pub fn accept(&mut self, sequence: u64) -> Result<()> {
+        self.check(sequence)?;
+        self.seen.insert(sequence);
+
+        let highest = self
+            .highest
+            .map_or(sequence, |highest| highest.max(sequence));
+        self.highest = Some(highest);
+        if highest >= REPLAY_WINDOW {
+            // Everything at or below the edge is rejected anyway.
+            self.seen = self.seen.split_off(&(highest - REPLAY_WINDOW + 1));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut window = ReplayWindow::new();
+        for sequence in 0..3 * REPLAY_WINDOW {
+            assert_eq!(window.accept(sequence), Ok(()));
+        }
+        assert!(window.seen.len() as u64 <= REPLAY_WINDOW);
+    }
+
+    #[test]
+    fn replayed() {
+        let mut window = ReplayWindow::new();
+        window.accept(0).unwrap();
+        window.accept(1).unwrap();
+        assert_eq!(window.accept(0), Err(Error::Replayed(0)));
+        assert_eq!(window.accept(1), Err(Error::Replayed(1)));
+    }
+
+    #[test]
+    fn reordered() {
+        let mut window = ReplayWindow::new();
+        window.accept(5).unwrap();
+        window.accept(2).unwrap();
+        window.accept(4).unwrap();
+        assert_eq!(window.accept(2), Err(Error::Replayed(2)));
+        assert_eq!(window.accept(3), Ok(()));
+    }
+
+    #[test]
+    fn out_of_window() {
+        let mut window = ReplayWindow::new();
+        window.accept(REPLAY_WINDOW + 10).unwrap();
+        assert_eq!(window.accept(10), Err(Error::OutOfWindow(10)));
+        assert_eq!(window.accept(11), Ok(()));
+    }
+}
@@ -0,0 +1,360 @@
+//! Sender keys for rooms with many members
+//!
+//! Instead of encrypting every message once per member, each member encrypts
+//! with its own sending chain and hands the chain to the others over the
+//! pairwise sessions it has with them, see [`super::DoubleRatchet`]. Keys move
+//! forward along the chain with every message, so a member who joins later
+//! can't read what was sent before. Messages are signed, so that members
+//! can't forge each other's messages with the chain they share. The chain is
+//! replaced with a new generation whenever a member leaves. See
+//! <https://signal.org/docs/specifications/group-messaging/>.
+
+use std::collections::BTreeMap;
+
+use rand_core::{OsRng, RngCore};
+
+use super::{
+    ratchet::expand, CryptoSchema, KeyPair, PublicKey, SecretKey, SharedSecret, Signature,
+    CRYPTO_KEY_LENGTH, MAX_SKIP, SIGNATURE_LENGTH,
+};
+use crate::prelude::*;
+
+const CHAIN: &[u8] = b"CORE_SENDER_CHAIN";
+const MESSAGE: &[u8] = b"CORE_SENDER_MESSAGE";
+
+const HEADER_LENGTH: usize = 2 * std::mem::size_of::<u32>();
+const DISTRIBUTION_LENGTH: usize = HEADER_LENGTH + 2 * CRYPTO_KEY_LENGTH;
+
+/// Sending chain of this member.
+#[derive(Clone)]
+pub struct SenderKey {
+    generation: u32,
+    chain: SharedSecret,
+    /// Number of the next message.
+    iteration: u32,
+    signing: KeyPair,
+}
+
+impl SenderKey {
+    pub fn new() -> Self {
+        Self::with_generation(0)
+    }
+
+    fn with_generation(generation: u32) -> Self {
+        let mut chain = [0; CRYPTO_KEY_LENGTH];
+        OsRng.fill_bytes(&mut chain);
+        Self {
+            generation,
+            chain: SharedSecret::new(chain),
+            iteration: 0,
+            signing: KeyPair::new_signing(),
+        }
+    }
+
+    /// A fresh chain of the next generation, members who got this one can't
+    /// read what's encrypted with it.
+    pub fn rotate(&self) -> Self {
+        Self::with_generation(self.generation.wrapping_add(1))
+    }
+
+    pub const fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// What members need to decrypt messages from now on.
+    pub fn distribution(&self) -> SenderKeyDistribution {
+        SenderKeyDistribution {
+            generation: self.generation,
+            iteration: self.iteration,
+            chain: self.chain.clone(),
+            signing_key: *self.signing.public(),
+        }
+    }
+
+    /// Encrypts and signs the next message; the header it needs is part of
+    /// the output.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the encryption fails.
+    pub fn encrypt<C: CryptoSchema>(&mut self, crypto: &C, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let (chain, key) = kdf_chain(&self.chain);
+        let header = encode_header(self.generation, self.iteration);
+
+        let ciphertext = crypto.encrypt_with_aad(&key, plaintext, &header)?;
+        let mut message = [header.as_slice(), &ciphertext].concat();
+        let signature = crypto.sign(self.signing.secret(), &message);
+        message.extend_from_slice(signature.as_ref());
+
+        self.chain = chain;
+        self.iteration = self
+            .iteration
+            .checked_add(1)
+            .ok_or_else(|| Error::crypto("sending chain is exhausted"))?;
+        Ok(message)
+    }
+}
+
+impl Default for SenderKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for SenderKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SenderKey")
+            .field("generation", &self.generation)
+            .field("iteration", &self.iteration)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sending chain of a member as it's handed to the others.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SenderKeyDistribution {
+    generation: u32,
+    /// Number of the next message, earlier ones can't be decrypted.
+    iteration: u32,
+    chain: SharedSecret,
+    signing_key: PublicKey,
+}
+
+impl SenderKeyDistribution {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            encode_header(self.generation, self.iteration).as_slice(),
+            self.chain.as_ref(),
+            self.signing_key.as_ref(),
+        ]
+        .concat()
+    }
+
+    /// # Errors
+    ///
+    /// This function will return an error if `bytes` aren't from
+    /// [`Self::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != DISTRIBUTION_LENGTH {
+            return Err(Error::decode("sender key has a wrong length"));
+        }
+        let (header, keys) = bytes.split_at(HEADER_LENGTH);
+        let (generation, iteration) = decode_header(header)?;
+        let (chain, signing_key) = keys.split_at(CRYPTO_KEY_LENGTH);
+        Ok(Self {
+            generation,
+            iteration,
+            chain: SharedSecret::try_from(chain)?,
+            signing_key: PublicKey::try_from(signing_key)?,
+        })
+    }
+}
+
+impl std::fmt::Debug for SenderKeyDistribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SenderKeyDistribution")
+            .field("generation", &self.generation)
+            .field("iteration", &self.iteration)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Receiving chain of another member.
+#[derive(Clone)]
+pub struct SenderChain {
+    generation: u32,
+    chain: SharedSecret,
+    /// Number of the next message.
+    iteration: u32,
+    signing_key: PublicKey,
+    /// Keys of messages which were skipped over, by their number.
+    skipped: BTreeMap<u32, SecretKey>,
+}
+
+impl SenderChain {
+    pub fn new(distribution: SenderKeyDistribution) -> Self {
+        Self {
+            generation: distribution.generation,
+            chain: distribution.chain,
+            iteration: distribution.iteration,
+            signing_key: distribution.signing_key,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    pub const fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Verifies and decrypts a message from [`SenderKey::encrypt`], which may
+    /// arrive out of order. The state is left untouched if it fails.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`Error::Replayed`] for a message which was
+    /// already decrypted, or an error if it's from another generation, isn't
+    /// signed by the sender, can't be decrypted or too many messages before it
+    /// are missing.
+    pub fn decrypt<C: CryptoSchema>(&mut self, crypto: &C, message: &[u8]) -> Result<Vec<u8>> {
+        if message.len() < HEADER_LENGTH + SIGNATURE_LENGTH {
+            return Err(Error::decode("message is shorter than its header"));
+        }
+        let (signed, signature) = message.split_at(message.len() - SIGNATURE_LENGTH);
+        crypto.verify(&self.signing_key, signed, &Signature::try_from(signature)?)?;
+        let (header, ciphertext) = signed.split_at(HEADER_LENGTH);
+        let (generation, iteration) = decode_header(header)?;
+        if generation != self.generation {
+            return Err(Error::crypto(format!(
+                "message is from generation {generation} of the sender key, not {}",
+                self.generation
+            )));
+        }
+
+        if let Some(key) = self.skipped.get(&iteration) {
+            let plaintext = crypto.decrypt_with_aad(key, ciphertext, header)?;
+            self.skipped.remove(&iteration);
+            return Ok(plaintext);
+        }
+        if iteration < self.iteration {
+            return Err(Error::Replayed(iteration.into()));
+        }
+        let missing = u64::from(iteration - self.iteration);
+        if self.skipped.len() as u64 + missing > MAX_SKIP.into() {
+            return Err(Error::crypto("too many messages are missing"));
+        }
+
+        let mut chain = self.chain.clone();
+        let mut skipped = Vec::new();
+        for number in self.iteration..iteration {
+            let (next, key) = kdf_chain(&chain);
+            skipped.push((number, key));
+            chain = next;
+        }
+        let (next, key) = kdf_chain(&chain);
+        let plaintext = crypto.decrypt_with_aad(&key, ciphertext, header)?;
+
+        self.skipped.extend(skipped);
+        self.chain = next;
+        self.iteration = iteration + 1;
+        Ok(plaintext)
+    }
+}
+
+impl std::fmt::Debug for SenderChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SenderChain")
+            .field("generation", &self.generation)
+            .field("iteration", &self.iteration)
+            .field("skipped", &self.skipped.len())
+            .finish_non_exhaustive()
+    }
+}
+
+fn kdf_chain(chain: &SharedSecret) -> (SharedSecret, SecretKey) {
+    (expand(chain, CHAIN), expand(chain, MESSAGE))
+}
+
+fn encode_header(generation: u32, iteration: u32) -> [u8; HEADER_LENGTH] {
+    let mut bytes = [0; HEADER_LENGTH];
+    bytes[..4].copy_from_slice(&generation.to_be_bytes());
+    bytes[4..].copy_from_slice(&iteration.to_be_bytes());
+    bytes
+}
+
+fn decode_header(bytes: &[u8]) -> Result<(u32, u32)> {
+    let (generation, iteration) = bytes.split_at(4);
+    Ok((
+        u32::from_be_bytes(generation.try_into().map_err(Error::decode)?),
+        u32::from_be_bytes(iteration.try_into().map_err(Error::decode)?),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Crypto;
+
+    fn received(key: &SenderKey) -> SenderChain {
+        let bytes = key.distribution().to_bytes();
+        SenderChain::new(SenderKeyDistribution::from_bytes(&bytes).unwrap())
+    }
+
+    #[test]
+    fn many_members() {
+        let mut alice = SenderKey::new();
+        let mut bob = received(&alice);
+        let mut carol = received(&alice);
+
+        for text in [b"one", b"two"] {
+            let message = alice.encrypt(&Crypto, text).unwrap();
+            assert_eq!(bob.decrypt(&Crypto, &message).unwrap(), text);
+            assert_eq!(carol.decrypt(&Crypto, &message).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn joined_later() {
+        let mut alice = SenderKey::new();
+        let before = alice.encrypt(&Crypto, b"before").unwrap();
+        let mut bob = received(&alice);
+        let after = alice.encrypt(&Crypto, b"after").unwrap();
+
+        assert!(bob.decrypt(&Crypto, &before).is_err());
+        assert_eq!(bob.decrypt(&Crypto, &after).unwrap(), b"after");
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut alice = SenderKey::new();
+        let mut bob = received(&alice);
+        let one = alice.encrypt(&Crypto, b"one").unwrap();
+        let two = alice.encrypt(&Crypto, b"two").unwrap();
+
+        assert_eq!(bob.decrypt(&Crypto, &two).unwrap(), b"two");
+        assert_eq!(bob.decrypt(&Crypto, &one).unwrap(), b"one");
+        assert!(bob.skipped.is_empty());
+        assert_eq!(bob.decrypt(&Crypto, &one), Err(Error::Replayed(0)));
+        assert_eq!(bob.decrypt(&Crypto, &two), Err(Error::Replayed(1)));
+    }
+
+    #[test]
+    fn forged() {
+        let alice = SenderKey::new();
+        let mut bob = received(&alice);
+        // Carol has the chain of Alice, but not her signing key.
+        let mut carol = alice.clone();
+        carol.signing = KeyPair::new_signing();
+
+        let forged = carol.encrypt(&Crypto, b"forged").unwrap();
+        assert!(bob.decrypt(&Crypto, &forged).is_err());
+        assert_eq!(bob.iteration, 0);
+    }
+
+    #[test]
+    fn rotated() {
+        let mut alice = SenderKey::new();
+        let mut bob = received(&alice);
+        let mut rotated = alice.rotate();
+        assert_eq!(rotated.generation(), alice.generation() + 1);
+
+        // Bob left, so he doesn't get the new generation.
+        let mut carol = received(&rotated);
+        let message = rotated.encrypt(&Crypto, b"secret").unwrap();
+        assert!(bob.decrypt(&Crypto, &message).is_err());
+        assert_eq!(carol.decrypt(&Crypto, &message).unwrap(), b"secret");
+
+        let old = alice.encrypt(&Crypto, b"old").unwrap();
+        assert!(carol.decrypt(&Crypto, &old).is_err());
+    }
+
+    #[test]
+    fn too_many_skipped() {
+        let mut alice = SenderKey::new();
+        let mut bob = received(&alice);
+        for _ in 0..=MAX_SKIP {
+            alice.encrypt(&Crypto, b"lost").unwrap();
+        }
+        let message = alice.encrypt(&Crypto, b"late").unwrap();
+        assert!(bob.decrypt(&Crypto, &message).is_err());
+    }
+}
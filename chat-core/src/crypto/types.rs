@@ -1,72 +1,196 @@
-use std::{
-    fmt,
-    ops::{Deref, DerefMut},
-};
+use std::{fmt, ops::Deref};
+
+use subtle::{Choice, ConstantTimeEq};
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 use crate::{crypto::Encodable, prelude::*};
 
 pub const CRYPTO_KEY_LENGTH: usize = 32;
-/// base64 url-safe encoded length
+// base64 url-safe encoded length
 // pub const CRYPTO_KEY_LENGTH_ENCODED: usize = 43;
 
+/// Implements what every 32-byte key shares.
+macro_rules! key {
+    ($name:ident) => {
+        impl $name {
+            pub const fn new(bytes: [u8; CRYPTO_KEY_LENGTH]) -> Self {
+                Self { bytes }
+            }
+        }
+
+        impl Deref for $name {
+            type Target = [u8; CRYPTO_KEY_LENGTH];
+            fn deref(&self) -> &Self::Target {
+                &self.bytes
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.bytes
+            }
+        }
+
+        impl From<[u8; CRYPTO_KEY_LENGTH]> for $name {
+            fn from(bytes: [u8; CRYPTO_KEY_LENGTH]) -> Self {
+                Self { bytes }
+            }
+        }
+
+        impl TryFrom<&[u8]> for $name {
+            type Error = Error;
+
+            fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+                let len = value.len();
+                let bytes: [u8; CRYPTO_KEY_LENGTH] = value.try_into().map_err(|_| {
+                    Error::generic(format!(
+                        "Expected a slice of length {CRYPTO_KEY_LENGTH} but it was {len}"
+                    ))
+                })?;
+                Ok(Self { bytes })
+            }
+        }
+
+        impl Encodable for $name {
+            fn encode(&self) -> String {
+                super::base64_encode(self)
+            }
+
+            fn try_decode<T: AsRef<[u8]>>(bytes: T) -> Result<Self> {
+                let decoded = super::base64_decode(bytes)?;
+                Self::try_from(decoded.as_slice())
+            }
+        }
+    };
+}
+
+/// Implements what secrets share on top of [`key!`]: they are wiped once
+/// dropped, compared in constant time and never formatted. Storing one takes
+/// an explicit [`Encodable::encode`].
+macro_rules! secret {
+    ($name:ident) => {
+        key!($name);
+
+        impl Zeroize for $name {
+            fn zeroize(&mut self) {
+                self.bytes.zeroize();
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                self.zeroize();
+            }
+        }
+
+        impl ZeroizeOnDrop for $name {}
+
+        impl ConstantTimeEq for $name {
+            fn ct_eq(&self, other: &Self) -> Choice {
+                self.bytes.ct_eq(&other.bytes)
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.ct_eq(other).into()
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(concat!(stringify!($name), "(<redacted>)"))
+            }
+        }
+    };
+}
+
+/// Public half of an X25519 or Ed25519 key pair.
 #[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct CryptoKey {
+pub struct PublicKey {
     bytes: [u8; CRYPTO_KEY_LENGTH],
 }
 
-pub type SecretKey = CryptoKey;
-pub type PublicKey = CryptoKey;
-pub type SharedSecret = CryptoKey;
+key!(PublicKey);
 
-impl CryptoKey {
-    pub const fn new(bytes: [u8; CRYPTO_KEY_LENGTH]) -> Self {
-        Self { bytes }
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.encode())
     }
 }
 
-impl Deref for CryptoKey {
-    type Target = [u8; CRYPTO_KEY_LENGTH];
-    fn deref(&self) -> &Self::Target {
-        &self.bytes
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PublicKey").field(&self.encode()).finish()
     }
 }
 
-impl DerefMut for CryptoKey {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.bytes
+/// Secret half of an X25519 or Ed25519 key pair, or a symmetric key.
+#[derive(Clone)]
+pub struct SecretKey {
+    bytes: [u8; CRYPTO_KEY_LENGTH],
+}
+
+secret!(SecretKey);
+
+/// Output of a key agreement, which keys are derived from.
+#[derive(Clone)]
+pub struct SharedSecret {
+    bytes: [u8; CRYPTO_KEY_LENGTH],
+}
+
+secret!(SharedSecret);
+
+pub const SIGNATURE_LENGTH: usize = 64;
+
+/// Detached Ed25519 signature made with an identity key.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    bytes: [u8; SIGNATURE_LENGTH],
+}
+
+impl Signature {
+    pub const fn new(bytes: [u8; SIGNATURE_LENGTH]) -> Self {
+        Self { bytes }
     }
 }
 
-impl AsRef<[u8]> for CryptoKey
-where
-    <Self as Deref>::Target: AsRef<[u8]>,
-{
+impl Deref for Signature {
+    type Target = [u8; SIGNATURE_LENGTH];
+    fn deref(&self) -> &Self::Target {
+        &self.bytes
+    }
+}
+
+impl AsRef<[u8]> for Signature {
     fn as_ref(&self) -> &[u8] {
-        &**self
+        &self.bytes
     }
 }
 
-impl From<[u8; CRYPTO_KEY_LENGTH]> for CryptoKey {
-    fn from(bytes: [u8; CRYPTO_KEY_LENGTH]) -> Self {
+impl From<[u8; SIGNATURE_LENGTH]> for Signature {
+    fn from(bytes: [u8; SIGNATURE_LENGTH]) -> Self {
         Self { bytes }
     }
 }
 
-impl TryFrom<&[u8]> for CryptoKey {
+impl TryFrom<&[u8]> for Signature {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         let len = value.len();
-        let bytes: [u8; CRYPTO_KEY_LENGTH] = value.try_into().map_err(|_| {
+        let bytes: [u8; SIGNATURE_LENGTH] = value.try_into().map_err(|_| {
             Error::generic(format!(
-                "Expected a slice of length {CRYPTO_KEY_LENGTH} but it was {len}"
+                "Expected a slice of length {SIGNATURE_LENGTH} but it was {len}"
             ))
         })?;
         Ok(Self { bytes })
     }
 }
 
-impl Encodable for CryptoKey {
+impl Encodable for Signature {
     fn encode(&self) -> String {
         super::base64_encode(self)
     }
@@ -77,21 +201,21 @@ impl Encodable for CryptoKey {
     }
 }
 
-impl fmt::Display for CryptoKey {
+impl fmt::Display for Signature {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.encode())
     }
 }
 
-impl fmt::Debug for CryptoKey {
+impl fmt::Debug for Signature {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("CryptoKey")
+        f.debug_struct("Signature")
             .field("bytes", &self.encode())
             .finish()
     }
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct KeyPair {
     secret: SecretKey,
     public: PublicKey,
@@ -103,13 +227,39 @@ impl KeyPair {
     }
     pub fn new_dh() -> Self {
         let secret = x25519_dalek::StaticSecret::random_from_rng(rand_core::OsRng);
-        let public = x25519_dalek::PublicKey::from(&secret);
+        Self::from_dh_secret(secret.to_bytes().into())
+    }
+    /// X25519 key pair of a stored `secret`.
+    pub fn from_dh_secret(secret: SecretKey) -> Self {
+        let public = x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::from(*secret));
+        Self {
+            secret,
+            public: public.to_bytes().into(),
+        }
+    }
+    /// Long-term Ed25519 key pair, e.g. a server identity.
+    pub fn new_signing() -> Self {
+        let secret = ed25519_dalek::SigningKey::generate(&mut rand_core::OsRng);
+        Self::from_signing_secret(secret.to_bytes().into())
+    }
+    /// Ed25519 key pair of a stored `secret`.
+    pub fn from_signing_secret(secret: SecretKey) -> Self {
+        let public = ed25519_dalek::SigningKey::from_bytes(&secret).verifying_key();
         Self {
-            secret: secret.to_bytes().into(),
+            secret,
             public: public.to_bytes().into(),
         }
     }
-    pub const fn into_split(self) -> (SecretKey, PublicKey) {
+    /// X25519 form of an Ed25519 key pair, so that an identity key can take
+    /// part in a key exchange as well.
+    pub fn to_dh(&self) -> Self {
+        let signing = ed25519_dalek::SigningKey::from_bytes(&self.secret);
+        Self {
+            secret: signing.to_scalar_bytes().into(),
+            public: signing.verifying_key().to_montgomery().to_bytes().into(),
+        }
+    }
+    pub fn into_split(self) -> (SecretKey, PublicKey) {
         (self.secret, self.public)
     }
     pub const fn secret(&self) -> &SecretKey {
@@ -138,12 +288,6 @@ impl KeyPair {
     }
 } */
 
-impl fmt::Display for KeyPair {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.secret.encode(), self.public.encode())
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -152,18 +296,74 @@ mod tests {
     fn crypto_key() {
         let arr: [u8; 32] = rand::random();
         let vec = arr.to_vec();
-        let key1 = CryptoKey::from(arr);
-        let key2 = CryptoKey::try_from(vec.as_ref()).unwrap();
+        let key1 = PublicKey::from(arr);
+        let key2 = PublicKey::try_from(vec.as_ref()).unwrap();
         assert_eq!(key1, key2);
         assert_eq!(*key1, *key2);
         assert_eq!(arr, *key1);
         assert_eq!(arr, *key2);
 
         let key_encoded = key1.encode();
-        let key_decoded = CryptoKey::try_decode(key_encoded).unwrap();
+        let key_decoded = PublicKey::try_decode(key_encoded).unwrap();
         assert_eq!(key1, key_decoded);
     }
 
+    #[test]
+    fn secret_key() {
+        let arr: [u8; 32] = rand::random();
+        let key = SecretKey::from(arr);
+        assert_eq!(key, SecretKey::try_decode(key.encode()).unwrap());
+        assert_ne!(key, SecretKey::from([0; 32]));
+        assert_eq!("SecretKey(<redacted>)", format!("{key:?}"));
+        assert_eq!(
+            "SharedSecret(<redacted>)",
+            format!("{:?}", SharedSecret::from(arr))
+        );
+        assert!(!format!("{:?}", KeyPair::new_dh()).contains(&key.encode()));
+
+        let mut key = key;
+        key.zeroize();
+        assert_eq!([0; 32], *key);
+    }
+
+    #[test]
+    fn signature() {
+        let arr = [7u8; SIGNATURE_LENGTH];
+        let signature = Signature::from(arr);
+        assert_eq!(arr, *signature);
+        assert!(Signature::try_from(&arr[1..]).is_err());
+
+        let decoded = Signature::try_decode(signature.encode()).unwrap();
+        assert_eq!(signature, decoded);
+    }
+
+    #[test]
+    fn signing_key_pair() {
+        let kp = KeyPair::new_signing();
+        assert_eq!(kp, KeyPair::from_signing_secret(kp.secret().clone()));
+    }
+
+    #[test]
+    fn signing_to_dh() {
+        let kp = KeyPair::new_signing();
+        let dh = kp.to_dh();
+        let secret = x25519_dalek::StaticSecret::from(**dh.secret());
+        assert_eq!(
+            **dh.public(),
+            x25519_dalek::PublicKey::from(&secret).to_bytes()
+        );
+        assert_eq!(
+            *dh.public(),
+            super::super::identity_to_dh(kp.public()).unwrap()
+        );
+    }
+
+    #[test]
+    fn dh_from_secret() {
+        let kp = KeyPair::new_dh();
+        assert_eq!(kp, KeyPair::from_dh_secret(kp.secret().clone()));
+    }
+
     /* #[test]
     fn key_pair() {
         let sec1: [u8; 32] = rand::random();
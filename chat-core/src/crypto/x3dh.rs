@@ -0,0 +1,307 @@
+//! Agreeing on a session secret with a client which may be offline
+//!
+//! Clients publish a bundle of prekeys on the server: a prekey signed with
+//! their identity key and a batch of one-time prekeys. Another client fetches
+//! the bundle, mixes it with its own identity and a fresh ephemeral key and
+//! sends the public halves it used along with its first message. See
+//! <https://signal.org/docs/specifications/x3dh/>.
+//!
+//! Identity keys are the Ed25519 keys clients already have, they take part in
+//! the exchange in their X25519 form, see [`KeyPair::to_dh`].
+//!
+//! A bundle may also carry a signed ML-KEM prekey. The secret encapsulated to
+//! it is mixed in after the X25519 ones, like PQXDH does, so the session stays
+//! safe against a quantum attacker who recorded it. See
+//! <https://signal.org/docs/specifications/pqxdh/>.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use super::{
+    identity_to_dh, CryptoSchema, EncapsulationKey, KemCiphertext, KemKeyPair, KeyPair, PublicKey,
+    SharedSecret, Signature,
+};
+use crate::prelude::*;
+
+const PREKEY: &[u8] = b"CORE_PREKEY";
+const KEM_PREKEY: &[u8] = b"CORE_KEM_PREKEY";
+const X3DH: &[u8] = b"CORE_X3DH";
+
+/// What an identity key signs to vouch for a prekey.
+pub fn prekey_signed_bytes(prekey: &PublicKey) -> Vec<u8> {
+    [PREKEY, prekey.as_ref()].concat()
+}
+
+/// Signs `prekey` with the `identity` key pair.
+pub fn sign_prekey<C: CryptoSchema>(
+    crypto: &C,
+    identity: &KeyPair,
+    prekey: &PublicKey,
+) -> Signature {
+    crypto.sign(identity.secret(), &prekey_signed_bytes(prekey))
+}
+
+/// What an identity key signs to vouch for an ML-KEM prekey.
+pub fn kem_prekey_signed_bytes(prekey: &EncapsulationKey) -> Vec<u8> {
+    [KEM_PREKEY, prekey.as_ref()].concat()
+}
+
+/// Signs the ML-KEM `prekey` with the `identity` key pair.
+pub fn sign_kem_prekey<C: CryptoSchema>(
+    crypto: &C,
+    identity: &KeyPair,
+    prekey: &EncapsulationKey,
+) -> Signature {
+    crypto.sign(identity.secret(), &kem_prekey_signed_bytes(prekey))
+}
+
+/// Secret of the side starting the session, along with the ephemeral key and
+/// the KEM ciphertext the other side needs to compute it too.
+///
+/// `kem_prekey` is the ML-KEM prekey of the bundle and its signature.
+///
+/// # Errors
+///
+/// This function will return an error if a signature doesn't vouch for its
+/// prekey or an identity key isn't a valid Ed25519 key.
+pub fn initiate<C: CryptoSchema>(
+    crypto: &C,
+    identity: &KeyPair,
+    remote_identity: &PublicKey,
+    signed_prekey: &PublicKey,
+    signature: &Signature,
+    one_time_prekey: Option<&PublicKey>,
+    kem_prekey: Option<(&EncapsulationKey, &Signature)>,
+) -> Result<(SharedSecret, PublicKey, Option<KemCiphertext>)> {
+    crypto.verify(
+        remote_identity,
+        &prekey_signed_bytes(signed_prekey),
+        signature,
+    )?;
+    if let Some((kem_prekey, signature)) = kem_prekey {
+        crypto.verify(
+            remote_identity,
+            &kem_prekey_signed_bytes(kem_prekey),
+            signature,
+        )?;
+    }
+    let ephemeral = KeyPair::new_dh();
+    let remote_dh = identity_to_dh(remote_identity)?;
+
+    let mut material = vec![
+        crypto.compute_dh(identity.to_dh().secret(), signed_prekey),
+        crypto.compute_dh(ephemeral.secret(), &remote_dh),
+        crypto.compute_dh(ephemeral.secret(), signed_prekey),
+    ];
+    if let Some(one_time_prekey) = one_time_prekey {
+        material.push(crypto.compute_dh(ephemeral.secret(), one_time_prekey));
+    }
+    let kem_ciphertext = kem_prekey.map(|(kem_prekey, _)| {
+        let (ciphertext, secret) = crypto.encapsulate(kem_prekey);
+        material.push(secret);
+        ciphertext
+    });
+
+    let secret = derive(&material, identity.public(), remote_identity);
+    Ok((secret, *ephemeral.public(), kem_ciphertext))
+}
+
+/// Secret of the side whose bundle the session was started from. `kem` is
+/// the ML-KEM prekey and the ciphertext the other side encapsulated to it.
+///
+/// # Errors
+///
+/// This function will return an error if `remote_identity` isn't a valid
+/// Ed25519 key.
+pub fn respond<C: CryptoSchema>(
+    crypto: &C,
+    identity: &KeyPair,
+    signed_prekey: &KeyPair,
+    one_time_prekey: Option<&KeyPair>,
+    kem: Option<(&KemKeyPair, &KemCiphertext)>,
+    remote_identity: &PublicKey,
+    ephemeral: &PublicKey,
+) -> Result<SharedSecret> {
+    let remote_dh = identity_to_dh(remote_identity)?;
+
+    let mut material = vec![
+        crypto.compute_dh(signed_prekey.secret(), &remote_dh),
+        crypto.compute_dh(identity.to_dh().secret(), ephemeral),
+        crypto.compute_dh(signed_prekey.secret(), ephemeral),
+    ];
+    if let Some(one_time_prekey) = one_time_prekey {
+        material.push(crypto.compute_dh(one_time_prekey.secret(), ephemeral));
+    }
+    if let Some((kem_prekey, ciphertext)) = kem {
+        material.push(crypto.decapsulate(kem_prekey, ciphertext));
+    }
+
+    Ok(derive(&material, remote_identity, identity.public()))
+}
+
+/// Binds the secret to both identities, the initiator's first.
+fn derive(material: &[SharedSecret], initiator: &PublicKey, responder: &PublicKey) -> SharedSecret {
+    let material: Vec<u8> = material.iter().flat_map(|dh| dh.iter().copied()).collect();
+    let info = [X3DH, initiator.as_ref(), responder.as_ref()].concat();
+
+    let mut secret = [0; super::CRYPTO_KEY_LENGTH];
+    Hkdf::<Sha256>::new(None, &material)
+        .expand(&info, &mut secret)
+        .expect("key length is valid for HKDF-SHA256");
+    SharedSecret::new(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Crypto;
+
+    struct Bundle {
+        identity: KeyPair,
+        signed_prekey: KeyPair,
+        signature: Signature,
+        one_time_prekey: KeyPair,
+        kem_prekey: KemKeyPair,
+        kem_signature: Signature,
+    }
+
+    fn bundle() -> Bundle {
+        let identity = KeyPair::new_signing();
+        let signed_prekey = KeyPair::new_dh();
+        let signature = sign_prekey(&Crypto, &identity, signed_prekey.public());
+        let kem_prekey = KemKeyPair::new();
+        let kem_signature = sign_kem_prekey(&Crypto, &identity, kem_prekey.public());
+        Bundle {
+            identity,
+            signed_prekey,
+            signature,
+            one_time_prekey: KeyPair::new_dh(),
+            kem_prekey,
+            kem_signature,
+        }
+    }
+
+    #[test]
+    fn agree() {
+        let alice = KeyPair::new_signing();
+        let bob = bundle();
+        let kem_prekey = (bob.kem_prekey.public(), &bob.kem_signature);
+
+        for one_time_prekey in [Some(&bob.one_time_prekey), None] {
+            for kem_prekey in [Some(kem_prekey), None] {
+                let (secret, ephemeral, kem_ciphertext) = initiate(
+                    &Crypto,
+                    &alice,
+                    bob.identity.public(),
+                    bob.signed_prekey.public(),
+                    &bob.signature,
+                    one_time_prekey.map(KeyPair::public),
+                    kem_prekey,
+                )
+                .unwrap();
+                assert_eq!(kem_prekey.is_some(), kem_ciphertext.is_some());
+                let answer = respond(
+                    &Crypto,
+                    &bob.identity,
+                    &bob.signed_prekey,
+                    one_time_prekey,
+                    kem_ciphertext.as_ref().map(|c| (&bob.kem_prekey, c)),
+                    alice.public(),
+                    &ephemeral,
+                )
+                .unwrap();
+                assert_eq!(secret, answer);
+            }
+        }
+    }
+
+    #[test]
+    fn kem_secret_is_mixed_in() {
+        let alice = KeyPair::new_signing();
+        let bob = bundle();
+        let (secret, ephemeral, kem_ciphertext) = initiate(
+            &Crypto,
+            &alice,
+            bob.identity.public(),
+            bob.signed_prekey.public(),
+            &bob.signature,
+            None,
+            Some((bob.kem_prekey.public(), &bob.kem_signature)),
+        )
+        .unwrap();
+        let kem_ciphertext = kem_ciphertext.unwrap();
+
+        // Breaking X25519 alone isn't enough, the KEM prekey is needed as well.
+        let other = KemKeyPair::new();
+        let answer = respond(
+            &Crypto,
+            &bob.identity,
+            &bob.signed_prekey,
+            None,
+            Some((&other, &kem_ciphertext)),
+            alice.public(),
+            &ephemeral,
+        )
+        .unwrap();
+        assert_ne!(secret, answer);
+
+        // A KEM prekey nobody vouched for is refused.
+        let forged = initiate(
+            &Crypto,
+            &alice,
+            bob.identity.public(),
+            bob.signed_prekey.public(),
+            &bob.signature,
+            None,
+            Some((other.public(), &bob.kem_signature)),
+        );
+        assert!(forged.is_err());
+    }
+
+    #[test]
+    fn unsigned_prekey() {
+        let alice = KeyPair::new_signing();
+        let bob = bundle();
+        let forged = KeyPair::new_dh();
+        let initiated = initiate(
+            &Crypto,
+            &alice,
+            bob.identity.public(),
+            forged.public(),
+            &bob.signature,
+            None,
+            None,
+        );
+        assert!(initiated.is_err());
+    }
+
+    #[test]
+    fn bound_to_identities() {
+        let alice = KeyPair::new_signing();
+        let mallory = KeyPair::new_signing();
+        let bob = bundle();
+
+        let (secret, ephemeral, _) = initiate(
+            &Crypto,
+            &alice,
+            bob.identity.public(),
+            bob.signed_prekey.public(),
+            &bob.signature,
+            None,
+            None,
+        )
+        .unwrap();
+        // Bob thinks the session comes from someone else.
+        let answer = respond(
+            &Crypto,
+            &bob.identity,
+            &bob.signed_prekey,
+            None,
+            None,
+            mallory.public(),
+            &ephemeral,
+        )
+        .unwrap();
+        assert_ne!(secret, answer);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::event::ErrorCode;
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(thiserror::Error, Debug, PartialEq, Eq)]
@@ -10,8 +12,21 @@ pub enum Error {
     Decode(String),
     #[error("IO error: {0}")]
     IO(String),
+    #[error("Incompatible peer: {0}")]
+    Incompatible(String),
+    #[error("Unexpected event: {0}")]
+    Unexpected(String),
+    #[error("Peer reported an error: {0}: {1}")]
+    Remote(ErrorCode, String),
+    #[error("Frame {0} was already received")]
+    Replayed(u64),
+    #[error("Frame {0} is too old to tell whether it was replayed")]
+    OutOfWindow(u64),
     #[error("Timeout")]
     Timeout,
+    /// The peer was turned away and has already been told why.
+    #[error("Rejected: {0}")]
+    Rejected(String),
     #[error("Shutdown")]
     Shutdown,
 }
@@ -29,4 +44,13 @@ impl Error {
     pub fn io<T: ToString>(msg: T) -> Self {
         Self::IO(msg.to_string())
     }
+    pub fn incompatible<T: ToString>(msg: T) -> Self {
+        Self::Incompatible(msg.to_string())
+    }
+    pub fn unexpected<T: ToString>(msg: T) -> Self {
+        Self::Unexpected(msg.to_string())
+    }
+    pub fn rejected<T: ToString>(msg: T) -> Self {
+        Self::Rejected(msg.to_string())
+    }
 }
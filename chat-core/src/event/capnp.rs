@@ -9,7 +9,7 @@ mod schema_capnp {
 }
 
 #[non_exhaustive]
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Capnp;
 
 impl From<capnp::Error> for crate::error::Error {
@@ -30,7 +30,8 @@ impl Serializable for Capnp {
     fn serialize(&self, entity: types::Entity<'_>) -> Vec<u8> {
         let mut message = capnp::message::Builder::new_default();
         let mut capnp_entity = message.init_root::<schema_capnp::entity::Builder<'_>>();
-        capnp_entity.set_timestamp(super::timestamp());
+        capnp_entity.set_id(&entity.id().to_bytes());
+        capnp_entity.set_timestamp(*entity.timestamp());
         let mut capnp_kind = capnp_entity.init_kind();
 
         match entity.kind() {
@@ -38,6 +39,15 @@ impl Serializable for Capnp {
             EventKind::Registration(inner) => serialize::registration(&mut capnp_kind, inner),
             EventKind::Authentication(inner) => serialize::authentication(&mut capnp_kind, inner),
             EventKind::Message(inner) => serialize::message(&mut capnp_kind, inner),
+            EventKind::Error(inner) => serialize::error(&mut capnp_kind, inner),
+            EventKind::Ping(inner) => serialize::ping(&mut capnp_kind, inner),
+            EventKind::Pong(inner) => serialize::pong(&mut capnp_kind, inner),
+            EventKind::Prekeys(inner) => serialize::prekeys(&mut capnp_kind, inner),
+            EventKind::SessionInit(inner) => serialize::session_init(&mut capnp_kind, inner),
+            EventKind::KeyDistribution(inner) => {
+                serialize::key_distribution(&mut capnp_kind, inner)
+            }
+            EventKind::Presence(inner) => serialize::presence(&mut capnp_kind, inner),
         };
 
         let mut buf = Vec::new();
@@ -51,6 +61,7 @@ impl Serializable for Capnp {
         let message_reader =
             serialize_packed::read_message(bytes, capnp::message::ReaderOptions::new())?;
         let capnp_entity = message_reader.get_root::<schema_capnp::entity::Reader<'_>>()?;
+        let id = capnp_entity.get_id()?.then(super::decode_id)?;
         let timestamp = capnp_entity.get_timestamp();
         let kind = capnp_entity.get_kind().which()?;
 
@@ -59,9 +70,16 @@ impl Serializable for Capnp {
             Which::Registration(inner) => deserialize::registration(inner?)?,
             Which::Authentication(inner) => deserialize::authentication(inner?)?,
             Which::Message(inner) => deserialize::message(inner?)?,
+            Which::Error(inner) => deserialize::error(inner?)?,
+            Which::Ping(inner) => deserialize::ping(inner?),
+            Which::Pong(inner) => deserialize::pong(inner?),
+            Which::Prekeys(inner) => deserialize::prekeys(inner?)?,
+            Which::SessionInit(inner) => deserialize::session_init(inner?)?,
+            Which::KeyDistribution(inner) => deserialize::key_distribution(inner?)?,
+            Which::Presence(inner) => deserialize::presence(inner?)?,
         };
 
-        Ok(types::Entity::new(timestamp, kind.into()))
+        Ok(types::Entity::new(id, timestamp, kind.into()))
     }
 }
 
@@ -74,6 +92,23 @@ mod serialize {
 
         let pub_key = kind.pub_key().encode();
         capnp_kind.set_pub_key(pub_key.as_str().into());
+        capnp_kind.set_version(*kind.version());
+        capnp_kind.set_capabilities(kind.capabilities().bits());
+        if let Some(identity) = kind.identity() {
+            let key = identity.key().encode();
+            capnp_kind.set_identity_key(key.as_str().into());
+            let signature = identity.signature().encode();
+            capnp_kind.set_signature(signature.as_str().into());
+        }
+        match kind.kem() {
+            Some(types::KemShare::Offer(key)) => {
+                capnp_kind.set_kem_offer(key.encode().as_str().into());
+            }
+            Some(types::KemShare::Answer(ciphertext)) => {
+                capnp_kind.set_kem_answer(ciphertext.encode().as_str().into());
+            }
+            None => (),
+        }
     }
 
     pub(crate) fn registration(capnp_kind: &mut Builder<'_>, kind: &types::Registration<'_>) {
@@ -139,10 +174,121 @@ mod serialize {
         capnp_kind.set_sender(sender.into());
         capnp_kind.set_text(text.into());
     }
+
+    pub(crate) fn error(capnp_kind: &mut Builder<'_>, kind: &types::ErrorEvent<'_>) {
+        use schema_capnp::error::Code;
+
+        let mut capnp_kind = capnp_kind.reborrow().init_error();
+        let code = match kind.code() {
+            types::ErrorCode::Internal => Code::Internal,
+            types::ErrorCode::Decode => Code::Decode,
+            types::ErrorCode::UnexpectedEvent => Code::UnexpectedEvent,
+            types::ErrorCode::Incompatible => Code::Incompatible,
+            types::ErrorCode::Crypto => Code::Crypto,
+            types::ErrorCode::Timeout => Code::Timeout,
+        };
+        capnp_kind.set_code(code);
+        capnp_kind.set_text(kind.text().into());
+    }
+
+    pub(crate) fn ping(capnp_kind: &mut Builder<'_>, kind: &types::Ping) {
+        let mut capnp_kind = capnp_kind.reborrow().init_ping();
+        capnp_kind.set_nonce(*kind.nonce());
+    }
+
+    pub(crate) fn pong(capnp_kind: &mut Builder<'_>, kind: &types::Pong) {
+        let mut capnp_kind = capnp_kind.reborrow().init_pong();
+        capnp_kind.set_nonce(*kind.nonce());
+    }
+
+    pub(crate) fn prekeys(capnp_kind: &mut Builder<'_>, kind: &types::Prekeys<'_>) {
+        let capnp_kind = capnp_kind.reborrow().init_prekeys().init_kind();
+        match kind {
+            types::Prekeys::Upload(inner) => {
+                bundle(capnp_kind.init_upload(), inner);
+            }
+            types::Prekeys::Request(inner) => {
+                let mut req = capnp_kind.init_request();
+                req.set_username(inner.username().into());
+            }
+            types::Prekeys::Response(inner) => {
+                let mut resp = capnp_kind.init_response();
+                resp.set_username(inner.username().into());
+                if let Some(inner) = inner.bundle() {
+                    bundle(resp.init_bundle(), inner);
+                }
+            }
+        }
+    }
+
+    fn bundle(
+        mut capnp_bundle: schema_capnp::prekey_bundle::Builder<'_>,
+        bundle: &types::PrekeyBundle,
+    ) {
+        let identity_key = bundle.identity_key().encode();
+        capnp_bundle.set_identity_key(identity_key.as_str().into());
+        let signed_prekey = bundle.signed_prekey().encode();
+        capnp_bundle.set_signed_prekey(signed_prekey.as_str().into());
+        let signature = bundle.signature().encode();
+        capnp_bundle.set_signature(signature.as_str().into());
+        if let Some(kem_prekey) = bundle.kem_prekey() {
+            let key = kem_prekey.key().encode();
+            capnp_bundle.set_kem_prekey(key.as_str().into());
+            let signature = kem_prekey.signature().encode();
+            capnp_bundle.set_kem_signature(signature.as_str().into());
+        }
+
+        let one_time_prekeys = bundle.one_time_prekeys();
+        let mut list = capnp_bundle.init_one_time_prekeys(one_time_prekeys.len() as u32);
+        for (i, prekey) in one_time_prekeys.iter().enumerate() {
+            let prekey = prekey.encode();
+            list.set(i as u32, prekey.as_str().into());
+        }
+    }
+
+    pub(crate) fn session_init(capnp_kind: &mut Builder<'_>, kind: &types::SessionInit<'_>) {
+        let mut capnp_kind = capnp_kind.reborrow().init_session_init();
+
+        capnp_kind.set_sender(kind.sender().into());
+        capnp_kind.set_recipient(kind.recipient().into());
+        let identity_key = kind.identity_key().encode();
+        capnp_kind.set_identity_key(identity_key.as_str().into());
+        let ephemeral_key = kind.ephemeral_key().encode();
+        capnp_kind.set_ephemeral_key(ephemeral_key.as_str().into());
+        let signed_prekey = kind.signed_prekey().encode();
+        capnp_kind.set_signed_prekey(signed_prekey.as_str().into());
+        if let Some(one_time_prekey) = kind.one_time_prekey() {
+            let one_time_prekey = one_time_prekey.encode();
+            capnp_kind.set_one_time_prekey(one_time_prekey.as_str().into());
+        }
+        if let Some(kem_ciphertext) = kind.kem_ciphertext() {
+            let kem_ciphertext = kem_ciphertext.encode();
+            capnp_kind.set_kem_ciphertext(kem_ciphertext.as_str().into());
+        }
+    }
+
+    pub(crate) fn key_distribution(
+        capnp_kind: &mut Builder<'_>,
+        kind: &types::KeyDistribution<'_>,
+    ) {
+        let mut capnp_kind = capnp_kind.reborrow().init_key_distribution();
+
+        capnp_kind.set_sender(kind.sender().into());
+        capnp_kind.set_recipient(kind.recipient().into());
+        capnp_kind.set_ciphertext(kind.ciphertext().into());
+    }
+
+    pub(crate) fn presence(capnp_kind: &mut Builder<'_>, kind: &types::Presence<'_>) {
+        let mut capnp_kind = capnp_kind.reborrow().init_presence();
+
+        capnp_kind.set_username(kind.username().into());
+        capnp_kind.set_online(*kind.online());
+    }
 }
 
 mod deserialize {
     use super::{schema_capnp, types, Encodable, Error, EventKind, PublicKey, Result, Then};
+    use crate::protocol::Capabilities;
 
     pub(crate) fn handshake<'a>(
         inner: schema_capnp::handshake::Reader<'_>,
@@ -151,7 +297,23 @@ mod deserialize {
             .get_pub_key()?
             .as_bytes()
             .then(PublicKey::try_decode)?;
-        Ok(EventKind::Handshake(types::Handshake::new(pub_key)))
+        let version = inner.get_version();
+        let capabilities = Capabilities::from_bits(inner.get_capabilities());
+        let identity = crate::event::decode_identity(
+            inner.get_identity_key()?.as_bytes(),
+            inner.get_signature()?.as_bytes(),
+        )?;
+        let kem = crate::event::decode_kem_share(
+            inner.get_kem_offer()?.as_bytes(),
+            inner.get_kem_answer()?.as_bytes(),
+        )?;
+        Ok(EventKind::Handshake(types::Handshake::new(
+            pub_key,
+            version,
+            capabilities,
+            kem,
+            identity,
+        )))
     }
 
     pub(crate) fn registration<'a>(
@@ -218,6 +380,120 @@ mod deserialize {
             text.into(),
         )))
     }
+
+    pub(crate) fn error<'a>(inner: schema_capnp::error::Reader<'_>) -> Result<EventKind<'a>> {
+        use schema_capnp::error::Code;
+
+        let code = match inner.get_code()? {
+            Code::Internal => types::ErrorCode::Internal,
+            Code::Decode => types::ErrorCode::Decode,
+            Code::UnexpectedEvent => types::ErrorCode::UnexpectedEvent,
+            Code::Incompatible => types::ErrorCode::Incompatible,
+            Code::Crypto => types::ErrorCode::Crypto,
+            Code::Timeout => types::ErrorCode::Timeout,
+        };
+        let text = inner.get_text()?.to_string().map_err(Error::generic)?;
+
+        Ok(EventKind::Error(types::ErrorEvent::new(code, text.into())))
+    }
+
+    pub(crate) fn ping<'a>(inner: schema_capnp::ping::Reader<'_>) -> EventKind<'a> {
+        EventKind::Ping(types::Ping::new(inner.get_nonce()))
+    }
+
+    pub(crate) fn pong<'a>(inner: schema_capnp::pong::Reader<'_>) -> EventKind<'a> {
+        EventKind::Pong(types::Pong::new(inner.get_nonce()))
+    }
+
+    pub(crate) fn prekeys<'a>(inner: schema_capnp::prekeys::Reader<'_>) -> Result<EventKind<'a>> {
+        use schema_capnp::prekeys::kind::Which;
+
+        let prekeys = match inner.get_kind().which()? {
+            Which::Upload(inner) => types::Prekeys::Upload(bundle(inner?)?),
+            Which::Request(inner) => {
+                let username = inner?.get_username()?.to_string().map_err(Error::generic)?;
+                types::Prekeys::Request(types::PrekeyRequest::new(username.into()))
+            }
+            Which::Response(inner) => {
+                let inner = inner?;
+                let username = inner.get_username()?.to_string().map_err(Error::generic)?;
+                let bundle = if inner.has_bundle() {
+                    Some(bundle(inner.get_bundle()?)?)
+                } else {
+                    None
+                };
+                types::Prekeys::Response(types::PrekeyResponse::new(username.into(), bundle))
+            }
+        };
+        Ok(EventKind::Prekeys(prekeys))
+    }
+
+    fn bundle(inner: schema_capnp::prekey_bundle::Reader<'_>) -> Result<types::PrekeyBundle> {
+        let one_time_prekeys = inner
+            .get_one_time_prekeys()?
+            .iter()
+            .map(|prekey| Ok(prekey?.as_bytes()))
+            .collect::<Result<Vec<_>>>()?;
+        crate::event::decode_bundle(
+            inner.get_identity_key()?.as_bytes(),
+            inner.get_signed_prekey()?.as_bytes(),
+            inner.get_signature()?.as_bytes(),
+            one_time_prekeys,
+            crate::event::decode_kem_prekey(
+                inner.get_kem_prekey()?.as_bytes(),
+                inner.get_kem_signature()?.as_bytes(),
+            )?,
+        )
+    }
+
+    pub(crate) fn session_init<'a>(
+        inner: schema_capnp::session_init::Reader<'_>,
+    ) -> Result<EventKind<'a>> {
+        let sender = inner.get_sender()?.to_string().map_err(Error::generic)?;
+        let recipient = inner.get_recipient()?.to_string().map_err(Error::generic)?;
+        let identity_key = PublicKey::try_decode(inner.get_identity_key()?.as_bytes())?;
+        let ephemeral_key = PublicKey::try_decode(inner.get_ephemeral_key()?.as_bytes())?;
+        let signed_prekey = PublicKey::try_decode(inner.get_signed_prekey()?.as_bytes())?;
+        let one_time_prekey =
+            crate::event::decode_optional(inner.get_one_time_prekey()?.as_bytes())?;
+        let kem_ciphertext = crate::event::decode_optional(inner.get_kem_ciphertext()?.as_bytes())?;
+
+        Ok(EventKind::SessionInit(types::SessionInit::new(
+            sender.into(),
+            recipient.into(),
+            identity_key,
+            ephemeral_key,
+            signed_prekey,
+            one_time_prekey,
+            kem_ciphertext,
+        )))
+    }
+
+    pub(crate) fn key_distribution<'a>(
+        inner: schema_capnp::key_distribution::Reader<'_>,
+    ) -> Result<EventKind<'a>> {
+        let sender = inner.get_sender()?.to_string().map_err(Error::generic)?;
+        let recipient = inner.get_recipient()?.to_string().map_err(Error::generic)?;
+        let ciphertext = inner
+            .get_ciphertext()?
+            .to_string()
+            .map_err(Error::generic)?;
+
+        Ok(EventKind::KeyDistribution(types::KeyDistribution::new(
+            sender.into(),
+            recipient.into(),
+            ciphertext.into(),
+        )))
+    }
+
+    pub(crate) fn presence<'a>(inner: schema_capnp::presence::Reader<'_>) -> Result<EventKind<'a>> {
+        let username = inner.get_username()?.to_string().map_err(Error::generic)?;
+
+        Ok(EventKind::Presence(types::Presence::new(
+            username.into(),
+            inner.get_online(),
+        )))
+    }
 }
 
 impl Constructable for Capnp {}
@@ -254,4 +530,29 @@ mod tests {
     fn message() {
         crate::event::tests::message(Capnp);
     }
+
+    #[test]
+    fn error() {
+        crate::event::tests::error(Capnp);
+    }
+
+    #[test]
+    fn keepalive() {
+        crate::event::tests::keepalive(Capnp);
+    }
+
+    #[test]
+    fn prekeys() {
+        crate::event::tests::prekeys(Capnp);
+    }
+
+    #[test]
+    fn session_init() {
+        crate::event::tests::session_init(Capnp);
+    }
+
+    #[test]
+    fn group() {
+        crate::event::tests::group(Capnp);
+    }
 }
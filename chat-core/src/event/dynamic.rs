@@ -0,0 +1,170 @@
+use std::str::FromStr;
+
+use super::types;
+use crate::{prelude::*, protocol::Capabilities};
+
+/// Serialization backend chosen at runtime, e.g. per connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DynSchema {
+    Capnp(Capnp),
+    Protobuf(Protobuf),
+    Json(Json),
+}
+
+impl DynSchema {
+    /// Picks a backend out of the negotiated capabilities, preferring the
+    /// binary ones. JSON is picked only if nothing else is offered.
+    ///
+    /// Peers which don't advertise any backend predate the negotiation and
+    /// always speak Capnp.
+    pub fn from_capabilities(capabilities: Capabilities) -> Self {
+        if capabilities.contains(Capabilities::CAPNP) {
+            Self::Capnp(Capnp::default())
+        } else if capabilities.contains(Capabilities::PROTOBUF) {
+            Self::Protobuf(Protobuf::default())
+        } else if capabilities.contains(Capabilities::JSON) {
+            Self::Json(Json::default())
+        } else {
+            Self::Capnp(Capnp::default())
+        }
+    }
+
+    /// The capability which advertises this backend.
+    pub const fn capability(&self) -> Capabilities {
+        match self {
+            Self::Capnp(_) => Capabilities::CAPNP,
+            Self::Protobuf(_) => Capabilities::PROTOBUF,
+            Self::Json(_) => Capabilities::JSON,
+        }
+    }
+}
+
+impl Default for DynSchema {
+    fn default() -> Self {
+        Self::Capnp(Capnp::default())
+    }
+}
+
+impl FromStr for DynSchema {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "capnp" => Ok(Self::Capnp(Capnp::default())),
+            "protobuf" => Ok(Self::Protobuf(Protobuf::default())),
+            "json" => Ok(Self::Json(Json::default())),
+            _ => Err(Error::generic(format!("Unknown event schema: {s}"))),
+        }
+    }
+}
+
+impl EventSchema for DynSchema {}
+
+impl Serializable for DynSchema {
+    fn serialize(&self, entity: types::Entity<'_>) -> Vec<u8> {
+        match self {
+            Self::Capnp(inner) => inner.serialize(entity),
+            Self::Protobuf(inner) => inner.serialize(entity),
+            Self::Json(inner) => inner.serialize(entity),
+        }
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<types::Entity<'_>> {
+        match self {
+            Self::Capnp(inner) => inner.deserialize(bytes),
+            Self::Protobuf(inner) => inner.deserialize(bytes),
+            Self::Json(inner) => inner.deserialize(bytes),
+        }
+    }
+}
+
+impl Constructable for DynSchema {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [DynSchema; 3] = [
+        DynSchema::Capnp(Capnp),
+        DynSchema::Protobuf(Protobuf),
+        DynSchema::Json(Json),
+    ];
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<DynSchema>();
+    }
+    #[test]
+    fn test_sync() {
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<DynSchema>();
+    }
+    #[test]
+    fn handshake() {
+        ALL.into_iter().for_each(crate::event::tests::handshake);
+    }
+
+    #[test]
+    fn registration() {
+        ALL.into_iter().for_each(crate::event::tests::registration);
+    }
+
+    #[test]
+    fn authentication() {
+        ALL.into_iter()
+            .for_each(crate::event::tests::authentication);
+    }
+
+    #[test]
+    fn message() {
+        ALL.into_iter().for_each(crate::event::tests::message);
+    }
+
+    #[test]
+    fn error() {
+        ALL.into_iter().for_each(crate::event::tests::error);
+    }
+
+    #[test]
+    fn keepalive() {
+        ALL.into_iter().for_each(crate::event::tests::keepalive);
+    }
+
+    #[test]
+    fn prekeys() {
+        ALL.into_iter().for_each(crate::event::tests::prekeys);
+    }
+
+    #[test]
+    fn session_init() {
+        ALL.into_iter().for_each(crate::event::tests::session_init);
+    }
+
+    #[test]
+    fn group() {
+        ALL.into_iter().for_each(crate::event::tests::group);
+    }
+
+    #[test]
+    fn from_capabilities() {
+        let both = Capabilities::CAPNP | Capabilities::PROTOBUF;
+        assert_eq!(ALL[0], DynSchema::from_capabilities(both));
+        let both = Capabilities::PROTOBUF | Capabilities::JSON;
+        assert_eq!(ALL[1], DynSchema::from_capabilities(both));
+        assert_eq!(ALL[2], DynSchema::from_capabilities(Capabilities::JSON));
+        assert_eq!(ALL[0], DynSchema::from_capabilities(Capabilities::EMPTY));
+
+        for schema in ALL {
+            assert_eq!(schema, DynSchema::from_capabilities(schema.capability()));
+        }
+    }
+
+    #[test]
+    fn from_str() {
+        assert_eq!(ALL[0], "capnp".parse().unwrap());
+        assert_eq!(ALL[1], "Protobuf".parse().unwrap());
+        assert_eq!(ALL[2], "json".parse().unwrap());
+        assert!("xml".parse::<DynSchema>().is_err());
+    }
+}
@@ -1,8 +1,13 @@
 use crate::{
-    event::types::{AuthenticationStatus, Entity, RegistrationStatus},
+    crypto::TransportKeys,
+    event::types::{
+        AuthenticationStatus, Entity, ErrorCode, PrekeyBundle, RegistrationStatus, SessionInit,
+    },
     prelude::*,
 };
 
+const FRAME: &[u8] = b"CORE_FRAME";
+
 #[derive(Debug)]
 pub struct EventBuilder;
 
@@ -39,14 +44,26 @@ impl EventBuilder {
 pub struct Constructing<E: EventSchema>(E);
 #[derive(Debug)]
 pub struct Constructed {
+    /// [`EventKind::tag`](crate::event::EventKind::tag) of the event.
+    tag: u8,
     bytes: Vec<u8>,
 }
 
+impl Constructed {
+    fn new<E: EventSchema>(event: &E, entity: Entity<'_>) -> Self {
+        Self {
+            tag: entity.kind().tag(),
+            bytes: event.serialize(entity),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Deconstructing<E: EventSchema>(E);
 #[derive(Debug)]
 pub struct Decrypted<E: EventSchema> {
     event: E,
+    tag: u8,
     bytes: Vec<u8>,
 }
 
@@ -76,53 +93,112 @@ where
     pub fn handshake(self, pub_key: &PublicKey) -> Builder<Constructed, C> {
         let event = self.state.0;
         let entity = event.construct_handshake(pub_key);
-        let state = Constructed {
-            bytes: event.serialize(entity),
-        };
+        let state = Constructed::new(&event, entity);
         create_builder!(self, state)
     }
 
     pub fn registration_request(self, username: &str, password: &str) -> Builder<Constructed, C> {
         let event = self.state.0;
         let entity = event.construct_registration_request(username, password);
-        let state = Constructed {
-            bytes: event.serialize(entity),
-        };
+        let state = Constructed::new(&event, entity);
         create_builder!(self, state)
     }
 
     pub fn registration_response(self, status: RegistrationStatus) -> Builder<Constructed, C> {
         let event = self.state.0;
         let entity = event.construct_registration_response(status);
-        let state = Constructed {
-            bytes: event.serialize(entity),
-        };
+        let state = Constructed::new(&event, entity);
         create_builder!(self, state)
     }
 
     pub fn authentication_request(self, username: &str, password: &str) -> Builder<Constructed, C> {
         let event = self.state.0;
         let entity = event.construct_authentication_request(username, password);
-        let state = Constructed {
-            bytes: event.serialize(entity),
-        };
+        let state = Constructed::new(&event, entity);
         create_builder!(self, state)
     }
     pub fn authentication_response(self, status: AuthenticationStatus) -> Builder<Constructed, C> {
         let event = self.state.0;
         let entity = event.construct_authentication_response(status);
-        let state = Constructed {
-            bytes: event.serialize(entity),
-        };
+        let state = Constructed::new(&event, entity);
         create_builder!(self, state)
     }
 
     pub fn message(self, sender: &str, text: &str) -> Builder<Constructed, C> {
         let event = self.state.0;
         let entity = event.construct_message(sender, text);
-        let state = Constructed {
-            bytes: event.serialize(entity),
-        };
+        let state = Constructed::new(&event, entity);
+        create_builder!(self, state)
+    }
+
+    pub fn ping(self, nonce: u64) -> Builder<Constructed, C> {
+        let event = self.state.0;
+        let entity = event.construct_ping(nonce);
+        let state = Constructed::new(&event, entity);
+        create_builder!(self, state)
+    }
+
+    pub fn pong(self, nonce: u64) -> Builder<Constructed, C> {
+        let event = self.state.0;
+        let entity = event.construct_pong(nonce);
+        let state = Constructed::new(&event, entity);
+        create_builder!(self, state)
+    }
+
+    pub fn prekey_upload(self, bundle: PrekeyBundle) -> Builder<Constructed, C> {
+        let event = self.state.0;
+        let entity = event.construct_prekey_upload(bundle);
+        let state = Constructed::new(&event, entity);
+        create_builder!(self, state)
+    }
+
+    pub fn prekey_request(self, username: &str) -> Builder<Constructed, C> {
+        let event = self.state.0;
+        let entity = event.construct_prekey_request(username);
+        let state = Constructed::new(&event, entity);
+        create_builder!(self, state)
+    }
+
+    pub fn prekey_response(
+        self,
+        username: &str,
+        bundle: Option<PrekeyBundle>,
+    ) -> Builder<Constructed, C> {
+        let event = self.state.0;
+        let entity = event.construct_prekey_response(username, bundle);
+        let state = Constructed::new(&event, entity);
+        create_builder!(self, state)
+    }
+
+    pub fn session_init(self, init: SessionInit<'_>) -> Builder<Constructed, C> {
+        let event = self.state.0;
+        let entity = event.construct_session_init(init);
+        let state = Constructed::new(&event, entity);
+        create_builder!(self, state)
+    }
+
+    pub fn key_distribution(
+        self,
+        sender: &str,
+        recipient: &str,
+        ciphertext: &str,
+    ) -> Builder<Constructed, C> {
+        let event = self.state.0;
+        let entity = event.construct_key_distribution(sender, recipient, ciphertext);
+        let state = Constructed::new(&event, entity);
+        create_builder!(self, state)
+    }
+
+    /// Takes an entity constructed elsewhere, e.g. to relay it to another peer.
+    pub fn entity(self, entity: Entity<'_>) -> Builder<Constructed, C> {
+        let state = Constructed::new(&self.state.0, entity);
+        create_builder!(self, state)
+    }
+
+    pub fn error(self, code: ErrorCode, text: &str) -> Builder<Constructed, C> {
+        let event = self.state.0;
+        let entity = event.construct_error(code, text);
+        let state = Constructed::new(&event, entity);
         create_builder!(self, state)
     }
 }
@@ -131,8 +207,21 @@ impl<C> Builder<Constructed, C>
 where
     C: CryptoSchema,
 {
-    pub fn encrypt(self, key: &SecretKey) -> Result<Vec<u8>> {
-        self.crypto_system.encrypt(key, &self.state.bytes)
+    /// Encrypts the event as a frame of protocol `version`.
+    pub fn encrypt(self, key: &SecretKey, version: u32) -> Result<Vec<u8>> {
+        let Constructed { tag, bytes } = &self.state;
+        let encrypted =
+            self.crypto_system
+                .encrypt_with_aad(key, bytes, &associated_data(version, *tag))?;
+        Ok(frame(*tag, encrypted))
+    }
+
+    /// Encrypts the event as the next frame of a connection.
+    pub fn seal(self, keys: &mut TransportKeys) -> Result<Vec<u8>> {
+        let Constructed { tag, bytes } = &self.state;
+        let aad = associated_data(keys.version(), *tag);
+        let sealed = keys.seal(&self.crypto_system, bytes, &aad)?;
+        Ok(frame(*tag, sealed))
     }
 }
 
@@ -144,11 +233,31 @@ where
     E: EventSchema,
     C: CryptoSchema,
 {
-    pub fn decrypt(self, key: &SecretKey, blob: &[u8]) -> Result<Builder<Decrypted<E>, C>> {
-        // let bytes = Some(self.crypto_system.decrypt(key, blob)?);
+    /// Decrypts a frame of protocol `version`.
+    pub fn decrypt(
+        self,
+        key: &SecretKey,
+        version: u32,
+        blob: &[u8],
+    ) -> Result<Builder<Decrypted<E>, C>> {
+        let (tag, encrypted) = unframe(blob)?;
+        let aad = associated_data(version, tag);
         let state = Decrypted {
             event: self.state.0,
-            bytes: self.crypto_system.decrypt(key, blob)?,
+            tag,
+            bytes: self.crypto_system.decrypt_with_aad(key, encrypted, &aad)?,
+        };
+        Ok(create_builder!(self, state))
+    }
+
+    /// Decrypts a frame of a connection, rejecting replayed ones.
+    pub fn open(self, keys: &mut TransportKeys, blob: &[u8]) -> Result<Builder<Decrypted<E>, C>> {
+        let (tag, sealed) = unframe(blob)?;
+        let aad = associated_data(keys.version(), tag);
+        let state = Decrypted {
+            event: self.state.0,
+            tag,
+            bytes: keys.open(&self.crypto_system, sealed, &aad)?,
         };
         Ok(create_builder!(self, state))
     }
@@ -161,36 +270,72 @@ where
 {
     pub fn deserialize(&self) -> Result<Entity<'_>> {
         let event = &self.state.event;
-        event.deserialize(&self.state.bytes)
+        let entity = event.deserialize(&self.state.bytes)?;
+        if entity.kind().tag() != self.state.tag {
+            return Err(Error::decode(format!(
+                "frame of kind {} carries a {}",
+                self.state.tag,
+                entity.kind().name()
+            )));
+        }
+        Ok(entity)
     }
+
+    /// Like [`Self::deserialize`], but the entity outlives the builder.
+    pub fn into_entity(self) -> Result<Entity<'static>> {
+        self.deserialize().map(Entity::into_owned)
+    }
+}
+
+/// Binds the kind of an event and the negotiated protocol version to its
+/// frame, so neither can be swapped without breaking the authentication.
+fn associated_data(version: u32, tag: u8) -> Vec<u8> {
+    [FRAME, &version.to_be_bytes(), &[tag]].concat()
+}
+
+/// Frames start with the tag of their event, the receiver needs it to
+/// rebuild the associated data before decrypting.
+fn frame(tag: u8, encrypted: Vec<u8>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(1 + encrypted.len());
+    frame.push(tag);
+    frame.extend(encrypted);
+    frame
+}
+
+fn unframe(blob: &[u8]) -> Result<(u8, &[u8])> {
+    let (tag, encrypted) = blob
+        .split_first()
+        .ok_or_else(|| Error::decode("frame is empty"))?;
+    Ok((*tag, encrypted))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::event::types;
+    use crate::crypto::KeySchedule;
+    use crate::protocol::PROTOCOL_VERSION;
 
     const fn event_system() -> Protobuf {
         Protobuf
     }
 
+    use crate::event::tests::{
+        Expected, AUTH_STATUS, PASSWORD, PUB_KEY, REGI_STATUS, SENDER, TEXT, USERNAME,
+    };
     use once_cell::sync::Lazy;
-    static PUB_KEY: Lazy<PublicKey> = Lazy::new(|| PublicKey::new(rand::random()));
-    static AUTH_STATUS: AuthenticationStatus = AuthenticationStatus::Success;
-    static REGI_STATUS: RegistrationStatus = RegistrationStatus::Success;
-    static USERNAME: &str = "Badum";
-    static PASSWORD: &str = "a$$word";
-    static SENDER: &str = "Meme";
-    static TEXT: &str = "Lorem ipsum dolor sit amet, qui minim labore adipisicing minim sint cillum sint consectetur cupidatat.";
+    static KEY: Lazy<SecretKey> = Lazy::new(|| SecretKey::new(rand::random()));
 
     #[test]
     fn build_handshake() -> Result<()> {
         let constructed = EventBuilder::construct(event_system(), Crypto)
             .handshake(&PUB_KEY)
-            .encrypt(&PUB_KEY)?;
+            .encrypt(&KEY, PROTOCOL_VERSION)?;
 
-        let binding =
-            EventBuilder::deconstruct(event_system(), Crypto).decrypt(&PUB_KEY, &constructed)?;
+        let binding = EventBuilder::deconstruct(event_system(), Crypto).decrypt(
+            &KEY,
+            PROTOCOL_VERSION,
+            &constructed,
+        )?;
         let deconstructed = binding.deserialize()?;
         handle_deconstructed(deconstructed);
         Ok(())
@@ -200,19 +345,25 @@ mod tests {
     fn build_registration() -> Result<()> {
         let constructed = EventBuilder::construct(event_system(), Crypto)
             .registration_request(USERNAME, PASSWORD)
-            .encrypt(&PUB_KEY)?;
+            .encrypt(&KEY, PROTOCOL_VERSION)?;
 
-        let binding =
-            EventBuilder::deconstruct(event_system(), Crypto).decrypt(&PUB_KEY, &constructed)?;
+        let binding = EventBuilder::deconstruct(event_system(), Crypto).decrypt(
+            &KEY,
+            PROTOCOL_VERSION,
+            &constructed,
+        )?;
         let deconstructed = binding.deserialize()?;
         handle_deconstructed(deconstructed);
 
         let constructed = EventBuilder::construct(event_system(), Crypto)
             .registration_response(REGI_STATUS)
-            .encrypt(&PUB_KEY)?;
+            .encrypt(&KEY, PROTOCOL_VERSION)?;
 
-        let binding =
-            EventBuilder::deconstruct(event_system(), Crypto).decrypt(&PUB_KEY, &constructed)?;
+        let binding = EventBuilder::deconstruct(event_system(), Crypto).decrypt(
+            &KEY,
+            PROTOCOL_VERSION,
+            &constructed,
+        )?;
         let deconstructed = binding.deserialize()?;
         handle_deconstructed(deconstructed);
 
@@ -223,19 +374,25 @@ mod tests {
     fn build_authentication() -> Result<()> {
         let constructed = EventBuilder::construct(event_system(), Crypto)
             .authentication_request(USERNAME, PASSWORD)
-            .encrypt(&PUB_KEY)?;
+            .encrypt(&KEY, PROTOCOL_VERSION)?;
 
-        let binding =
-            EventBuilder::deconstruct(event_system(), Crypto).decrypt(&PUB_KEY, &constructed)?;
+        let binding = EventBuilder::deconstruct(event_system(), Crypto).decrypt(
+            &KEY,
+            PROTOCOL_VERSION,
+            &constructed,
+        )?;
         let deconstructed = binding.deserialize()?;
         handle_deconstructed(deconstructed);
 
         let constructed = EventBuilder::construct(event_system(), Crypto)
             .authentication_response(AUTH_STATUS)
-            .encrypt(&PUB_KEY)?;
+            .encrypt(&KEY, PROTOCOL_VERSION)?;
 
-        let binding =
-            EventBuilder::deconstruct(event_system(), Crypto).decrypt(&PUB_KEY, &constructed)?;
+        let binding = EventBuilder::deconstruct(event_system(), Crypto).decrypt(
+            &KEY,
+            PROTOCOL_VERSION,
+            &constructed,
+        )?;
         let deconstructed = binding.deserialize()?;
         handle_deconstructed(deconstructed);
 
@@ -246,54 +403,100 @@ mod tests {
     fn build_message() -> Result<()> {
         let constructed = EventBuilder::construct(event_system(), Crypto)
             .message(SENDER, TEXT)
-            .encrypt(&PUB_KEY)?;
+            .encrypt(&KEY, PROTOCOL_VERSION)?;
 
-        let binding =
-            EventBuilder::deconstruct(event_system(), Crypto).decrypt(&PUB_KEY, &constructed)?;
+        let binding = EventBuilder::deconstruct(event_system(), Crypto).decrypt(
+            &KEY,
+            PROTOCOL_VERSION,
+            &constructed,
+        )?;
         let deconstructed = binding.deserialize()?;
         handle_deconstructed(deconstructed);
         Ok(())
     }
 
-    fn handle_deconstructed(deconstructed: Entity<'_>) {
-        match deconstructed.kind() {
-            EventKind::Handshake(kind) => handle_handshake(kind),
-            EventKind::Registration(kind) => handle_registration(kind),
-            EventKind::Authentication(kind) => handle_authentication(kind),
-            EventKind::Message(kind) => handle_message(kind),
-        }
+    #[test]
+    fn build_owned() -> Result<()> {
+        let constructed = EventBuilder::construct(event_system(), Crypto)
+            .message(SENDER, TEXT)
+            .encrypt(&KEY, PROTOCOL_VERSION)?;
+
+        let entity = EventBuilder::deconstruct(event_system(), Crypto)
+            .decrypt(&KEY, PROTOCOL_VERSION, &constructed)?
+            .into_entity()?;
+        // The decrypted buffer is gone by now, so the entity may leave the thread.
+        std::thread::spawn(move || handle_deconstructed(entity))
+            .join()
+            .unwrap();
+        Ok(())
     }
 
-    fn handle_handshake(kind: &types::Handshake) {
-        assert_eq!(*PUB_KEY, *kind.pub_key());
+    #[test]
+    fn build_sealed() -> Result<()> {
+        let schedule = KeySchedule::new(b"secret", b"transcript");
+        let (mut client, mut server) = (
+            schedule.client(PROTOCOL_VERSION),
+            schedule.server(PROTOCOL_VERSION),
+        );
+        let constructed = EventBuilder::construct(event_system(), Crypto)
+            .message(SENDER, TEXT)
+            .seal(&mut client)?;
+
+        let binding =
+            EventBuilder::deconstruct(event_system(), Crypto).open(&mut server, &constructed)?;
+        handle_deconstructed(binding.deserialize()?);
+
+        let replayed = EventBuilder::deconstruct(event_system(), Crypto)
+            .open(&mut server, &constructed)
+            .err();
+        assert_eq!(replayed, Some(Error::Replayed(0)));
+        Ok(())
     }
 
-    fn handle_registration(kind: &types::Registration<'_>) {
-        match kind {
-            types::Registration::Request(req) => {
-                assert_eq!(USERNAME, req.username());
-                assert_eq!(PASSWORD, req.password());
-            }
-            types::Registration::Response(resp) => {
-                assert_eq!(REGI_STATUS, *resp.status());
-            }
-        }
+    #[test]
+    fn bound_kind() -> Result<()> {
+        let mut constructed = EventBuilder::construct(event_system(), Crypto)
+            .message(SENDER, TEXT)
+            .encrypt(&KEY, PROTOCOL_VERSION)?;
+        // Relabeling the frame breaks its authentication.
+        constructed[0] = 0;
+        let relabeled = EventBuilder::deconstruct(event_system(), Crypto).decrypt(
+            &KEY,
+            PROTOCOL_VERSION,
+            &constructed,
+        );
+        assert!(matches!(relabeled, Err(Error::Crypto(_))));
+        assert!(EventBuilder::deconstruct(event_system(), Crypto)
+            .decrypt(&KEY, PROTOCOL_VERSION, &[])
+            .is_err());
+        Ok(())
     }
 
-    fn handle_authentication(kind: &types::Authentication<'_>) {
-        match kind {
-            types::Authentication::Request(req) => {
-                assert_eq!(USERNAME, req.username());
-                assert_eq!(PASSWORD, req.password());
-            }
-            types::Authentication::Response(resp) => {
-                assert_eq!(AUTH_STATUS, *resp.status());
-            }
-        }
+    #[test]
+    fn bound_version() -> Result<()> {
+        let constructed = EventBuilder::construct(event_system(), Crypto)
+            .ping(42)
+            .encrypt(&KEY, PROTOCOL_VERSION)?;
+        let older = EventBuilder::deconstruct(event_system(), Crypto).decrypt(
+            &KEY,
+            PROTOCOL_VERSION - 1,
+            &constructed,
+        );
+        assert!(matches!(older, Err(Error::Crypto(_))));
+
+        // Frames of a connection carry the version negotiated for it.
+        let schedule = KeySchedule::new(b"secret", b"transcript");
+        let (mut client, mut server) = (schedule.client(8), schedule.server(9));
+        let constructed = EventBuilder::construct(event_system(), Crypto)
+            .ping(42)
+            .seal(&mut client)?;
+        let opened =
+            EventBuilder::deconstruct(event_system(), Crypto).open(&mut server, &constructed);
+        assert!(matches!(opened, Err(Error::Crypto(_))));
+        Ok(())
     }
 
-    fn handle_message(kind: &types::Message<'_>) {
-        assert_eq!(SENDER, kind.sender());
-        assert_eq!(TEXT, kind.text());
+    fn handle_deconstructed(deconstructed: Entity<'_>) {
+        Expected.dispatch(&deconstructed).unwrap();
     }
 }
@@ -0,0 +1,236 @@
+//! Typed dispatch of deserialized events
+
+use super::types::{
+    AuthenticationRequest, AuthenticationResponse, Entity, ErrorEvent, Handshake, KeyDistribution,
+    Message, Ping, Pong, PrekeyBundle, PrekeyRequest, PrekeyResponse, Presence,
+    RegistrationRequest, RegistrationResponse, SessionInit,
+};
+use super::{Authentication, EventKind, Prekeys, Registration};
+use crate::prelude::*;
+
+/// Reacts to every kind of event with its own method.
+///
+/// Every handler spells out each method, so a new [`EventKind`] doesn't
+/// compile until all of them decide what to do with it. Events a handler
+/// rejects are listed in [`unexpected_events!`](crate::unexpected_events), which routes them to
+/// [`EventHandler::unexpected`]. Call [`EventHandler::dispatch`] to route an
+/// entity to the matching method.
+pub trait EventHandler {
+    type Output;
+
+    fn on_handshake(&mut self, entity: &Entity<'_>, event: &Handshake) -> Result<Self::Output>;
+
+    fn on_registration_request(
+        &mut self,
+        entity: &Entity<'_>,
+        event: &RegistrationRequest<'_>,
+    ) -> Result<Self::Output>;
+
+    fn on_registration_response(
+        &mut self,
+        entity: &Entity<'_>,
+        event: &RegistrationResponse,
+    ) -> Result<Self::Output>;
+
+    fn on_authentication_request(
+        &mut self,
+        entity: &Entity<'_>,
+        event: &AuthenticationRequest<'_>,
+    ) -> Result<Self::Output>;
+
+    fn on_authentication_response(
+        &mut self,
+        entity: &Entity<'_>,
+        event: &AuthenticationResponse,
+    ) -> Result<Self::Output>;
+
+    fn on_message(&mut self, entity: &Entity<'_>, event: &Message<'_>) -> Result<Self::Output>;
+
+    fn on_error(&mut self, entity: &Entity<'_>, event: &ErrorEvent<'_>) -> Result<Self::Output>;
+
+    fn on_ping(&mut self, entity: &Entity<'_>, event: &Ping) -> Result<Self::Output>;
+
+    fn on_pong(&mut self, entity: &Entity<'_>, event: &Pong) -> Result<Self::Output>;
+
+    fn on_prekey_upload(
+        &mut self,
+        entity: &Entity<'_>,
+        event: &PrekeyBundle,
+    ) -> Result<Self::Output>;
+
+    fn on_prekey_request(
+        &mut self,
+        entity: &Entity<'_>,
+        event: &PrekeyRequest<'_>,
+    ) -> Result<Self::Output>;
+
+    fn on_prekey_response(
+        &mut self,
+        entity: &Entity<'_>,
+        event: &PrekeyResponse<'_>,
+    ) -> Result<Self::Output>;
+
+    fn on_session_init(
+        &mut self,
+        entity: &Entity<'_>,
+        event: &SessionInit<'_>,
+    ) -> Result<Self::Output>;
+
+    fn on_key_distribution(
+        &mut self,
+        entity: &Entity<'_>,
+        event: &KeyDistribution<'_>,
+    ) -> Result<Self::Output>;
+
+    fn on_presence(&mut self, entity: &Entity<'_>, event: &Presence<'_>) -> Result<Self::Output>;
+
+    /// Called for every event the handler has no method for.
+    fn unexpected(&mut self, entity: &Entity<'_>) -> Result<Self::Output> {
+        Err(Error::unexpected(entity.kind().name()))
+    }
+
+    fn dispatch(&mut self, entity: &Entity<'_>) -> Result<Self::Output> {
+        match entity.kind() {
+            EventKind::Handshake(inner) => self.on_handshake(entity, inner),
+            EventKind::Registration(Registration::Request(inner)) => {
+                self.on_registration_request(entity, inner)
+            }
+            EventKind::Registration(Registration::Response(inner)) => {
+                self.on_registration_response(entity, inner)
+            }
+            EventKind::Authentication(Authentication::Request(inner)) => {
+                self.on_authentication_request(entity, inner)
+            }
+            EventKind::Authentication(Authentication::Response(inner)) => {
+                self.on_authentication_response(entity, inner)
+            }
+            EventKind::Message(inner) => self.on_message(entity, inner),
+            EventKind::Error(inner) => self.on_error(entity, inner),
+            EventKind::Ping(inner) => self.on_ping(entity, inner),
+            EventKind::Pong(inner) => self.on_pong(entity, inner),
+            EventKind::Prekeys(Prekeys::Upload(inner)) => self.on_prekey_upload(entity, inner),
+            EventKind::Prekeys(Prekeys::Request(inner)) => self.on_prekey_request(entity, inner),
+            EventKind::Prekeys(Prekeys::Response(inner)) => self.on_prekey_response(entity, inner),
+            EventKind::SessionInit(inner) => self.on_session_init(entity, inner),
+            EventKind::KeyDistribution(inner) => self.on_key_distribution(entity, inner),
+            EventKind::Presence(inner) => self.on_presence(entity, inner),
+        }
+    }
+}
+
+/// Implements the listed [`EventHandler`] methods by handing their events to
+/// [`EventHandler::unexpected`], for use inside an `impl EventHandler` block.
+#[macro_export]
+macro_rules! unexpected_events {
+    ($($method:ident),+ $(,)?) => {
+        $($crate::unexpected_events!(@ $method);)+
+    };
+    (@ on_handshake) => {
+        $crate::unexpected_events!(@ on_handshake, $crate::event::Handshake);
+    };
+    (@ on_registration_request) => {
+        $crate::unexpected_events!(@ on_registration_request, $crate::event::RegistrationRequest<'_>);
+    };
+    (@ on_registration_response) => {
+        $crate::unexpected_events!(@ on_registration_response, $crate::event::RegistrationResponse);
+    };
+    (@ on_authentication_request) => {
+        $crate::unexpected_events!(@ on_authentication_request, $crate::event::AuthenticationRequest<'_>);
+    };
+    (@ on_authentication_response) => {
+        $crate::unexpected_events!(@ on_authentication_response, $crate::event::AuthenticationResponse);
+    };
+    (@ on_message) => {
+        $crate::unexpected_events!(@ on_message, $crate::event::Message<'_>);
+    };
+    (@ on_error) => {
+        $crate::unexpected_events!(@ on_error, $crate::event::ErrorEvent<'_>);
+    };
+    (@ on_ping) => {
+        $crate::unexpected_events!(@ on_ping, $crate::event::Ping);
+    };
+    (@ on_pong) => {
+        $crate::unexpected_events!(@ on_pong, $crate::event::Pong);
+    };
+    (@ on_prekey_upload) => {
+        $crate::unexpected_events!(@ on_prekey_upload, $crate::event::PrekeyBundle);
+    };
+    (@ on_prekey_request) => {
+        $crate::unexpected_events!(@ on_prekey_request, $crate::event::PrekeyRequest<'_>);
+    };
+    (@ on_prekey_response) => {
+        $crate::unexpected_events!(@ on_prekey_response, $crate::event::PrekeyResponse<'_>);
+    };
+    (@ on_session_init) => {
+        $crate::unexpected_events!(@ on_session_init, $crate::event::SessionInit<'_>);
+    };
+    (@ on_key_distribution) => {
+        $crate::unexpected_events!(@ on_key_distribution, $crate::event::KeyDistribution<'_>);
+    };
+    (@ on_presence) => {
+        $crate::unexpected_events!(@ on_presence, $crate::event::Presence<'_>);
+    };
+    (@ $method:ident, $event:ty) => {
+        fn $method(
+            &mut self,
+            entity: &$crate::event::Entity<'_>,
+            _event: &$event,
+        ) -> $crate::error::Result<Self::Output> {
+            self.unexpected(entity)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts messages and nothing else.
+    #[derive(Default)]
+    struct Messages(usize);
+
+    impl EventHandler for Messages {
+        type Output = ();
+
+        fn on_message(&mut self, _entity: &Entity<'_>, _event: &Message<'_>) -> Result<()> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        crate::unexpected_events!(
+            on_handshake,
+            on_registration_request,
+            on_registration_response,
+            on_authentication_request,
+            on_authentication_response,
+            on_error,
+            on_ping,
+            on_pong,
+            on_prekey_upload,
+            on_prekey_request,
+            on_prekey_response,
+            on_session_init,
+            on_key_distribution,
+            on_presence,
+        );
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut handler = Messages::default();
+        let message = Capnp.construct_message("Meme", "Lorem ipsum");
+        handler.dispatch(&message).unwrap();
+        handler.dispatch(&message).unwrap();
+        assert_eq!(2, handler.0);
+    }
+
+    #[test]
+    fn unexpected() {
+        let mut handler = Messages::default();
+        let response =
+            Capnp.construct_registration_response(crate::event::RegistrationStatus::Success);
+        let err = handler.dispatch(&response).unwrap_err();
+        assert_eq!(Error::unexpected("registration response"), err);
+        assert_eq!(0, handler.0);
+    }
+}
@@ -0,0 +1,672 @@
+use super::types;
+use crate::prelude::*;
+
+/// Human readable mirror of `schema.proto` and `schema.capnp`.
+mod _json {
+    use serde::{Deserialize, Serialize};
+    use ulid::Ulid;
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Entity {
+        pub(super) id: Ulid,
+        pub(super) timestamp: i64,
+        pub(super) kind: Kind,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum Kind {
+        Handshake(Handshake),
+        Registration(Registration),
+        Authentication(Authentication),
+        Message(Message),
+        Error(Error),
+        Ping(Ping),
+        Pong(Pong),
+        Prekeys(Prekeys),
+        SessionInit(SessionInit),
+        KeyDistribution(KeyDistribution),
+        Presence(Presence),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Handshake {
+        pub(super) pub_key: String,
+        pub(super) version: u32,
+        pub(super) capabilities: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) kem: Option<KemShare>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) identity: Option<IdentityProof>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum KemShare {
+        Offer(String),
+        Answer(String),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct IdentityProof {
+        pub(super) key: String,
+        pub(super) signature: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum Registration {
+        Request(Request),
+        Response(RegistrationResponse),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum Authentication {
+        Request(Request),
+        Response(AuthenticationResponse),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Request {
+        pub(super) username: String,
+        pub(super) password: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct RegistrationResponse {
+        pub(super) status: RegistrationStatus,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum RegistrationStatus {
+        Success,
+        UserExists,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct AuthenticationResponse {
+        pub(super) status: AuthenticationStatus,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum AuthenticationStatus {
+        Success,
+        UserDoesNotExist,
+        WrongPassword,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Message {
+        pub(super) sender: String,
+        pub(super) text: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Ping {
+        pub(super) nonce: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Pong {
+        pub(super) nonce: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum Prekeys {
+        Upload(PrekeyBundle),
+        Request(PrekeyRequest),
+        Response(PrekeyResponse),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct PrekeyBundle {
+        pub(super) identity_key: String,
+        pub(super) signed_prekey: String,
+        pub(super) signature: String,
+        pub(super) one_time_prekeys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) kem_prekey: Option<KemPrekey>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct KemPrekey {
+        pub(super) key: String,
+        pub(super) signature: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct PrekeyRequest {
+        pub(super) username: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct PrekeyResponse {
+        pub(super) username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) bundle: Option<PrekeyBundle>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct SessionInit {
+        pub(super) sender: String,
+        pub(super) recipient: String,
+        pub(super) identity_key: String,
+        pub(super) ephemeral_key: String,
+        pub(super) signed_prekey: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) one_time_prekey: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub(super) kem_ciphertext: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct KeyDistribution {
+        pub(super) sender: String,
+        pub(super) recipient: String,
+        pub(super) ciphertext: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Presence {
+        pub(super) username: String,
+        pub(super) online: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Error {
+        pub(super) code: ErrorCode,
+        pub(super) text: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub(super) enum ErrorCode {
+        Internal,
+        Decode,
+        UnexpectedEvent,
+        Incompatible,
+        Crypto,
+        Timeout,
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Json;
+
+impl EventSchema for Json {}
+
+impl Serializable for Json {
+    fn serialize(&self, entity: types::Entity<'_>) -> Vec<u8> {
+        let kind = match entity.kind() {
+            EventKind::Handshake(kind) => serialize::handshake(kind),
+            EventKind::Registration(kind) => serialize::registration(kind),
+            EventKind::Authentication(kind) => serialize::authentication(kind),
+            EventKind::Message(kind) => serialize::message(kind),
+            EventKind::Error(kind) => serialize::error(kind),
+            EventKind::Ping(kind) => serialize::ping(kind),
+            EventKind::Pong(kind) => serialize::pong(kind),
+            EventKind::Prekeys(kind) => serialize::prekeys(kind),
+            EventKind::SessionInit(kind) => serialize::session_init(kind),
+            EventKind::KeyDistribution(kind) => serialize::key_distribution(kind),
+            EventKind::Presence(kind) => serialize::presence(kind),
+        };
+
+        let entity = _json::Entity {
+            id: *entity.id(),
+            timestamp: *entity.timestamp(),
+            kind,
+        };
+
+        // Unwrap is safe, since the entity has neither maps nor custom `Serialize` impls.
+        serde_json::to_vec(&entity).unwrap()
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<types::Entity<'_>> {
+        use _json::Kind;
+
+        let decoded: _json::Entity = serde_json::from_slice(bytes).map_err(Error::decode)?;
+        let id = decoded.id;
+        let timestamp = decoded.timestamp;
+
+        let kind = match decoded.kind {
+            Kind::Handshake(kind) => deserialize::handshake(kind)?,
+            Kind::Registration(kind) => deserialize::registration(kind),
+            Kind::Authentication(kind) => deserialize::authentication(kind),
+            Kind::Message(kind) => deserialize::message(kind),
+            Kind::Error(kind) => deserialize::error(kind),
+            Kind::Ping(kind) => deserialize::ping(kind),
+            Kind::Pong(kind) => deserialize::pong(kind),
+            Kind::Prekeys(kind) => deserialize::prekeys(kind)?,
+            Kind::SessionInit(kind) => deserialize::session_init(kind)?,
+            Kind::KeyDistribution(kind) => deserialize::key_distribution(kind),
+            Kind::Presence(kind) => deserialize::presence(kind),
+        };
+
+        let entity = types::Entity::new(id, timestamp, kind.into());
+        Ok(entity)
+    }
+}
+
+mod serialize {
+    use super::{_json, types, Encodable};
+    use _json::Kind;
+
+    pub(crate) fn handshake(kind: &types::Handshake) -> Kind {
+        let a = _json::Handshake {
+            pub_key: kind.pub_key().encode(),
+            version: *kind.version(),
+            capabilities: kind.capabilities().bits(),
+            kem: kind.kem().map(|kem| match kem {
+                types::KemShare::Offer(key) => _json::KemShare::Offer(key.encode()),
+                types::KemShare::Answer(ciphertext) => _json::KemShare::Answer(ciphertext.encode()),
+            }),
+            identity: kind.identity().map(|identity| _json::IdentityProof {
+                key: identity.key().encode(),
+                signature: identity.signature().encode(),
+            }),
+        };
+        Kind::Handshake(a)
+    }
+
+    pub(crate) fn registration(kind: &types::Registration<'_>) -> Kind {
+        let a = match kind {
+            types::Registration::Request(inner) => {
+                let req = _json::Request {
+                    username: inner.username().to_owned(),
+                    password: inner.password().to_owned(),
+                };
+                _json::Registration::Request(req)
+            }
+            types::Registration::Response(inner) => {
+                let status = match inner.status() {
+                    types::RegistrationStatus::Success => _json::RegistrationStatus::Success,
+                    types::RegistrationStatus::UserExists => _json::RegistrationStatus::UserExists,
+                };
+                _json::Registration::Response(_json::RegistrationResponse { status })
+            }
+        };
+        Kind::Registration(a)
+    }
+
+    pub(crate) fn authentication(kind: &types::Authentication<'_>) -> Kind {
+        let a = match kind {
+            types::Authentication::Request(inner) => {
+                let req = _json::Request {
+                    username: inner.username().to_owned(),
+                    password: inner.password().to_owned(),
+                };
+                _json::Authentication::Request(req)
+            }
+            types::Authentication::Response(inner) => {
+                let status = match inner.status() {
+                    types::AuthenticationStatus::Success => _json::AuthenticationStatus::Success,
+                    types::AuthenticationStatus::UserDoesNotExist => {
+                        _json::AuthenticationStatus::UserDoesNotExist
+                    }
+                    types::AuthenticationStatus::WrongPassword => {
+                        _json::AuthenticationStatus::WrongPassword
+                    }
+                };
+                _json::Authentication::Response(_json::AuthenticationResponse { status })
+            }
+        };
+        Kind::Authentication(a)
+    }
+
+    pub(crate) fn message(kind: &types::Message<'_>) -> Kind {
+        let a = _json::Message {
+            sender: kind.sender().to_owned(),
+            text: kind.text().to_owned(),
+        };
+        Kind::Message(a)
+    }
+
+    pub(crate) fn error(kind: &types::ErrorEvent<'_>) -> Kind {
+        let code = match kind.code() {
+            types::ErrorCode::Internal => _json::ErrorCode::Internal,
+            types::ErrorCode::Decode => _json::ErrorCode::Decode,
+            types::ErrorCode::UnexpectedEvent => _json::ErrorCode::UnexpectedEvent,
+            types::ErrorCode::Incompatible => _json::ErrorCode::Incompatible,
+            types::ErrorCode::Crypto => _json::ErrorCode::Crypto,
+            types::ErrorCode::Timeout => _json::ErrorCode::Timeout,
+        };
+        let a = _json::Error {
+            code,
+            text: kind.text().to_owned(),
+        };
+        Kind::Error(a)
+    }
+
+    pub(crate) fn ping(kind: &types::Ping) -> Kind {
+        Kind::Ping(_json::Ping {
+            nonce: *kind.nonce(),
+        })
+    }
+
+    pub(crate) fn pong(kind: &types::Pong) -> Kind {
+        Kind::Pong(_json::Pong {
+            nonce: *kind.nonce(),
+        })
+    }
+
+    pub(crate) fn prekeys(kind: &types::Prekeys<'_>) -> Kind {
+        let a = match kind {
+            types::Prekeys::Upload(inner) => _json::Prekeys::Upload(bundle(inner)),
+            types::Prekeys::Request(inner) => _json::Prekeys::Request(_json::PrekeyRequest {
+                username: inner.username().to_owned(),
+            }),
+            types::Prekeys::Response(inner) => _json::Prekeys::Response(_json::PrekeyResponse {
+                username: inner.username().to_owned(),
+                bundle: inner.bundle().map(bundle),
+            }),
+        };
+        Kind::Prekeys(a)
+    }
+
+    fn bundle(bundle: &types::PrekeyBundle) -> _json::PrekeyBundle {
+        _json::PrekeyBundle {
+            identity_key: bundle.identity_key().encode(),
+            signed_prekey: bundle.signed_prekey().encode(),
+            signature: bundle.signature().encode(),
+            one_time_prekeys: bundle
+                .one_time_prekeys()
+                .iter()
+                .map(Encodable::encode)
+                .collect(),
+            kem_prekey: bundle.kem_prekey().map(|prekey| _json::KemPrekey {
+                key: prekey.key().encode(),
+                signature: prekey.signature().encode(),
+            }),
+        }
+    }
+
+    pub(crate) fn session_init(kind: &types::SessionInit<'_>) -> Kind {
+        let a = _json::SessionInit {
+            sender: kind.sender().to_owned(),
+            recipient: kind.recipient().to_owned(),
+            identity_key: kind.identity_key().encode(),
+            ephemeral_key: kind.ephemeral_key().encode(),
+            signed_prekey: kind.signed_prekey().encode(),
+            one_time_prekey: kind.one_time_prekey().map(Encodable::encode),
+            kem_ciphertext: kind.kem_ciphertext().map(Encodable::encode),
+        };
+        Kind::SessionInit(a)
+    }
+
+    pub(crate) fn key_distribution(kind: &types::KeyDistribution<'_>) -> Kind {
+        let a = _json::KeyDistribution {
+            sender: kind.sender().to_owned(),
+            recipient: kind.recipient().to_owned(),
+            ciphertext: kind.ciphertext().to_owned(),
+        };
+        Kind::KeyDistribution(a)
+    }
+
+    pub(crate) fn presence(kind: &types::Presence<'_>) -> Kind {
+        let a = _json::Presence {
+            username: kind.username().to_owned(),
+            online: *kind.online(),
+        };
+        Kind::Presence(a)
+    }
+}
+
+mod deserialize {
+    use super::{_json, types, Encodable, EventKind, PublicKey, Result, Signature, Then};
+    use crate::crypto::{EncapsulationKey, KemCiphertext};
+    use crate::protocol::Capabilities;
+
+    pub(crate) fn handshake<'a>(kind: _json::Handshake) -> Result<EventKind<'a>> {
+        let pub_key = kind.pub_key.then(PublicKey::try_decode)?;
+        let capabilities = Capabilities::from_bits(kind.capabilities);
+        let identity = match kind.identity {
+            Some(identity) => Some(types::IdentityProof::new(
+                PublicKey::try_decode(identity.key)?,
+                Signature::try_decode(identity.signature)?,
+            )),
+            None => None,
+        };
+        let kem = match kind.kem {
+            Some(_json::KemShare::Offer(key)) => {
+                Some(types::KemShare::Offer(EncapsulationKey::try_decode(key)?))
+            }
+            Some(_json::KemShare::Answer(ciphertext)) => Some(types::KemShare::Answer(
+                KemCiphertext::try_decode(ciphertext)?,
+            )),
+            None => None,
+        };
+        Ok(EventKind::Handshake(types::Handshake::new(
+            pub_key,
+            kind.version,
+            capabilities,
+            kem,
+            identity,
+        )))
+    }
+
+    pub(crate) fn registration<'a>(kind: _json::Registration) -> EventKind<'a> {
+        let a = match kind {
+            _json::Registration::Request(req) => {
+                let request =
+                    types::RegistrationRequest::new(req.username.into(), req.password.into());
+                types::Registration::Request(request)
+            }
+            _json::Registration::Response(resp) => {
+                let status = match resp.status {
+                    _json::RegistrationStatus::Success => types::RegistrationStatus::Success,
+                    _json::RegistrationStatus::UserExists => types::RegistrationStatus::UserExists,
+                };
+                types::Registration::Response(types::RegistrationResponse::new(status))
+            }
+        };
+        EventKind::Registration(a)
+    }
+
+    pub(crate) fn authentication<'a>(kind: _json::Authentication) -> EventKind<'a> {
+        let a = match kind {
+            _json::Authentication::Request(req) => {
+                let request =
+                    types::AuthenticationRequest::new(req.username.into(), req.password.into());
+                types::Authentication::Request(request)
+            }
+            _json::Authentication::Response(resp) => {
+                let status = match resp.status {
+                    _json::AuthenticationStatus::Success => types::AuthenticationStatus::Success,
+                    _json::AuthenticationStatus::UserDoesNotExist => {
+                        types::AuthenticationStatus::UserDoesNotExist
+                    }
+                    _json::AuthenticationStatus::WrongPassword => {
+                        types::AuthenticationStatus::WrongPassword
+                    }
+                };
+                types::Authentication::Response(types::AuthenticationResponse::new(status))
+            }
+        };
+        EventKind::Authentication(a)
+    }
+
+    pub(crate) fn message<'a>(kind: _json::Message) -> EventKind<'a> {
+        let sender = kind.sender;
+        let text = kind.text;
+        EventKind::Message(types::Message::new(sender.into(), text.into()))
+    }
+
+    pub(crate) fn error<'a>(kind: _json::Error) -> EventKind<'a> {
+        let code = match kind.code {
+            _json::ErrorCode::Internal => types::ErrorCode::Internal,
+            _json::ErrorCode::Decode => types::ErrorCode::Decode,
+            _json::ErrorCode::UnexpectedEvent => types::ErrorCode::UnexpectedEvent,
+            _json::ErrorCode::Incompatible => types::ErrorCode::Incompatible,
+            _json::ErrorCode::Crypto => types::ErrorCode::Crypto,
+            _json::ErrorCode::Timeout => types::ErrorCode::Timeout,
+        };
+        EventKind::Error(types::ErrorEvent::new(code, kind.text.into()))
+    }
+
+    pub(crate) fn ping<'a>(kind: _json::Ping) -> EventKind<'a> {
+        EventKind::Ping(types::Ping::new(kind.nonce))
+    }
+
+    pub(crate) fn pong<'a>(kind: _json::Pong) -> EventKind<'a> {
+        EventKind::Pong(types::Pong::new(kind.nonce))
+    }
+
+    pub(crate) fn prekeys<'a>(kind: _json::Prekeys) -> Result<EventKind<'a>> {
+        let a = match kind {
+            _json::Prekeys::Upload(upload) => types::Prekeys::Upload(bundle(upload)?),
+            _json::Prekeys::Request(req) => {
+                types::Prekeys::Request(types::PrekeyRequest::new(req.username.into()))
+            }
+            _json::Prekeys::Response(resp) => {
+                let bundle = resp.bundle.map(bundle).transpose()?;
+                let response = types::PrekeyResponse::new(resp.username.into(), bundle);
+                types::Prekeys::Response(response)
+            }
+        };
+        Ok(EventKind::Prekeys(a))
+    }
+
+    fn bundle(bundle: _json::PrekeyBundle) -> Result<types::PrekeyBundle> {
+        crate::event::decode_bundle(
+            bundle.identity_key.as_bytes(),
+            bundle.signed_prekey.as_bytes(),
+            bundle.signature.as_bytes(),
+            bundle.one_time_prekeys.iter().map(String::as_bytes),
+            bundle.kem_prekey.map(kem_prekey).transpose()?,
+        )
+    }
+
+    fn kem_prekey(prekey: _json::KemPrekey) -> Result<types::KemPrekey> {
+        Ok(types::KemPrekey::new(
+            EncapsulationKey::try_decode(prekey.key)?,
+            Signature::try_decode(prekey.signature)?,
+        ))
+    }
+
+    pub(crate) fn session_init<'a>(kind: _json::SessionInit) -> Result<EventKind<'a>> {
+        let one_time_prekey = kind
+            .one_time_prekey
+            .map(PublicKey::try_decode)
+            .transpose()?;
+        let kem_ciphertext = kind
+            .kem_ciphertext
+            .map(KemCiphertext::try_decode)
+            .transpose()?;
+        Ok(EventKind::SessionInit(types::SessionInit::new(
+            kind.sender.into(),
+            kind.recipient.into(),
+            kind.identity_key.then(PublicKey::try_decode)?,
+            kind.ephemeral_key.then(PublicKey::try_decode)?,
+            kind.signed_prekey.then(PublicKey::try_decode)?,
+            one_time_prekey,
+            kem_ciphertext,
+        )))
+    }
+
+    pub(crate) fn key_distribution<'a>(kind: _json::KeyDistribution) -> EventKind<'a> {
+        EventKind::KeyDistribution(types::KeyDistribution::new(
+            kind.sender.into(),
+            kind.recipient.into(),
+            kind.ciphertext.into(),
+        ))
+    }
+
+    pub(crate) fn presence<'a>(kind: _json::Presence) -> EventKind<'a> {
+        EventKind::Presence(types::Presence::new(kind.username.into(), kind.online))
+    }
+}
+
+impl Constructable for Json {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Json>();
+    }
+    #[test]
+    fn test_sync() {
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<Json>();
+    }
+    #[test]
+    fn handshake() {
+        crate::event::tests::handshake(Json);
+    }
+
+    #[test]
+    fn registration() {
+        crate::event::tests::registration(Json);
+    }
+
+    #[test]
+    fn authentication() {
+        crate::event::tests::authentication(Json);
+    }
+
+    #[test]
+    fn message() {
+        crate::event::tests::message(Json);
+    }
+
+    #[test]
+    fn error() {
+        crate::event::tests::error(Json);
+    }
+
+    #[test]
+    fn keepalive() {
+        crate::event::tests::keepalive(Json);
+    }
+
+    #[test]
+    fn prekeys() {
+        crate::event::tests::prekeys(Json);
+    }
+
+    #[test]
+    fn session_init() {
+        crate::event::tests::session_init(Json);
+    }
+
+    #[test]
+    fn group() {
+        crate::event::tests::group(Json);
+    }
+
+    #[test]
+    fn fixture() {
+        let fixture = r#"{
+            "id": "01HF7YAT00ZX4SS0A5T1PKDMZA",
+            "timestamp": 1700000000000,
+            "kind": {
+                "authentication": {
+                    "response": { "status": "wrong_password" }
+                }
+            }
+        }"#;
+        let deserialized = Json.deserialize(fixture.as_bytes()).unwrap();
+        assert_eq!("01HF7YAT00ZX4SS0A5T1PKDMZA", deserialized.id().to_string());
+        assert_eq!(1700000000000, *deserialized.timestamp());
+        let response = deserialized.expect_authentication_response().unwrap();
+        assert_eq!(
+            types::AuthenticationStatus::WrongPassword,
+            *response.status()
+        );
+
+        let entity = Json.construct_message("Meme", "Lorem ipsum");
+        let id = entity.id().to_string();
+        let serialized = Json.serialize(entity);
+        let value: serde_json::Value = serde_json::from_slice(&serialized).unwrap();
+        assert_eq!(id, value["id"]);
+        assert_eq!("Meme", value["kind"]["message"]["sender"]);
+        assert_eq!("Lorem ipsum", value["kind"]["message"]["text"]);
+    }
+}
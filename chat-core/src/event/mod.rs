@@ -1,14 +1,25 @@
-use crate::prelude::*;
+use crate::{
+    crypto::EncapsulationKey,
+    prelude::*,
+    protocol::{Capabilities, PROTOCOL_VERSION},
+};
 
 mod capnp;
+mod dynamic;
 mod event_builder;
+mod handler;
+mod json;
 mod protobuf;
 mod types;
 
 pub use capnp::Capnp;
+pub use dynamic::DynSchema;
 pub use event_builder::EventBuilder;
+pub use handler::EventHandler;
+pub use json::Json;
 pub use protobuf::Protobuf;
 pub use types::*;
+pub use ulid::Ulid;
 
 pub trait EventSchema: Serializable + Constructable {}
 
@@ -19,9 +30,32 @@ pub trait Serializable {
 
 pub trait Constructable: Serializable {
     fn construct_handshake(&self, pub_key: &PublicKey) -> types::Entity<'_> {
-        let a = types::Handshake::new(*pub_key);
+        self.construct_handshake_with(pub_key, Capabilities::supported())
+    }
+
+    fn construct_handshake_with(
+        &self,
+        pub_key: &PublicKey,
+        capabilities: Capabilities,
+    ) -> types::Entity<'_> {
+        let a = types::Handshake::new(*pub_key, PROTOCOL_VERSION, capabilities, None, None);
+        let kind = types::EventKind::Handshake(a);
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    /// Handshake proving it comes from the owner of the `identity` key pair.
+    fn construct_signed_handshake<C: CryptoSchema>(
+        &self,
+        pub_key: &PublicKey,
+        capabilities: Capabilities,
+        kem: Option<types::KemShare>,
+        crypto: &C,
+        identity: &KeyPair,
+    ) -> types::Entity<'_> {
+        let a = types::Handshake::new(*pub_key, PROTOCOL_VERSION, capabilities, kem, None)
+            .signed(crypto, identity);
         let kind = types::EventKind::Handshake(a);
-        types::Entity::new(timestamp(), kind.into())
+        types::Entity::new(id(), timestamp(), kind.into())
     }
 
     fn construct_registration_request<'a>(
@@ -32,7 +66,7 @@ pub trait Constructable: Serializable {
         let a = types::RegistrationRequest::new(username.into(), password.into());
         let a = types::Registration::Request(a);
         let kind = types::EventKind::Registration(a);
-        types::Entity::new(timestamp(), kind.into())
+        types::Entity::new(id(), timestamp(), kind.into())
     }
 
     fn construct_registration_response(
@@ -42,7 +76,7 @@ pub trait Constructable: Serializable {
         let a = types::RegistrationResponse::new(status);
         let a = types::Registration::Response(a);
         let kind = types::EventKind::Registration(a);
-        types::Entity::new(timestamp(), kind.into())
+        types::Entity::new(id(), timestamp(), kind.into())
     }
 
     fn construct_authentication_request<'a>(
@@ -53,7 +87,7 @@ pub trait Constructable: Serializable {
         let a = types::AuthenticationRequest::new(username.into(), password.into());
         let a = types::Authentication::Request(a);
         let kind = types::EventKind::Authentication(a);
-        types::Entity::new(timestamp(), kind.into())
+        types::Entity::new(id(), timestamp(), kind.into())
     }
 
     fn construct_authentication_response(
@@ -63,18 +97,156 @@ pub trait Constructable: Serializable {
         let a = types::AuthenticationResponse::new(status);
         let a = types::Authentication::Response(a);
         let kind = types::EventKind::Authentication(a);
-        types::Entity::new(timestamp(), kind.into())
+        types::Entity::new(id(), timestamp(), kind.into())
     }
 
     fn construct_message<'a>(&'a self, sender: &'a str, text: &'a str) -> types::Entity<'a> {
         let a = types::Message::new(sender.into(), text.into());
         let kind = types::EventKind::Message(a);
-        types::Entity::new(timestamp(), kind.into())
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    fn construct_ping(&self, nonce: u64) -> types::Entity<'_> {
+        let a = types::Ping::new(nonce);
+        let kind = types::EventKind::Ping(a);
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    fn construct_pong(&self, nonce: u64) -> types::Entity<'_> {
+        let a = types::Pong::new(nonce);
+        let kind = types::EventKind::Pong(a);
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    fn construct_error<'a>(&'a self, code: types::ErrorCode, text: &'a str) -> types::Entity<'a> {
+        let a = types::ErrorEvent::new(code, text.into());
+        let kind = types::EventKind::Error(a);
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    fn construct_prekey_upload(&self, bundle: types::PrekeyBundle) -> types::Entity<'_> {
+        let a = types::Prekeys::Upload(bundle);
+        let kind = types::EventKind::Prekeys(a);
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    fn construct_prekey_request<'a>(&'a self, username: &'a str) -> types::Entity<'a> {
+        let a = types::PrekeyRequest::new(username.into());
+        let a = types::Prekeys::Request(a);
+        let kind = types::EventKind::Prekeys(a);
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    fn construct_prekey_response<'a>(
+        &'a self,
+        username: &'a str,
+        bundle: Option<types::PrekeyBundle>,
+    ) -> types::Entity<'a> {
+        let a = types::PrekeyResponse::new(username.into(), bundle);
+        let a = types::Prekeys::Response(a);
+        let kind = types::EventKind::Prekeys(a);
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    fn construct_session_init<'a>(&'a self, init: types::SessionInit<'a>) -> types::Entity<'a> {
+        let kind = types::EventKind::SessionInit(init);
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    fn construct_key_distribution<'a>(
+        &'a self,
+        sender: &'a str,
+        recipient: &'a str,
+        ciphertext: &'a str,
+    ) -> types::Entity<'a> {
+        let a = types::KeyDistribution::new(sender.into(), recipient.into(), ciphertext.into());
+        let kind = types::EventKind::KeyDistribution(a);
+        types::Entity::new(id(), timestamp(), kind.into())
+    }
+
+    fn construct_presence<'a>(&'a self, username: &'a str, online: bool) -> types::Entity<'a> {
+        let a = types::Presence::new(username.into(), online);
+        let kind = types::EventKind::Presence(a);
+        types::Entity::new(id(), timestamp(), kind.into())
     }
 }
 
+/// Current time in milliseconds since the Unix epoch.
 pub fn timestamp() -> i64 {
-    chrono::Utc::now().timestamp()
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Fresh identifier, sortable by the time it was generated at.
+pub fn id() -> Ulid {
+    Ulid::new()
+}
+
+/// Reads an identifier as it's laid out in the binary schemas.
+pub(crate) fn decode_id(bytes: &[u8]) -> Result<Ulid> {
+    <[u8; 16]>::try_from(bytes)
+        .map(Ulid::from_bytes)
+        .map_err(|_| Error::decode("Bad event structure"))
+}
+
+/// Reads an identity proof as it's laid out in the binary schemas, where a
+/// missing proof is two empty strings.
+pub(crate) fn decode_identity(key: &[u8], signature: &[u8]) -> Result<Option<IdentityProof>> {
+    if key.is_empty() && signature.is_empty() {
+        return Ok(None);
+    }
+    let key = PublicKey::try_decode(key)?;
+    let signature = Signature::try_decode(signature)?;
+    Ok(Some(IdentityProof::new(key, signature)))
+}
+
+/// Reads a key which may be missing from the binary schemas, where it's then
+/// an empty string.
+pub(crate) fn decode_optional<T: Encodable>(key: &[u8]) -> Result<Option<T>> {
+    if key.is_empty() {
+        return Ok(None);
+    }
+    T::try_decode(key).map(Some)
+}
+
+/// Reads the ML-KEM share of a handshake as it's laid out in the binary
+/// schemas.
+pub(crate) fn decode_kem_share(offer: &[u8], answer: &[u8]) -> Result<Option<KemShare>> {
+    match (decode_optional(offer)?, decode_optional(answer)?) {
+        (None, None) => Ok(None),
+        (Some(offer), None) => Ok(Some(KemShare::Offer(offer))),
+        (None, Some(answer)) => Ok(Some(KemShare::Answer(answer))),
+        (Some(_), Some(_)) => Err(Error::decode("Handshake both offers and answers a KEM")),
+    }
+}
+
+pub(crate) fn decode_kem_prekey(key: &[u8], signature: &[u8]) -> Result<Option<KemPrekey>> {
+    if key.is_empty() && signature.is_empty() {
+        return Ok(None);
+    }
+    let key = EncapsulationKey::try_decode(key)?;
+    let signature = Signature::try_decode(signature)?;
+    Ok(Some(KemPrekey::new(key, signature)))
+}
+
+/// Reads a prekey bundle as it's laid out in the binary schemas.
+pub(crate) fn decode_bundle<'b>(
+    identity_key: &[u8],
+    signed_prekey: &[u8],
+    signature: &[u8],
+    one_time_prekeys: impl IntoIterator<Item = &'b [u8]>,
+    kem_prekey: Option<KemPrekey>,
+) -> Result<PrekeyBundle> {
+    let one_time_prekeys = one_time_prekeys
+        .into_iter()
+        .map(PublicKey::try_decode)
+        .collect::<Result<_>>()?;
+    Ok(PrekeyBundle::new(
+        PublicKey::try_decode(identity_key)?,
+        PublicKey::try_decode(signed_prekey)?,
+        Signature::try_decode(signature)?,
+        one_time_prekeys,
+        kem_prekey,
+    ))
 }
 
 #[cfg(test)]
@@ -82,87 +254,465 @@ pub(crate) mod tests {
     use super::*;
 
     use once_cell::sync::Lazy;
-    static PUB_KEY: Lazy<PublicKey> = Lazy::new(|| PublicKey::new(rand::random()));
-    static AUTH_STATUS: AuthenticationStatus = AuthenticationStatus::Success;
-    static REGI_STATUS: RegistrationStatus = RegistrationStatus::Success;
-    static USERNAME: &str = "Badum";
-    static PASSWORD: &str = "a$$word";
-    static SENDER: &str = "Meme";
-    static TEXT: &str = "Lorem ipsum dolor sit amet, qui minim labore adipisicing minim sint cillum sint consectetur cupidatat.";
+    pub(crate) static PUB_KEY: Lazy<PublicKey> = Lazy::new(|| PublicKey::new(rand::random()));
+    static IDENTITY: Lazy<KeyPair> = Lazy::new(KeyPair::new_signing);
+    pub(crate) static AUTH_STATUS: AuthenticationStatus = AuthenticationStatus::Success;
+    pub(crate) static REGI_STATUS: RegistrationStatus = RegistrationStatus::Success;
+    pub(crate) static USERNAME: &str = "Badum";
+    pub(crate) static PASSWORD: &str = "a$$word";
+    pub(crate) static SENDER: &str = "Meme";
+    pub(crate) static TEXT: &str = "Lorem ipsum dolor sit amet, qui minim labore adipisicing minim sint cillum sint consectetur cupidatat.";
+    static TIMESTAMP: i64 = 1_700_000_000_123;
+    static ERROR_CODE: ErrorCode = ErrorCode::UnexpectedEvent;
+    static NONCE: u64 = u64::MAX - 1;
+    static SIGNED_PREKEY: Lazy<KeyPair> = Lazy::new(KeyPair::new_dh);
+    static ONE_TIME_PREKEYS: Lazy<Vec<PublicKey>> =
+        Lazy::new(|| (0..3).map(|_| *KeyPair::new_dh().public()).collect());
+    static KEM: Lazy<crate::crypto::KemKeyPair> = Lazy::new(crate::crypto::KemKeyPair::new);
+
+    fn bundle(one_time_prekeys: Vec<PublicKey>) -> PrekeyBundle {
+        PrekeyBundle::signed(
+            &Crypto,
+            &IDENTITY,
+            *SIGNED_PREKEY.public(),
+            one_time_prekeys,
+            Some(KEM.public().clone()),
+        )
+    }
 
     pub(crate) fn handshake<E: EventSchema + Clone>(event: E) {
-        let entity = event.construct_handshake(&PUB_KEY);
+        let entity = event
+            .construct_handshake(&PUB_KEY)
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
         let serialized = event.serialize(entity);
-        handle_serialized(event.clone(), &serialized).unwrap();
+        handle_serialized(event.clone(), id, &serialized).unwrap();
+        let deserialized = event.deserialize(&serialized).unwrap();
+        assert!(deserialized
+            .expect_handshake()
+            .unwrap()
+            .identity()
+            .is_none());
+
+        let (ciphertext, _) = KEM.public().encapsulate();
+        let shares = [
+            None,
+            Some(KemShare::Offer(KEM.public().clone())),
+            Some(KemShare::Answer(ciphertext)),
+        ];
+        for kem in shares {
+            let entity = event
+                .construct_signed_handshake(
+                    &PUB_KEY,
+                    Capabilities::supported(),
+                    kem.clone(),
+                    &Crypto,
+                    &IDENTITY,
+                )
+                .with_timestamp(TIMESTAMP);
+            let id = *entity.id();
+            let serialized = event.serialize(entity);
+            handle_serialized(event.clone(), id, &serialized).unwrap();
+            let deserialized = event.deserialize(&serialized).unwrap();
+            let handshake = deserialized.expect_handshake().unwrap();
+            assert_eq!(Some(IDENTITY.public()), handshake.verify(&Crypto).unwrap());
+            assert_eq!(kem.as_ref(), handshake.kem());
+        }
     }
 
     pub(crate) fn registration<E: EventSchema + Clone>(event: E) {
-        let entity = event.construct_registration_request(USERNAME, PASSWORD);
+        let entity = event
+            .construct_registration_request(USERNAME, PASSWORD)
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
         let serialized = event.serialize(entity);
-        handle_serialized(event.clone(), &serialized).unwrap();
+        handle_serialized(event.clone(), id, &serialized).unwrap();
 
-        let entity = event.construct_registration_response(REGI_STATUS);
+        let entity = event
+            .construct_registration_response(REGI_STATUS)
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
         let serialized = event.serialize(entity);
-        handle_serialized(event.clone(), &serialized).unwrap();
+        handle_serialized(event.clone(), id, &serialized).unwrap();
     }
 
     pub(crate) fn authentication<E: EventSchema + Clone>(event: E) {
-        let entity = event.construct_authentication_request(USERNAME, PASSWORD);
+        let entity = event
+            .construct_authentication_request(USERNAME, PASSWORD)
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
         let serialized = event.serialize(entity);
-        handle_serialized(event.clone(), &serialized).unwrap();
+        handle_serialized(event.clone(), id, &serialized).unwrap();
 
-        let entity = event.construct_authentication_response(AUTH_STATUS);
+        let entity = event
+            .construct_authentication_response(AUTH_STATUS)
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
         let serialized = event.serialize(entity);
-        handle_serialized(event.clone(), &serialized).unwrap();
+        handle_serialized(event.clone(), id, &serialized).unwrap();
     }
 
     pub(crate) fn message<E: EventSchema + Clone>(event: E) {
-        let entity = event.construct_message(SENDER, TEXT);
+        let entity = event
+            .construct_message(SENDER, TEXT)
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
         let serialized = event.serialize(entity);
-        handle_serialized(event.clone(), &serialized).unwrap();
+        handle_serialized(event.clone(), id, &serialized).unwrap();
     }
 
-    fn handle_serialized<E: EventSchema + Clone>(event: E, serialized: &[u8]) -> Result<()> {
-        let deserialized = event.deserialize(serialized)?;
-        match deserialized.kind() {
-            EventKind::Handshake(kind) => handle_handshake(kind),
-            EventKind::Registration(kind) => handle_registration(kind),
-            EventKind::Authentication(kind) => handle_authentication(kind),
-            EventKind::Message(kind) => handle_message(kind),
+    pub(crate) fn keepalive<E: EventSchema + Clone>(event: E) {
+        let entity = event.construct_ping(NONCE).with_timestamp(TIMESTAMP);
+        let id = *entity.id();
+        let serialized = event.serialize(entity);
+        handle_serialized(event.clone(), id, &serialized).unwrap();
+
+        let entity = event.construct_pong(NONCE).with_timestamp(TIMESTAMP);
+        let id = *entity.id();
+        let serialized = event.serialize(entity);
+        handle_serialized(event.clone(), id, &serialized).unwrap();
+    }
+
+    pub(crate) fn prekeys<E: EventSchema + Clone>(event: E) {
+        let entity = event
+            .construct_prekey_upload(bundle(ONE_TIME_PREKEYS.clone()))
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
+        let serialized = event.serialize(entity);
+        handle_serialized(event.clone(), id, &serialized).unwrap();
+
+        let entity = event
+            .construct_prekey_request(USERNAME)
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
+        let serialized = event.serialize(entity);
+        handle_serialized(event.clone(), id, &serialized).unwrap();
+
+        for bundle in [Some(bundle(ONE_TIME_PREKEYS[..1].to_vec())), None] {
+            let entity = event
+                .construct_prekey_response(USERNAME, bundle.clone())
+                .with_timestamp(TIMESTAMP);
+            let id = *entity.id();
+            let serialized = event.serialize(entity);
+            handle_serialized(event.clone(), id, &serialized).unwrap();
+            let deserialized = event.deserialize(&serialized).unwrap();
+            let EventKind::Prekeys(Prekeys::Response(response)) = deserialized.kind() else {
+                panic!("Expected a prekey response");
+            };
+            assert_eq!(bundle.as_ref(), response.bundle());
         }
-        Ok(())
     }
 
-    fn handle_handshake(kind: &types::Handshake) {
-        assert_eq!(*PUB_KEY, *kind.pub_key());
+    pub(crate) fn session_init<E: EventSchema + Clone>(event: E) {
+        let (ciphertext, _) = KEM.public().encapsulate();
+        let prekeys = [(Some(ONE_TIME_PREKEYS[0]), Some(ciphertext)), (None, None)];
+        for (one_time_prekey, kem_ciphertext) in prekeys {
+            let init = SessionInit::new(
+                SENDER.into(),
+                USERNAME.into(),
+                *IDENTITY.public(),
+                *PUB_KEY,
+                *SIGNED_PREKEY.public(),
+                one_time_prekey,
+                kem_ciphertext.clone(),
+            );
+            let entity = event.construct_session_init(init).with_timestamp(TIMESTAMP);
+            let id = *entity.id();
+            let serialized = event.serialize(entity);
+            handle_serialized(event.clone(), id, &serialized).unwrap();
+            let deserialized = event.deserialize(&serialized).unwrap();
+            let EventKind::SessionInit(init) = deserialized.kind() else {
+                panic!("Expected a session init");
+            };
+            assert_eq!(one_time_prekey.as_ref(), init.one_time_prekey());
+            assert_eq!(kem_ciphertext.as_ref(), init.kem_ciphertext());
+        }
     }
 
-    fn handle_registration(kind: &types::Registration<'_>) {
-        match kind {
-            types::Registration::Request(req) => {
-                assert_eq!(USERNAME, req.username());
-                assert_eq!(PASSWORD, req.password());
-            }
-            types::Registration::Response(resp) => {
-                assert_eq!(REGI_STATUS, *resp.status());
-            }
+    pub(crate) fn group<E: EventSchema + Clone>(event: E) {
+        let entity = event
+            .construct_key_distribution(SENDER, USERNAME, TEXT)
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
+        let serialized = event.serialize(entity);
+        handle_serialized(event.clone(), id, &serialized).unwrap();
+
+        for online in [true, false] {
+            let entity = event
+                .construct_presence(USERNAME, online)
+                .with_timestamp(TIMESTAMP);
+            let id = *entity.id();
+            let serialized = event.serialize(entity);
+            handle_serialized(event.clone(), id, &serialized).unwrap();
+            let deserialized = event.deserialize(&serialized).unwrap();
+            let EventKind::Presence(presence) = deserialized.kind() else {
+                panic!("Expected a presence");
+            };
+            assert_eq!(online, *presence.online());
         }
     }
 
-    fn handle_authentication(kind: &types::Authentication<'_>) {
-        match kind {
-            types::Authentication::Request(req) => {
-                assert_eq!(USERNAME, req.username());
-                assert_eq!(PASSWORD, req.password());
+    pub(crate) fn error<E: EventSchema + Clone>(event: E) {
+        let entity = event
+            .construct_error(ERROR_CODE, TEXT)
+            .with_timestamp(TIMESTAMP);
+        let id = *entity.id();
+        let serialized = event.serialize(entity);
+        handle_serialized(event.clone(), id, &serialized).unwrap();
+    }
+
+    fn handle_serialized<E: EventSchema + Clone>(
+        event: E,
+        id: Ulid,
+        serialized: &[u8],
+    ) -> Result<()> {
+        let deserialized = event.deserialize(serialized)?;
+        assert_eq!(id, *deserialized.id());
+        assert_eq!(TIMESTAMP, *deserialized.timestamp());
+        Expected.dispatch(&deserialized)
+    }
+
+    #[test]
+    fn timestamp_in_millis() {
+        let before = chrono::Utc::now().timestamp() * 1000;
+        let now = timestamp();
+        let after = chrono::Utc::now().timestamp() * 1000 + 1000;
+        assert!((before..after).contains(&now));
+    }
+
+    #[test]
+    fn ids_are_unique_and_sorted() {
+        let ids: Vec<_> = (0..64).map(|_| id()).collect();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(ids.len(), sorted.len());
+        // Identifiers generated in different milliseconds keep their order.
+        let earlier = Ulid::from_parts(timestamp() as u64 - 1, u128::MAX);
+        assert!(earlier < id());
+    }
+
+    #[test]
+    fn tampered_handshake() {
+        let offer = Some(KemShare::Offer(KEM.public().clone()));
+        let handshake = Handshake::new(
+            *PUB_KEY,
+            PROTOCOL_VERSION,
+            Capabilities::supported(),
+            offer.clone(),
+            None,
+        )
+        .signed(&Crypto, &IDENTITY);
+        assert!(handshake.verify(&Crypto).is_ok());
+
+        // Downgrading the capabilities invalidates the signature.
+        let tampered = Handshake::new(
+            *PUB_KEY,
+            PROTOCOL_VERSION,
+            Capabilities::EMPTY,
+            offer,
+            handshake.identity().cloned(),
+        );
+        assert!(tampered.verify(&Crypto).is_err());
+
+        // So does leaving out or swapping the KEM offer.
+        let other = crate::crypto::KemKeyPair::new();
+        for kem in [None, Some(KemShare::Offer(other.public().clone()))] {
+            let tampered = Handshake::new(
+                *PUB_KEY,
+                PROTOCOL_VERSION,
+                Capabilities::supported(),
+                kem,
+                handshake.identity().cloned(),
+            );
+            assert!(tampered.verify(&Crypto).is_err());
+        }
+    }
+
+    #[test]
+    fn decode_malformed_kem_share() {
+        assert!(decode_kem_share(b"", b"").unwrap().is_none());
+        let (ciphertext, _) = KEM.public().encapsulate();
+        let (offer, answer) = (KEM.public().encode(), ciphertext.encode());
+        assert!(decode_kem_share(offer.as_bytes(), answer.as_bytes()).is_err());
+        // A ciphertext isn't an encapsulation key.
+        assert!(decode_kem_share(answer.as_bytes(), b"").is_err());
+    }
+
+    #[test]
+    fn decode_malformed_identity() {
+        assert!(decode_identity(b"", b"").unwrap().is_none());
+        let key = IDENTITY.public().encode();
+        assert!(decode_identity(key.as_bytes(), b"").is_err());
+    }
+
+    #[test]
+    fn forged_bundle() {
+        let bundle = bundle(Vec::new());
+        assert!(bundle.verify(&Crypto).is_ok());
+
+        let forged = PrekeyBundle::new(
+            *bundle.identity_key(),
+            *PUB_KEY,
+            *bundle.signature(),
+            Vec::new(),
+            bundle.kem_prekey().cloned(),
+        );
+        assert!(forged.verify(&Crypto).is_err());
+
+        let kem_prekey = bundle.kem_prekey().unwrap();
+        let other = crate::crypto::KemKeyPair::new();
+        let forged = PrekeyBundle::new(
+            *bundle.identity_key(),
+            *bundle.signed_prekey(),
+            *bundle.signature(),
+            Vec::new(),
+            Some(KemPrekey::new(
+                other.public().clone(),
+                *kem_prekey.signature(),
+            )),
+        );
+        assert!(forged.verify(&Crypto).is_err());
+    }
+
+    #[test]
+    fn decode_malformed_id() {
+        assert!(decode_id(&[]).is_err());
+        assert!(decode_id(&[0; 15]).is_err());
+        assert_eq!(Ulid::nil(), decode_id(&[0; 16]).unwrap());
+    }
+
+    /// Checks every event against the values the helpers above construct.
+    pub(crate) struct Expected;
+
+    impl EventHandler for Expected {
+        type Output = ();
+
+        fn on_handshake(&mut self, _: &Entity<'_>, event: &types::Handshake) -> Result<()> {
+            assert_eq!(*PUB_KEY, *event.pub_key());
+            assert_eq!(PROTOCOL_VERSION, *event.version());
+            assert_eq!(Capabilities::supported(), *event.capabilities());
+            if let Some(identity) = event.identity() {
+                assert_eq!(IDENTITY.public(), identity.key());
             }
-            types::Authentication::Response(resp) => {
-                assert_eq!(AUTH_STATUS, *resp.status());
+            Ok(())
+        }
+
+        fn on_registration_request(
+            &mut self,
+            _: &Entity<'_>,
+            event: &types::RegistrationRequest<'_>,
+        ) -> Result<()> {
+            assert_eq!(USERNAME, event.username());
+            assert_eq!(PASSWORD, event.password());
+            Ok(())
+        }
+
+        fn on_registration_response(
+            &mut self,
+            _: &Entity<'_>,
+            event: &types::RegistrationResponse,
+        ) -> Result<()> {
+            assert_eq!(REGI_STATUS, *event.status());
+            Ok(())
+        }
+
+        fn on_authentication_request(
+            &mut self,
+            _: &Entity<'_>,
+            event: &types::AuthenticationRequest<'_>,
+        ) -> Result<()> {
+            assert_eq!(USERNAME, event.username());
+            assert_eq!(PASSWORD, event.password());
+            Ok(())
+        }
+
+        fn on_authentication_response(
+            &mut self,
+            _: &Entity<'_>,
+            event: &types::AuthenticationResponse,
+        ) -> Result<()> {
+            assert_eq!(AUTH_STATUS, *event.status());
+            Ok(())
+        }
+
+        fn on_message(&mut self, _: &Entity<'_>, event: &types::Message<'_>) -> Result<()> {
+            assert_eq!(SENDER, event.sender());
+            assert_eq!(TEXT, event.text());
+            Ok(())
+        }
+
+        fn on_ping(&mut self, _: &Entity<'_>, event: &types::Ping) -> Result<()> {
+            assert_eq!(NONCE, *event.nonce());
+            Ok(())
+        }
+
+        fn on_pong(&mut self, _: &Entity<'_>, event: &types::Pong) -> Result<()> {
+            assert_eq!(NONCE, *event.nonce());
+            Ok(())
+        }
+
+        fn on_error(&mut self, _: &Entity<'_>, event: &types::ErrorEvent<'_>) -> Result<()> {
+            assert_eq!(ERROR_CODE, *event.code());
+            assert_eq!(TEXT, event.text());
+            Ok(())
+        }
+
+        fn on_prekey_upload(&mut self, _: &Entity<'_>, event: &types::PrekeyBundle) -> Result<()> {
+            assert_eq!(IDENTITY.public(), event.identity_key());
+            assert_eq!(SIGNED_PREKEY.public(), event.signed_prekey());
+            assert_eq!(ONE_TIME_PREKEYS.as_slice(), event.one_time_prekeys());
+            event.verify(&Crypto)
+        }
+
+        fn on_prekey_request(
+            &mut self,
+            _: &Entity<'_>,
+            event: &types::PrekeyRequest<'_>,
+        ) -> Result<()> {
+            assert_eq!(USERNAME, event.username());
+            Ok(())
+        }
+
+        fn on_prekey_response(
+            &mut self,
+            _: &Entity<'_>,
+            event: &types::PrekeyResponse<'_>,
+        ) -> Result<()> {
+            assert_eq!(USERNAME, event.username());
+            match event.bundle() {
+                Some(bundle) => bundle.verify(&Crypto),
+                None => Ok(()),
             }
         }
-    }
 
-    fn handle_message(kind: &types::Message<'_>) {
-        assert_eq!(SENDER, kind.sender());
-        assert_eq!(TEXT, kind.text());
+        fn on_session_init(
+            &mut self,
+            _: &Entity<'_>,
+            event: &types::SessionInit<'_>,
+        ) -> Result<()> {
+            assert_eq!(SENDER, event.sender());
+            assert_eq!(USERNAME, event.recipient());
+            assert_eq!(IDENTITY.public(), event.identity_key());
+            assert_eq!(*PUB_KEY, *event.ephemeral_key());
+            assert_eq!(SIGNED_PREKEY.public(), event.signed_prekey());
+            Ok(())
+        }
+
+        fn on_key_distribution(
+            &mut self,
+            _: &Entity<'_>,
+            event: &types::KeyDistribution<'_>,
+        ) -> Result<()> {
+            assert_eq!(SENDER, event.sender());
+            assert_eq!(USERNAME, event.recipient());
+            assert_eq!(TEXT, event.ciphertext());
+            Ok(())
+        }
+
+        fn on_presence(&mut self, _: &Entity<'_>, event: &types::Presence<'_>) -> Result<()> {
+            assert_eq!(USERNAME, event.username());
+            Ok(())
+        }
     }
 }
@@ -7,7 +7,7 @@ mod _protobuf {
 }
 
 #[non_exhaustive]
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Protobuf;
 
 impl EventSchema for Protobuf {}
@@ -21,10 +21,18 @@ impl Serializable for Protobuf {
             EventKind::Registration(kind) => serialize::registration(kind),
             EventKind::Authentication(kind) => serialize::authentication(kind),
             EventKind::Message(kind) => serialize::message(kind),
+            EventKind::Error(kind) => serialize::error(kind),
+            EventKind::Ping(kind) => serialize::ping(kind),
+            EventKind::Pong(kind) => serialize::pong(kind),
+            EventKind::Prekeys(kind) => serialize::prekeys(kind),
+            EventKind::SessionInit(kind) => serialize::session_init(kind),
+            EventKind::KeyDistribution(kind) => serialize::key_distribution(kind),
+            EventKind::Presence(kind) => serialize::presence(kind),
         };
 
         let entity = _protobuf::Entity {
-            timestamp: super::timestamp(),
+            id: entity.id().to_bytes().to_vec(),
+            timestamp: *entity.timestamp(),
             kind: Some(kind),
         };
 
@@ -40,6 +48,7 @@ impl Serializable for Protobuf {
         use std::io::Cursor;
 
         let decoded = _protobuf::Entity::decode(&mut Cursor::new(bytes)).map_err(Error::decode)?;
+        let id = super::decode_id(&decoded.id)?;
         let timestamp = decoded.timestamp;
         let kind = decoded
             .kind
@@ -50,20 +59,45 @@ impl Serializable for Protobuf {
             Kind::Registration(kind) => deserialize::registration(kind)?,
             Kind::Authentication(kind) => deserialize::authentication(kind)?,
             Kind::Message(kind) => deserialize::message(kind),
+            Kind::Error(kind) => deserialize::error(kind)?,
+            Kind::Ping(kind) => deserialize::ping(kind),
+            Kind::Pong(kind) => deserialize::pong(kind),
+            Kind::Prekeys(kind) => deserialize::prekeys(kind)?,
+            Kind::SessionInit(kind) => deserialize::session_init(kind)?,
+            Kind::KeyDistribution(kind) => deserialize::key_distribution(kind),
+            Kind::Presence(kind) => deserialize::presence(kind),
         };
 
-        let entity = types::Entity::new(timestamp, kind.into());
+        let entity = types::Entity::new(id, timestamp, kind.into());
         Ok(entity)
     }
 }
 
 mod serialize {
-    use super::{Encodable, _protobuf, types};
+    use super::{_protobuf, types, Encodable};
     use _protobuf::entity::Kind;
 
     pub(crate) fn handshake(kind: &types::Handshake) -> Kind {
         let a = _protobuf::Handshake {
             pub_key: kind.pub_key().encode(),
+            version: *kind.version(),
+            capabilities: kind.capabilities().bits(),
+            identity_key: kind
+                .identity()
+                .map(|identity| identity.key().encode())
+                .unwrap_or_default(),
+            signature: kind
+                .identity()
+                .map(|identity| identity.signature().encode())
+                .unwrap_or_default(),
+            kem_offer: match kind.kem() {
+                Some(types::KemShare::Offer(key)) => key.encode(),
+                _ => String::new(),
+            },
+            kem_answer: match kind.kem() {
+                Some(types::KemShare::Answer(ciphertext)) => ciphertext.encode(),
+                _ => String::new(),
+            },
         };
         Kind::Handshake(a)
     }
@@ -115,14 +149,124 @@ mod serialize {
         };
         Kind::Message(a)
     }
+
+    pub(crate) fn error(kind: &types::ErrorEvent<'_>) -> Kind {
+        let a = _protobuf::Error {
+            code: *kind.code() as i32,
+            text: kind.text().to_owned(),
+        };
+        Kind::Error(a)
+    }
+
+    pub(crate) fn ping(kind: &types::Ping) -> Kind {
+        Kind::Ping(_protobuf::Ping {
+            nonce: *kind.nonce(),
+        })
+    }
+
+    pub(crate) fn pong(kind: &types::Pong) -> Kind {
+        Kind::Pong(_protobuf::Pong {
+            nonce: *kind.nonce(),
+        })
+    }
+
+    pub(crate) fn prekeys(kind: &types::Prekeys<'_>) -> Kind {
+        let kind = match kind {
+            types::Prekeys::Upload(inner) => _protobuf::prekeys::Kind::Upload(bundle(inner)),
+            types::Prekeys::Request(inner) => {
+                let req = _protobuf::prekeys::Request {
+                    username: inner.username().to_owned(),
+                };
+                _protobuf::prekeys::Kind::Request(req)
+            }
+            types::Prekeys::Response(inner) => {
+                let resp = _protobuf::prekeys::Response {
+                    username: inner.username().to_owned(),
+                    bundle: inner.bundle().map(bundle),
+                };
+                _protobuf::prekeys::Kind::Response(resp)
+            }
+        };
+        let a = _protobuf::Prekeys { kind: Some(kind) };
+        Kind::Prekeys(a)
+    }
+
+    fn bundle(bundle: &types::PrekeyBundle) -> _protobuf::PrekeyBundle {
+        _protobuf::PrekeyBundle {
+            identity_key: bundle.identity_key().encode(),
+            signed_prekey: bundle.signed_prekey().encode(),
+            signature: bundle.signature().encode(),
+            one_time_prekeys: bundle
+                .one_time_prekeys()
+                .iter()
+                .map(Encodable::encode)
+                .collect(),
+            kem_prekey: bundle
+                .kem_prekey()
+                .map(|prekey| prekey.key().encode())
+                .unwrap_or_default(),
+            kem_signature: bundle
+                .kem_prekey()
+                .map(|prekey| prekey.signature().encode())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub(crate) fn session_init(kind: &types::SessionInit<'_>) -> Kind {
+        let a = _protobuf::SessionInit {
+            sender: kind.sender().to_owned(),
+            recipient: kind.recipient().to_owned(),
+            identity_key: kind.identity_key().encode(),
+            ephemeral_key: kind.ephemeral_key().encode(),
+            signed_prekey: kind.signed_prekey().encode(),
+            one_time_prekey: kind
+                .one_time_prekey()
+                .map(Encodable::encode)
+                .unwrap_or_default(),
+            kem_ciphertext: kind
+                .kem_ciphertext()
+                .map(Encodable::encode)
+                .unwrap_or_default(),
+        };
+        Kind::SessionInit(a)
+    }
+
+    pub(crate) fn key_distribution(kind: &types::KeyDistribution<'_>) -> Kind {
+        let a = _protobuf::KeyDistribution {
+            sender: kind.sender().to_owned(),
+            recipient: kind.recipient().to_owned(),
+            ciphertext: kind.ciphertext().to_owned(),
+        };
+        Kind::KeyDistribution(a)
+    }
+
+    pub(crate) fn presence(kind: &types::Presence<'_>) -> Kind {
+        let a = _protobuf::Presence {
+            username: kind.username().to_owned(),
+            online: *kind.online(),
+        };
+        Kind::Presence(a)
+    }
 }
 
 mod deserialize {
-    use super::{Encodable, Error, EventKind, PublicKey, Result, Then, _protobuf, types};
+    use super::{_protobuf, types, Encodable, Error, EventKind, PublicKey, Result, Then};
+    use crate::protocol::Capabilities;
 
     pub(crate) fn handshake<'a>(kind: _protobuf::Handshake) -> Result<EventKind<'a>> {
         let pub_key = kind.pub_key.then(PublicKey::try_decode)?;
-        Ok(EventKind::Handshake(types::Handshake::new(pub_key)))
+        let capabilities = Capabilities::from_bits(kind.capabilities);
+        let identity =
+            crate::event::decode_identity(kind.identity_key.as_bytes(), kind.signature.as_bytes())?;
+        let kem =
+            crate::event::decode_kem_share(kind.kem_offer.as_bytes(), kind.kem_answer.as_bytes())?;
+        Ok(EventKind::Handshake(types::Handshake::new(
+            pub_key,
+            kind.version,
+            capabilities,
+            kem,
+            identity,
+        )))
     }
 
     pub(crate) fn registration<'a>(kind: _protobuf::Registration) -> Result<EventKind<'a>> {
@@ -172,6 +316,79 @@ mod deserialize {
         let text = kind.text;
         EventKind::Message(types::Message::new(sender.into(), text.into()))
     }
+
+    pub(crate) fn error<'a>(kind: _protobuf::Error) -> Result<EventKind<'a>> {
+        let code = types::ErrorCode::try_from(kind.code)?;
+        let error = types::ErrorEvent::new(code, kind.text.into());
+        Ok(EventKind::Error(error))
+    }
+
+    pub(crate) fn ping<'a>(kind: _protobuf::Ping) -> EventKind<'a> {
+        EventKind::Ping(types::Ping::new(kind.nonce))
+    }
+
+    pub(crate) fn pong<'a>(kind: _protobuf::Pong) -> EventKind<'a> {
+        EventKind::Pong(types::Pong::new(kind.nonce))
+    }
+
+    pub(crate) fn prekeys<'a>(kind: _protobuf::Prekeys) -> Result<EventKind<'a>> {
+        let kind = kind
+            .kind
+            .ok_or_else(|| Error::decode("Bad event structure"))?;
+
+        let a = match kind {
+            _protobuf::prekeys::Kind::Upload(upload) => types::Prekeys::Upload(bundle(upload)?),
+            _protobuf::prekeys::Kind::Request(req) => {
+                types::Prekeys::Request(types::PrekeyRequest::new(req.username.into()))
+            }
+            _protobuf::prekeys::Kind::Response(resp) => {
+                let bundle = resp.bundle.map(bundle).transpose()?;
+                let response = types::PrekeyResponse::new(resp.username.into(), bundle);
+                types::Prekeys::Response(response)
+            }
+        };
+
+        Ok(EventKind::Prekeys(a))
+    }
+
+    fn bundle(bundle: _protobuf::PrekeyBundle) -> Result<types::PrekeyBundle> {
+        crate::event::decode_bundle(
+            bundle.identity_key.as_bytes(),
+            bundle.signed_prekey.as_bytes(),
+            bundle.signature.as_bytes(),
+            bundle.one_time_prekeys.iter().map(String::as_bytes),
+            crate::event::decode_kem_prekey(
+                bundle.kem_prekey.as_bytes(),
+                bundle.kem_signature.as_bytes(),
+            )?,
+        )
+    }
+
+    pub(crate) fn session_init<'a>(kind: _protobuf::SessionInit) -> Result<EventKind<'a>> {
+        let one_time_prekey = crate::event::decode_optional(kind.one_time_prekey.as_bytes())?;
+        let kem_ciphertext = crate::event::decode_optional(kind.kem_ciphertext.as_bytes())?;
+        Ok(EventKind::SessionInit(types::SessionInit::new(
+            kind.sender.into(),
+            kind.recipient.into(),
+            kind.identity_key.then(PublicKey::try_decode)?,
+            kind.ephemeral_key.then(PublicKey::try_decode)?,
+            kind.signed_prekey.then(PublicKey::try_decode)?,
+            one_time_prekey,
+            kem_ciphertext,
+        )))
+    }
+
+    pub(crate) fn key_distribution<'a>(kind: _protobuf::KeyDistribution) -> EventKind<'a> {
+        EventKind::KeyDistribution(types::KeyDistribution::new(
+            kind.sender.into(),
+            kind.recipient.into(),
+            kind.ciphertext.into(),
+        ))
+    }
+
+    pub(crate) fn presence<'a>(kind: _protobuf::Presence) -> EventKind<'a> {
+        EventKind::Presence(types::Presence::new(kind.username.into(), kind.online))
+    }
 }
 
 impl Constructable for Protobuf {}
@@ -208,4 +425,29 @@ mod tests {
     fn message() {
         crate::event::tests::message(Protobuf);
     }
+
+    #[test]
+    fn error() {
+        crate::event::tests::error(Protobuf);
+    }
+
+    #[test]
+    fn keepalive() {
+        crate::event::tests::keepalive(Protobuf);
+    }
+
+    #[test]
+    fn prekeys() {
+        crate::event::tests::prekeys(Protobuf);
+    }
+
+    #[test]
+    fn session_init() {
+        crate::event::tests::session_init(Protobuf);
+    }
+
+    #[test]
+    fn group() {
+        crate::event::tests::group(Protobuf);
+    }
 }
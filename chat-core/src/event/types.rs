@@ -2,85 +2,273 @@
 
 use std::borrow::Cow;
 
-use crate::prelude::*;
+use crate::{
+    crypto::{EncapsulationKey, KemCiphertext},
+    prelude::*,
+    protocol::Capabilities,
+};
 use chat_macros::{Get, New};
+use ulid::Ulid;
 
-#[derive(New, Get, Debug)]
+#[derive(New, Get, Debug, Clone)]
 pub struct Entity<'a> {
+    /// Globally unique, sorts by creation time.
+    id: Ulid,
+    /// Milliseconds since the Unix epoch.
     timestamp: i64,
     kind: Box<EventKind<'a>>,
 }
 
 impl<'a> Entity<'a> {
-    pub fn expect_handshake(&'a self) -> Result<&Handshake> {
+    /// Detaches the entity from the buffer it was deserialized from, so it
+    /// can be stored or sent to another task.
+    pub fn into_owned(self) -> Entity<'static> {
+        Entity {
+            id: self.id,
+            timestamp: self.timestamp,
+            kind: Box::new(self.kind.into_owned()),
+        }
+    }
+
+    /// Replaces the creation time, e.g. to replay history or to get
+    /// deterministic output.
+    #[must_use]
+    pub const fn with_timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn expect_handshake(&'a self) -> Result<&'a Handshake> {
         match *self.kind {
             EventKind::Handshake(ref inner) => Ok(inner),
             _ => Err(Error::decode("Bad event structure")),
         }
     }
 
-    pub fn expect_registration_request(&'a self) -> Result<&RegistrationRequest<'a>> {
+    pub fn expect_registration_request(&'a self) -> Result<&'a RegistrationRequest<'a>> {
         match *self.kind {
             EventKind::Registration(Registration::Request(ref inner)) => Ok(inner),
             _ => Err(Error::decode("Bad event structure")),
         }
     }
-    pub fn expect_registration_response(&'a self) -> Result<&RegistrationResponse> {
+    pub fn expect_registration_response(&'a self) -> Result<&'a RegistrationResponse> {
         match *self.kind {
             EventKind::Registration(Registration::Response(ref inner)) => Ok(inner),
             _ => Err(Error::decode("Bad event structure")),
         }
     }
 
-    pub fn expect_authentication_request(&'a self) -> Result<&AuthenticationRequest<'a>> {
+    pub fn expect_authentication_request(&'a self) -> Result<&'a AuthenticationRequest<'a>> {
         match *self.kind {
             EventKind::Authentication(Authentication::Request(ref inner)) => Ok(inner),
             _ => Err(Error::decode("Bad event structure")),
         }
     }
-    pub fn expect_authentication_response(&'a self) -> Result<&AuthenticationResponse> {
+    pub fn expect_authentication_response(&'a self) -> Result<&'a AuthenticationResponse> {
         match *self.kind {
             EventKind::Authentication(Authentication::Response(ref inner)) => Ok(inner),
             _ => Err(Error::decode("Bad event structure")),
         }
     }
 
-    pub fn expect_message(&'a self) -> Result<&Message<'_>> {
+    pub fn expect_message(&'a self) -> Result<&'a Message<'a>> {
         match *self.kind {
             EventKind::Message(ref inner) => Ok(inner),
             _ => Err(Error::decode("Bad event structure")),
         }
     }
+
+    pub fn expect_pong(&'a self) -> Result<&'a Pong> {
+        match *self.kind {
+            EventKind::Pong(ref inner) => Ok(inner),
+            _ => Err(Error::decode("Bad event structure")),
+        }
+    }
+
+    pub fn expect_error(&'a self) -> Result<&'a ErrorEvent<'a>> {
+        match *self.kind {
+            EventKind::Error(ref inner) => Ok(inner),
+            _ => Err(Error::decode("Bad event structure")),
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum EventKind<'a> {
     Handshake(Handshake),
     Registration(Registration<'a>),
     Authentication(Authentication<'a>),
     Message(Message<'a>),
+    Error(ErrorEvent<'a>),
+    Ping(Ping),
+    Pong(Pong),
+    Prekeys(Prekeys<'a>),
+    SessionInit(SessionInit<'a>),
+    KeyDistribution(KeyDistribution<'a>),
+    Presence(Presence<'a>),
 }
 
-#[derive(New, Get, Debug)]
+impl EventKind<'_> {
+    /// Human readable name of the event, e.g. for logs and errors.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Handshake(_) => "handshake",
+            Self::Registration(Registration::Request(_)) => "registration request",
+            Self::Registration(Registration::Response(_)) => "registration response",
+            Self::Authentication(Authentication::Request(_)) => "authentication request",
+            Self::Authentication(Authentication::Response(_)) => "authentication response",
+            Self::Message(_) => "message",
+            Self::Error(_) => "error",
+            Self::Ping(_) => "ping",
+            Self::Pong(_) => "pong",
+            Self::Prekeys(Prekeys::Upload(_)) => "prekey upload",
+            Self::Prekeys(Prekeys::Request(_)) => "prekey request",
+            Self::Prekeys(Prekeys::Response(_)) => "prekey response",
+            Self::SessionInit(_) => "session init",
+            Self::KeyDistribution(_) => "key distribution",
+            Self::Presence(_) => "presence",
+        }
+    }
+
+    /// Stable number of the event kind, which labels every frame carrying
+    /// such an event.
+    pub const fn tag(&self) -> u8 {
+        match self {
+            Self::Handshake(_) => 0,
+            Self::Registration(Registration::Request(_)) => 1,
+            Self::Registration(Registration::Response(_)) => 2,
+            Self::Authentication(Authentication::Request(_)) => 3,
+            Self::Authentication(Authentication::Response(_)) => 4,
+            Self::Message(_) => 5,
+            Self::Error(_) => 6,
+            Self::Ping(_) => 7,
+            Self::Pong(_) => 8,
+            Self::Prekeys(Prekeys::Upload(_)) => 9,
+            Self::Prekeys(Prekeys::Request(_)) => 10,
+            Self::Prekeys(Prekeys::Response(_)) => 11,
+            Self::SessionInit(_) => 12,
+            Self::KeyDistribution(_) => 13,
+            Self::Presence(_) => 14,
+        }
+    }
+
+    pub fn into_owned(self) -> EventKind<'static> {
+        match self {
+            Self::Handshake(inner) => EventKind::Handshake(inner),
+            Self::Registration(inner) => EventKind::Registration(inner.into_owned()),
+            Self::Authentication(inner) => EventKind::Authentication(inner.into_owned()),
+            Self::Message(inner) => EventKind::Message(inner.into_owned()),
+            Self::Error(inner) => EventKind::Error(inner.into_owned()),
+            Self::Ping(inner) => EventKind::Ping(inner),
+            Self::Pong(inner) => EventKind::Pong(inner),
+            Self::Prekeys(inner) => EventKind::Prekeys(inner.into_owned()),
+            Self::SessionInit(inner) => EventKind::SessionInit(inner.into_owned()),
+            Self::KeyDistribution(inner) => EventKind::KeyDistribution(inner.into_owned()),
+            Self::Presence(inner) => EventKind::Presence(inner.into_owned()),
+        }
+    }
+}
+
+#[derive(New, Get, Debug, Clone)]
 pub struct Handshake {
     pub_key: PublicKey,
+    version: u32,
+    capabilities: Capabilities,
+    /// ML-KEM half of a hybrid handshake, see [`Capabilities::HYBRID`].
+    kem: Option<KemShare>,
+    /// Servers prove who they are, clients leave it out.
+    identity: Option<IdentityProof>,
+}
+
+impl Handshake {
+    /// What an identity key signs: everything but the proof itself.
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(14 + 32 + 4 + 8);
+        bytes.extend_from_slice(b"CORE_HANDSHAKE");
+        bytes.extend_from_slice(self.pub_key.as_ref());
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.capabilities.bits().to_be_bytes());
+        match &self.kem {
+            None => bytes.push(0),
+            Some(KemShare::Offer(key)) => bytes.extend([&[1][..], key.as_ref()].concat()),
+            Some(KemShare::Answer(ciphertext)) => {
+                bytes.extend([&[2][..], ciphertext.as_ref()].concat());
+            }
+        }
+        bytes
+    }
+
+    /// Attaches a proof made with the long-term `identity` key pair.
+    #[must_use]
+    pub fn signed<C: CryptoSchema>(mut self, crypto: &C, identity: &KeyPair) -> Self {
+        let signature = crypto.sign(identity.secret(), &self.signed_bytes());
+        self.identity = Some(IdentityProof::new(*identity.public(), signature));
+        self
+    }
+
+    /// Checks the proof, if there is one, and returns the identity key it was
+    /// made with.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the signature doesn't match.
+    pub fn verify<C: CryptoSchema>(&self, crypto: &C) -> Result<Option<&PublicKey>> {
+        let Some(proof) = &self.identity else {
+            return Ok(None);
+        };
+        crypto.verify(proof.key(), &self.signed_bytes(), proof.signature())?;
+        Ok(Some(proof.key()))
+    }
+}
+
+/// Whoever sends the first payload of a hybrid handshake offers a key, the
+/// other side answers with a secret encapsulated to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KemShare {
+    Offer(EncapsulationKey),
+    Answer(KemCiphertext),
+}
+
+/// Signature of a [`Handshake`] by a long-term identity key.
+#[derive(New, Get, Debug, Clone, PartialEq, Eq)]
+pub struct IdentityProof {
+    key: PublicKey,
+    signature: Signature,
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 // Registration
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Registration<'a> {
     Request(RegistrationRequest<'a>),
     Response(RegistrationResponse),
 }
 
-#[derive(New, Get, Debug)]
+impl Registration<'_> {
+    pub fn into_owned(self) -> Registration<'static> {
+        match self {
+            Self::Request(inner) => Registration::Request(inner.into_owned()),
+            Self::Response(inner) => Registration::Response(inner),
+        }
+    }
+}
+
+#[derive(New, Get, Debug, Clone)]
 pub struct RegistrationRequest<'a> {
     username: Cow<'a, str>,
     password: Cow<'a, str>,
 }
 
-#[derive(New, Get, Debug)]
+impl RegistrationRequest<'_> {
+    pub fn into_owned(self) -> RegistrationRequest<'static> {
+        RegistrationRequest {
+            username: Cow::Owned(self.username.into_owned()),
+            password: Cow::Owned(self.password.into_owned()),
+        }
+    }
+}
+
+#[derive(New, Get, Debug, Clone)]
 pub struct RegistrationResponse {
     status: RegistrationStatus,
 }
@@ -113,18 +301,36 @@ impl TryFrom<i32> for RegistrationStatus {
 
 ///////////////////////////////////////////////////////////////////////////////
 // Authentication
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Authentication<'a> {
     Request(AuthenticationRequest<'a>),
     Response(AuthenticationResponse),
 }
 
-#[derive(New, Get, Debug)]
+impl Authentication<'_> {
+    pub fn into_owned(self) -> Authentication<'static> {
+        match self {
+            Self::Request(inner) => Authentication::Request(inner.into_owned()),
+            Self::Response(inner) => Authentication::Response(inner),
+        }
+    }
+}
+
+#[derive(New, Get, Debug, Clone)]
 pub struct AuthenticationRequest<'a> {
     username: Cow<'a, str>,
     password: Cow<'a, str>,
 }
 
+impl AuthenticationRequest<'_> {
+    pub fn into_owned(self) -> AuthenticationRequest<'static> {
+        AuthenticationRequest {
+            username: Cow::Owned(self.username.into_owned()),
+            password: Cow::Owned(self.password.into_owned()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AuthenticationStatus {
     Success,
@@ -155,15 +361,290 @@ impl TryFrom<i32> for AuthenticationStatus {
     }
 }
 
-#[derive(New, Get, Debug)]
+#[derive(New, Get, Debug, Clone)]
 pub struct AuthenticationResponse {
     status: AuthenticationStatus,
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 // Message
-#[derive(New, Get, Debug)]
+#[derive(New, Get, Debug, Clone)]
 pub struct Message<'a> {
     sender: Cow<'a, str>,
     text: Cow<'a, str>,
 }
+
+impl Message<'_> {
+    pub fn into_owned(self) -> Message<'static> {
+        Message {
+            sender: Cow::Owned(self.sender.into_owned()),
+            text: Cow::Owned(self.text.into_owned()),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Error
+/// Tells the peer why the connection is about to be closed.
+#[derive(New, Get, Debug, Clone)]
+pub struct ErrorEvent<'a> {
+    code: ErrorCode,
+    text: Cow<'a, str>,
+}
+
+impl ErrorEvent<'_> {
+    pub fn into_owned(self) -> ErrorEvent<'static> {
+        ErrorEvent {
+            code: self.code,
+            text: Cow::Owned(self.text.into_owned()),
+        }
+    }
+}
+
+impl From<&ErrorEvent<'_>> for Error {
+    fn from(value: &ErrorEvent<'_>) -> Self {
+        Self::Remote(value.code, value.text.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorCode {
+    Internal,
+    Decode,
+    UnexpectedEvent,
+    Incompatible,
+    Crypto,
+    Timeout,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Internal => write!(f, "Internal error"),
+            Self::Decode => write!(f, "Decoding failed"),
+            Self::UnexpectedEvent => write!(f, "Unexpected event"),
+            Self::Incompatible => write!(f, "Incompatible peer"),
+            Self::Crypto => write!(f, "Cryptographic function failed"),
+            Self::Timeout => write!(f, "Timeout"),
+        }
+    }
+}
+
+impl TryFrom<i32> for ErrorCode {
+    type Error = crate::error::Error;
+
+    fn try_from(value: i32) -> std::result::Result<Self, Self::Error> {
+        match value {
+            x if x == Self::Internal as i32 => Ok(Self::Internal),
+            x if x == Self::Decode as i32 => Ok(Self::Decode),
+            x if x == Self::UnexpectedEvent as i32 => Ok(Self::UnexpectedEvent),
+            x if x == Self::Incompatible as i32 => Ok(Self::Incompatible),
+            x if x == Self::Crypto as i32 => Ok(Self::Crypto),
+            x if x == Self::Timeout as i32 => Ok(Self::Timeout),
+            _ => Err(crate::error::Error::decode("Bad event structure")),
+        }
+    }
+}
+
+impl From<&Error> for ErrorCode {
+    fn from(value: &Error) -> Self {
+        match value {
+            Error::Decode(_) => Self::Decode,
+            Error::Unexpected(_) => Self::UnexpectedEvent,
+            Error::Incompatible(_) => Self::Incompatible,
+            Error::Crypto(_) | Error::Replayed(_) | Error::OutOfWindow(_) => Self::Crypto,
+            Error::Timeout => Self::Timeout,
+            Error::Remote(code, _) => *code,
+            Error::Generic(_) | Error::IO(_) | Error::Rejected(_) | Error::Shutdown => {
+                Self::Internal
+            }
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Keepalive
+/// Asks the peer to prove that it's still alive.
+#[derive(New, Get, Debug, Clone, Copy)]
+pub struct Ping {
+    nonce: u64,
+}
+
+/// Answers a [`Ping`] with the same nonce.
+#[derive(New, Get, Debug, Clone, Copy)]
+pub struct Pong {
+    nonce: u64,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Prekeys
+/// Keys which let clients start a session with each other while one of them
+/// is offline, see [`crate::crypto::x3dh`].
+#[derive(Debug, Clone)]
+pub enum Prekeys<'a> {
+    /// Publishes the bundle of the sender, replacing its previous one.
+    Upload(PrekeyBundle),
+    Request(PrekeyRequest<'a>),
+    Response(PrekeyResponse<'a>),
+}
+
+impl Prekeys<'_> {
+    pub fn into_owned(self) -> Prekeys<'static> {
+        match self {
+            Self::Upload(inner) => Prekeys::Upload(inner),
+            Self::Request(inner) => Prekeys::Request(inner.into_owned()),
+            Self::Response(inner) => Prekeys::Response(inner.into_owned()),
+        }
+    }
+}
+
+#[derive(New, Get, Debug, Clone, PartialEq, Eq)]
+pub struct PrekeyBundle {
+    identity_key: PublicKey,
+    signed_prekey: PublicKey,
+    /// Signature of `signed_prekey` by `identity_key`.
+    signature: Signature,
+    /// An upload carries a batch, a response at most one, which the server
+    /// never hands out again.
+    one_time_prekeys: Vec<PublicKey>,
+    /// Mixed into sessions along with the other prekeys, unless the owner
+    /// doesn't take part in hybrid sessions.
+    kem_prekey: Option<KemPrekey>,
+}
+
+impl PrekeyBundle {
+    /// Bundle of `signed_prekey`, vouched for by the `identity` key pair.
+    pub fn signed<C: CryptoSchema>(
+        crypto: &C,
+        identity: &KeyPair,
+        signed_prekey: PublicKey,
+        one_time_prekeys: Vec<PublicKey>,
+        kem_prekey: Option<EncapsulationKey>,
+    ) -> Self {
+        let signature = crate::crypto::x3dh::sign_prekey(crypto, identity, &signed_prekey);
+        let kem_prekey = kem_prekey.map(|key| {
+            let signature = crate::crypto::x3dh::sign_kem_prekey(crypto, identity, &key);
+            KemPrekey::new(key, signature)
+        });
+        Self::new(
+            *identity.public(),
+            signed_prekey,
+            signature,
+            one_time_prekeys,
+            kem_prekey,
+        )
+    }
+
+    /// # Errors
+    ///
+    /// This function will return an error if a signature doesn't match.
+    pub fn verify<C: CryptoSchema>(&self, crypto: &C) -> Result<()> {
+        let signed = crate::crypto::x3dh::prekey_signed_bytes(&self.signed_prekey);
+        crypto.verify(&self.identity_key, &signed, &self.signature)?;
+        if let Some(kem_prekey) = &self.kem_prekey {
+            let signed = crate::crypto::x3dh::kem_prekey_signed_bytes(kem_prekey.key());
+            crypto.verify(&self.identity_key, &signed, kem_prekey.signature())?;
+        }
+        Ok(())
+    }
+}
+
+/// ML-KEM prekey of a [`PrekeyBundle`], signed by the same identity key.
+#[derive(New, Get, Debug, Clone, PartialEq, Eq)]
+pub struct KemPrekey {
+    key: EncapsulationKey,
+    signature: Signature,
+}
+
+#[derive(New, Get, Debug, Clone)]
+pub struct PrekeyRequest<'a> {
+    username: Cow<'a, str>,
+}
+
+impl PrekeyRequest<'_> {
+    pub fn into_owned(self) -> PrekeyRequest<'static> {
+        PrekeyRequest {
+            username: Cow::Owned(self.username.into_owned()),
+        }
+    }
+}
+
+#[derive(New, Get, Debug, Clone)]
+pub struct PrekeyResponse<'a> {
+    username: Cow<'a, str>,
+    /// Unset unless the user has published a bundle.
+    bundle: Option<PrekeyBundle>,
+}
+
+impl PrekeyResponse<'_> {
+    pub fn into_owned(self) -> PrekeyResponse<'static> {
+        PrekeyResponse {
+            username: Cow::Owned(self.username.into_owned()),
+            bundle: self.bundle,
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Session
+/// Keys `recipient` needs to join a session `sender` started from its
+/// [`PrekeyBundle`]. The server keeps it until the recipient is online.
+#[derive(New, Get, Debug, Clone)]
+pub struct SessionInit<'a> {
+    sender: Cow<'a, str>,
+    recipient: Cow<'a, str>,
+    identity_key: PublicKey,
+    ephemeral_key: PublicKey,
+    /// Prekeys of the recipient's bundle the session was started from.
+    signed_prekey: PublicKey,
+    one_time_prekey: Option<PublicKey>,
+    /// Secret encapsulated to the KEM prekey of the bundle, if it had one.
+    kem_ciphertext: Option<KemCiphertext>,
+}
+
+impl SessionInit<'_> {
+    pub fn into_owned(self) -> SessionInit<'static> {
+        SessionInit {
+            sender: Cow::Owned(self.sender.into_owned()),
+            recipient: Cow::Owned(self.recipient.into_owned()),
+            ..self
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Group
+/// Sender key of `sender`, encrypted over its session with `recipient`, see
+/// [`crate::crypto::SenderKey`].
+#[derive(New, Get, Debug, Clone)]
+pub struct KeyDistribution<'a> {
+    sender: Cow<'a, str>,
+    recipient: Cow<'a, str>,
+    ciphertext: Cow<'a, str>,
+}
+
+impl KeyDistribution<'_> {
+    pub fn into_owned(self) -> KeyDistribution<'static> {
+        KeyDistribution {
+            sender: Cow::Owned(self.sender.into_owned()),
+            recipient: Cow::Owned(self.recipient.into_owned()),
+            ciphertext: Cow::Owned(self.ciphertext.into_owned()),
+        }
+    }
+}
+
+/// Sent by the server whenever a user logs in or disconnects.
+#[derive(New, Get, Debug, Clone)]
+pub struct Presence<'a> {
+    username: Cow<'a, str>,
+    online: bool,
+}
+
+impl Presence<'_> {
+    pub fn into_owned(self) -> Presence<'static> {
+        Presence {
+            username: Cow::Owned(self.username.into_owned()),
+            online: self.online,
+        }
+    }
+}
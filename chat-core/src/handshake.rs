@@ -0,0 +1,541 @@
+//! Noise handshake opening every connection
+//!
+//! Both sides authenticate with their long-term identity key, see
+//! [`KeyPair::new_signing`], whose X25519 form is their Noise static key. A
+//! client meeting a server for the first time runs `XX` and learns the server's
+//! identity on the way. A client which already pinned it runs `IK`, which is a
+//! round trip shorter and shows the client's identity to that server only.
+//!
+//! The payloads are [`Handshake`] events encoded with [`Capnp`], so that they
+//! can be read before a serialization backend is agreed on. Each one is signed
+//! by its sender and carries the protocol version and capabilities.
+//!
+//! If both sides support [`Capabilities::HYBRID`], whoever sends the first
+//! payload offers an ML-KEM key and the other side answers with a secret
+//! encapsulated to it. That secret is mixed into the transport keys along with
+//! the Noise split, so recorded traffic stays safe even once X25519 falls.
+
+use bytes::BytesMut;
+use futures::SinkExt;
+use snow::{params::NoiseParams, Builder, HandshakeState};
+
+use crate::{
+    crypto::{identity_to_dh, KemKeyPair, KeySchedule},
+    event::{DynSchema, Entity, Handshake, KemShare},
+    prelude::*,
+    protocol::{self, Capabilities, Negotiated},
+};
+
+/// First contact, the server's identity is learned during the handshake.
+const XX: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// The client knows the server's identity beforehand.
+const IK: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+const PROLOGUE: &[u8] = b"CORE_NOISE";
+/// Longest message Noise allows.
+const MAX_MESSAGE_LENGTH: usize = 65535;
+
+/// Prefixes the first message, so the server knows which pattern to answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Pattern {
+    Xx = 0,
+    Ik = 1,
+}
+
+impl From<snow::Error> for crate::error::Error {
+    fn from(value: snow::Error) -> Self {
+        Self::crypto(value)
+    }
+}
+
+/// Our half of the ML-KEM exchange.
+enum Hybrid {
+    /// Either side doesn't support it.
+    Off,
+    Offered(KemKeyPair),
+    Answered(SharedSecret),
+}
+
+impl Hybrid {
+    /// Taken by whoever sends the first payload.
+    fn offer(capabilities: Capabilities) -> Self {
+        if capabilities.contains(Capabilities::HYBRID) {
+            Self::Offered(KemKeyPair::new())
+        } else {
+            Self::Off
+        }
+    }
+
+    /// Taken by whoever sends the second payload, answers the offer in
+    /// `remote` if both sides support it.
+    fn answer<C: CryptoSchema>(
+        crypto: &C,
+        capabilities: Capabilities,
+        remote: &Handshake,
+    ) -> (Self, Option<KemShare>) {
+        let both = capabilities.intersection(*remote.capabilities());
+        match remote.kem() {
+            Some(KemShare::Offer(key)) if both.contains(Capabilities::HYBRID) => {
+                let (ciphertext, secret) = crypto.encapsulate(key);
+                (Self::Answered(secret), Some(KemShare::Answer(ciphertext)))
+            }
+            _ => (Self::Off, None),
+        }
+    }
+
+    /// What goes into our payload along with an offer.
+    fn share(&self) -> Option<KemShare> {
+        match self {
+            Self::Offered(pair) => Some(KemShare::Offer(pair.public().clone())),
+            Self::Off | Self::Answered(_) => None,
+        }
+    }
+
+    /// Secret to mix into the transport keys, unless the `negotiated`
+    /// capabilities leave out [`Capabilities::HYBRID`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if both sides support it but an
+    /// offer went unanswered.
+    fn secret<C: CryptoSchema>(
+        self,
+        crypto: &C,
+        negotiated: Capabilities,
+        remote: &Handshake,
+    ) -> Result<Option<SharedSecret>> {
+        if !negotiated.contains(Capabilities::HYBRID) {
+            return Ok(None);
+        }
+        match (self, remote.kem()) {
+            (Self::Offered(pair), Some(KemShare::Answer(ciphertext))) => {
+                Ok(Some(crypto.decapsulate(&pair, ciphertext)))
+            }
+            (Self::Answered(secret), _) => Ok(Some(secret)),
+            _ => Err(Error::crypto("The hybrid key exchange is incomplete")),
+        }
+    }
+}
+
+/// Client side of the handshake.
+///
+/// `server` is the identity pinned for the server, `XX` is run without one.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - Sending or recieving failed or timeouted
+/// - The server isn't the owner of `server`
+/// - The server's identity doesn't match its Noise static key
+/// - The server speaks an incompatible protocol version
+pub async fn initiate<F, C>(
+    stream: &mut F,
+    capabilities: Capabilities,
+    crypto: C,
+    identity: &KeyPair,
+    server: Option<&PublicKey>,
+) -> Result<Negotiated>
+where
+    F: Frames,
+    C: CryptoSchema,
+{
+    let static_key = identity.to_dh();
+
+    match server {
+        Some(server) => {
+            let server = identity_to_dh(server)?;
+            let mut noise = builder(IK)
+                .local_private_key(static_key.secret().as_ref())
+                .remote_public_key(server.as_ref())
+                .build_initiator()?;
+            let hybrid = Hybrid::offer(capabilities);
+            let local =
+                local_handshake(&static_key, capabilities, hybrid.share(), &crypto, identity);
+            // -> e, es, s, ss
+            let payload = Capnp.serialize(local.clone());
+            send(stream, &mut noise, Some(Pattern::Ik), &payload).await?;
+            // <- e, ee, se
+            let remote = payload_of(&recieve(stream, &mut noise).await?)?;
+            finish(noise, &local, &remote, hybrid, &crypto)
+        }
+        None => {
+            let mut noise = builder(XX)
+                .local_private_key(static_key.secret().as_ref())
+                .build_initiator()?;
+            // -> e
+            send(stream, &mut noise, Some(Pattern::Xx), &[]).await?;
+            // <- e, ee, s, es
+            let remote = payload_of(&recieve(stream, &mut noise).await?)?;
+            let (hybrid, kem) = Hybrid::answer(&crypto, capabilities, remote.expect_handshake()?);
+            let local = local_handshake(&static_key, capabilities, kem, &crypto, identity);
+            // -> s, se
+            send(stream, &mut noise, None, &Capnp.serialize(local.clone())).await?;
+            finish(noise, &local, &remote, hybrid, &crypto)
+        }
+    }
+}
+
+/// Server side of the handshake, answers whichever pattern the client picked.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - Sending or recieving failed or timeouted
+/// - The client picked `IK` with an identity this server doesn't own
+/// - The client's identity doesn't match its Noise static key
+/// - The client speaks an incompatible protocol version
+pub async fn respond<F, C>(
+    stream: &mut F,
+    capabilities: Capabilities,
+    crypto: C,
+    identity: &KeyPair,
+) -> Result<Negotiated>
+where
+    F: Frames,
+    C: CryptoSchema,
+{
+    let static_key = identity.to_dh();
+
+    let first = crate::recieve(stream).await?;
+    let (pattern, message) = first
+        .split_first()
+        .ok_or_else(|| Error::decode("Empty handshake message"))?;
+    let pattern = match *pattern {
+        p if p == Pattern::Xx as u8 => XX,
+        p if p == Pattern::Ik as u8 => IK,
+        p => {
+            return Err(Error::incompatible(format!(
+                "unknown handshake pattern {p}"
+            )))
+        }
+    };
+    let mut noise = builder(pattern)
+        .local_private_key(static_key.secret().as_ref())
+        .build_responder()?;
+    let mut buf = vec![0; MAX_MESSAGE_LENGTH];
+    let len = noise.read_message(message, &mut buf)?;
+
+    let (local, remote, hybrid) = if pattern == IK {
+        // <- e, es, s, ss  -> e, ee, se
+        let remote = payload_of(&buf[..len])?;
+        let (hybrid, kem) = Hybrid::answer(&crypto, capabilities, remote.expect_handshake()?);
+        let local = local_handshake(&static_key, capabilities, kem, &crypto, identity);
+        send(stream, &mut noise, None, &Capnp.serialize(local.clone())).await?;
+        (local, remote, hybrid)
+    } else {
+        // <- e  -> e, ee, s, es  <- s, se
+        let hybrid = Hybrid::offer(capabilities);
+        let local = local_handshake(&static_key, capabilities, hybrid.share(), &crypto, identity);
+        send(stream, &mut noise, None, &Capnp.serialize(local.clone())).await?;
+        let remote = payload_of(&recieve(stream, &mut noise).await?)?;
+        (local, remote, hybrid)
+    };
+    finish(noise, &local, &remote, hybrid, &crypto)
+}
+
+fn builder(pattern: &'static str) -> Builder<'static> {
+    let params: NoiseParams = pattern.parse().expect("pattern is valid");
+    Builder::new(params).prologue(PROLOGUE)
+}
+
+/// Our signed payload, its `pub_key` is our Noise static key.
+fn local_handshake<C: CryptoSchema>(
+    static_key: &KeyPair,
+    capabilities: Capabilities,
+    kem: Option<KemShare>,
+    crypto: &C,
+    identity: &KeyPair,
+) -> Entity<'static> {
+    Capnp
+        .construct_signed_handshake(static_key.public(), capabilities, kem, crypto, identity)
+        .into_owned()
+}
+
+/// Reads the payload of the other side, which has to be a handshake.
+fn payload_of(bytes: &[u8]) -> Result<Entity<'static>> {
+    let remote = Capnp.deserialize(bytes)?.into_owned();
+    remote.expect_handshake()?;
+    Ok(remote)
+}
+
+async fn send<F: Frames>(
+    stream: &mut F,
+    noise: &mut HandshakeState,
+    pattern: Option<Pattern>,
+    payload: &[u8],
+) -> Result<()> {
+    let mut buf = vec![0; MAX_MESSAGE_LENGTH];
+    let len = noise.write_message(payload, &mut buf)?;
+
+    let mut frame = BytesMut::with_capacity(len + 1);
+    if let Some(pattern) = pattern {
+        frame.extend_from_slice(&[pattern as u8]);
+    }
+    frame.extend_from_slice(&buf[..len]);
+    stream.send(frame).await.map_err(Error::io)
+}
+
+/// Reads the next handshake message, returns its payload.
+async fn recieve<F: Frames>(stream: &mut F, noise: &mut HandshakeState) -> Result<Vec<u8>> {
+    let message = crate::recieve(stream).await?;
+    let mut buf = vec![0; MAX_MESSAGE_LENGTH];
+    let len = noise.read_message(&message, &mut buf)?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Checks the remote payload against the Noise static key it came with and
+/// splits the transport keys.
+fn finish<C: CryptoSchema>(
+    mut noise: HandshakeState,
+    local: &Entity<'_>,
+    remote: &Entity<'_>,
+    hybrid: Hybrid,
+    crypto: &C,
+) -> Result<Negotiated> {
+    let remote: &Handshake = remote.expect_handshake()?;
+    let remote_static = noise
+        .get_remote_static()
+        .ok_or_else(|| Error::crypto("The other side has no static key"))?
+        .then(PublicKey::try_from)?;
+
+    let remote_identity = *remote
+        .verify(crypto)?
+        .ok_or_else(|| Error::crypto("The handshake isn't signed"))?;
+    if *remote.pub_key() != remote_static || identity_to_dh(&remote_identity)? != remote_static {
+        return Err(Error::crypto(
+            "The identity doesn't match the Noise static key",
+        ));
+    }
+
+    let (version, capabilities) = protocol::negotiate(local.expect_handshake()?, remote)?;
+    let kem_secret = hybrid.secret(crypto, capabilities, remote)?;
+
+    // Both halves of the split are secret, the transcript binds them to this
+    // very handshake.
+    let (initiator, responder) = noise.dangerously_get_raw_split();
+    let mut secret = [initiator, responder].concat();
+    if let Some(kem_secret) = kem_secret {
+        secret.extend_from_slice(kem_secret.as_ref());
+    }
+    let schedule = KeySchedule::new(&secret, noise.get_handshake_hash());
+    let keys = if noise.is_initiator() {
+        schedule.client(version)
+    } else {
+        schedule.server(version)
+    };
+
+    Ok(Negotiated::new(
+        keys,
+        version,
+        capabilities,
+        DynSchema::from_capabilities(capabilities),
+        remote_identity,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use tokio_util::codec::Framed;
+
+    /// Both ends of an in-memory connection.
+    fn connection() -> (
+        Framed<impl Transport, FrameCodec>,
+        Framed<impl Transport, FrameCodec>,
+    ) {
+        let (client, server) = duplex(4096);
+        (
+            Framed::new(client, FrameCodec::new()),
+            Framed::new(server, FrameCodec::new()),
+        )
+    }
+
+    /// Runs `IK` if the client `pinned` the server, `XX` otherwise.
+    async fn handshake(
+        pinned: bool,
+        client_caps: Capabilities,
+    ) -> (Result<Negotiated>, Result<Negotiated>, KeyPair, KeyPair) {
+        let (mut client, mut server) = connection();
+        let (client_id, server_id) = (KeyPair::new_signing(), KeyPair::new_signing());
+        let pinned = pinned.then_some(server_id.public());
+        let (client, server) = tokio::join!(
+            initiate(&mut client, client_caps, Crypto, &client_id, pinned),
+            respond(&mut server, Capabilities::supported(), Crypto, &server_id),
+        );
+        (client, server, client_id, server_id)
+    }
+
+    fn assert_agreed(client: &Negotiated, server: &Negotiated) {
+        assert_eq!(client.keys().send(), server.keys().recv());
+        assert_eq!(client.keys().recv(), server.keys().send());
+        assert_eq!(client.version(), server.version());
+        assert_eq!(client.capabilities(), server.capabilities());
+        assert_eq!(client.event(), server.event());
+    }
+
+    #[tokio::test]
+    async fn xx() {
+        let (client, server, client_id, server_id) =
+            handshake(false, Capabilities::supported()).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+
+        assert_agreed(&client, &server);
+        assert_eq!(server_id.public(), client.remote_identity());
+        assert_eq!(client_id.public(), server.remote_identity());
+    }
+
+    #[tokio::test]
+    async fn ik() {
+        let (client, server, client_id, server_id) =
+            handshake(true, Capabilities::supported()).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+
+        assert_agreed(&client, &server);
+        assert_eq!(server_id.public(), client.remote_identity());
+        assert_eq!(client_id.public(), server.remote_identity());
+    }
+
+    #[tokio::test]
+    async fn ik_wrong_server() {
+        let (mut client, mut server) = connection();
+        let (client_id, server_id) = (KeyPair::new_signing(), KeyPair::new_signing());
+        let pinned = KeyPair::new_signing();
+
+        let (client, server) = tokio::join!(
+            initiate(
+                &mut client,
+                Capabilities::supported(),
+                Crypto,
+                &client_id,
+                Some(pinned.public())
+            ),
+            async {
+                let result = respond(&mut server, Capabilities::supported(), Crypto, &server_id);
+                let result = result.await;
+                drop(server);
+                result
+            },
+        );
+        assert!(matches!(server, Err(Error::Crypto(_))));
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn hybrid() {
+        for pinned in [false, true] {
+            let (client, server, _, _) = handshake(pinned, Capabilities::supported()).await;
+            let (client, server) = (client.unwrap(), server.unwrap());
+
+            assert_agreed(&client, &server);
+            assert!(client.capabilities().contains(Capabilities::HYBRID));
+        }
+    }
+
+    #[tokio::test]
+    async fn classic() {
+        let caps = Capabilities::CAPNP | Capabilities::JSON;
+        for pinned in [false, true] {
+            let (client, server, _, _) = handshake(pinned, caps).await;
+            let (client, server) = (client.unwrap(), server.unwrap());
+
+            assert_agreed(&client, &server);
+            assert!(!client.capabilities().contains(Capabilities::HYBRID));
+        }
+    }
+
+    #[test]
+    fn unanswered_offer() {
+        let remote = Handshake::new(
+            *KeyPair::new_dh().public(),
+            crate::protocol::PROTOCOL_VERSION,
+            Capabilities::supported(),
+            None,
+            None,
+        );
+        let offer = Hybrid::offer(Capabilities::supported());
+        let secret = offer.secret(&Crypto, Capabilities::supported(), &remote);
+        assert!(matches!(secret, Err(Error::Crypto(_))));
+
+        // Nothing to answer unless both sides support it.
+        let offer = Hybrid::offer(Capabilities::supported()).share();
+        let remote = Handshake::new(
+            *KeyPair::new_dh().public(),
+            crate::protocol::PROTOCOL_VERSION,
+            Capabilities::CAPNP,
+            offer,
+            None,
+        );
+        let (answer, kem) = Hybrid::answer(&Crypto, Capabilities::supported(), &remote);
+        assert!(matches!(answer, Hybrid::Off));
+        assert!(kem.is_none());
+    }
+
+    #[tokio::test]
+    async fn restricted() {
+        let (client, server, _, _) = handshake(false, Capabilities::PROTOBUF).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+
+        assert_eq!(DynSchema::Protobuf(Protobuf), *client.event());
+        assert_eq!(client.event(), server.event());
+    }
+
+    #[tokio::test]
+    async fn restricted_hybrid() {
+        let caps = Capabilities::supported().with_schema(Capabilities::JSON);
+        for pinned in [false, true] {
+            let (client, server, _, _) = handshake(pinned, caps).await;
+            let (client, server) = (client.unwrap(), server.unwrap());
+
+            assert_agreed(&client, &server);
+            assert_eq!(DynSchema::Json(Json), *client.event());
+            assert!(client.capabilities().contains(Capabilities::HYBRID));
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_pattern() {
+        let (mut client, mut server) = connection();
+        client.send(BytesMut::from(&[7u8][..])).await.unwrap();
+        let result = respond(
+            &mut server,
+            Capabilities::supported(),
+            Crypto,
+            &KeyPair::new_signing(),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Incompatible(_))));
+    }
+
+    #[tokio::test]
+    async fn event_after_handshake() {
+        let (mut client, mut server) = connection();
+        let (client_id, server_id) = (KeyPair::new_signing(), KeyPair::new_signing());
+        let (negotiated, remote) = tokio::join!(
+            initiate(
+                &mut client,
+                Capabilities::supported(),
+                Crypto,
+                &client_id,
+                None
+            ),
+            respond(&mut server, Capabilities::supported(), Crypto, &server_id),
+        );
+        let (negotiated, remote) = (negotiated.unwrap(), remote.unwrap());
+
+        let event = EventBuilder::construct(negotiated.event().clone(), Crypto)
+            .message("Meme", "Lorem ipsum")
+            .encrypt(negotiated.keys().send(), *negotiated.version())
+            .unwrap();
+        client.send(bytes::Bytes::from(event)).await.unwrap();
+
+        let recieved = crate::recieve(&mut server).await.unwrap();
+        let entity = EventBuilder::deconstruct(remote.event().clone(), Crypto)
+            .decrypt(remote.keys().recv(), *remote.version(), &recieved)
+            .unwrap()
+            .into_entity()
+            .unwrap();
+        assert_eq!("Lorem ipsum", entity.expect_message().unwrap().text());
+    }
+}
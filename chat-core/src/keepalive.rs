@@ -0,0 +1,191 @@
+//! Liveness tracking with ping/pong events
+
+use std::{
+    fmt::Display,
+    num::{NonZeroU32, NonZeroU64},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::prelude::*;
+
+/// How often a ping is sent by default.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(15);
+/// How many pings in a row may stay unanswered by default.
+pub const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+
+/// Keeps track of pings sent to a peer and pongs it answered with.
+///
+/// Call [`Keepalive::ping`] every [`Keepalive::interval`] and send a ping with
+/// the returned nonce, then feed every pong into [`Keepalive::pong`].
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+    interval: Duration,
+    max_missed: u32,
+    nonce: u64,
+    /// Nonce of the last ping and the time it was sent at.
+    pending: Option<(u64, Instant)>,
+    missed: u32,
+    latency: Option<Duration>,
+}
+
+impl Keepalive {
+    pub const fn new(interval: Duration, max_missed: u32) -> Self {
+        Self {
+            interval,
+            max_missed,
+            nonce: 0,
+            pending: None,
+            missed: 0,
+            latency: None,
+        }
+    }
+
+    /// Reads `PING_INTERVAL_MS` and `MAX_MISSED_PONGS`, both are optional.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if either variable isn't a positive
+    /// integer.
+    pub fn from_env() -> Result<Self> {
+        Self::parse(
+            std::env::var("PING_INTERVAL_MS").ok().as_deref(),
+            std::env::var("MAX_MISSED_PONGS").ok().as_deref(),
+        )
+    }
+
+    fn parse(interval: Option<&str>, max_missed: Option<&str>) -> Result<Self> {
+        let interval = match interval {
+            Some(ms) => Duration::from_millis(parse::<NonZeroU64>("PING_INTERVAL_MS", ms)?.get()),
+            None => DEFAULT_PING_INTERVAL,
+        };
+        let max_missed = match max_missed {
+            Some(count) => parse::<NonZeroU32>("MAX_MISSED_PONGS", count)?.get(),
+            None => DEFAULT_MAX_MISSED_PONGS,
+        };
+        Ok(Self::new(interval, max_missed))
+    }
+
+    pub const fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Round-trip time measured with the last answered ping.
+    pub const fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Registers a new ping and returns its nonce.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`Error::Timeout`] if the peer hasn't
+    /// answered the last `max_missed` pings.
+    pub fn ping(&mut self) -> Result<u64> {
+        if self.pending.is_some() {
+            self.missed += 1;
+        }
+        if self.missed >= self.max_missed {
+            return Err(Error::Timeout);
+        }
+
+        self.nonce = self.nonce.wrapping_add(1);
+        self.pending = Some((self.nonce, Instant::now()));
+        Ok(self.nonce)
+    }
+
+    /// Registers a pong and returns the round-trip time.
+    ///
+    /// Pongs to pings other than the last one are ignored.
+    pub fn pong(&mut self, nonce: u64) -> Option<Duration> {
+        match self.pending {
+            Some((pending, sent_at)) if pending == nonce => {
+                self.pending = None;
+                self.missed = 0;
+                self.latency = Some(sent_at.elapsed());
+                self.latency
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parses the value of the environment variable `name`.
+fn parse<T>(name: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|err| Error::generic(format!("Invalid {name} {value:?}: {err}")))
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::new(DEFAULT_PING_INTERVAL, DEFAULT_MAX_MISSED_PONGS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Keepalive>();
+    }
+    #[test]
+    fn test_sync() {
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<Keepalive>();
+    }
+
+    #[test]
+    fn answered() {
+        let mut keepalive = Keepalive::new(Duration::ZERO, 1);
+        for _ in 0..5 {
+            let nonce = keepalive.ping().unwrap();
+            assert!(keepalive.pong(nonce).is_some());
+        }
+        assert!(keepalive.latency().is_some());
+    }
+
+    #[test]
+    fn missed() {
+        let mut keepalive = Keepalive::new(Duration::ZERO, 2);
+        let nonce = keepalive.ping().unwrap();
+        keepalive.ping().unwrap();
+        // Too late, another ping is already pending.
+        assert_eq!(None, keepalive.pong(nonce));
+        assert_eq!(Err(Error::Timeout), keepalive.ping());
+    }
+
+    #[test]
+    fn recovered() {
+        let mut keepalive = Keepalive::new(Duration::ZERO, 2);
+        keepalive.ping().unwrap();
+        let nonce = keepalive.ping().unwrap();
+        assert!(keepalive.pong(nonce).is_some());
+        keepalive.ping().unwrap();
+        keepalive.ping().unwrap();
+    }
+
+    #[test]
+    fn parse() {
+        let keepalive = Keepalive::parse(None, None).unwrap();
+        assert_eq!(DEFAULT_PING_INTERVAL, keepalive.interval());
+        assert_eq!(DEFAULT_MAX_MISSED_PONGS, keepalive.max_missed);
+
+        let keepalive = Keepalive::parse(Some("250"), Some("1")).unwrap();
+        assert_eq!(Duration::from_millis(250), keepalive.interval());
+        assert_eq!(1, keepalive.max_missed);
+
+        // A zero interval can't be ticked and zero missed pongs drops every peer.
+        assert!(Keepalive::parse(Some("0"), None).is_err());
+        assert!(Keepalive::parse(None, Some("0")).is_err());
+        assert!(Keepalive::parse(Some("-1"), None).is_err());
+        assert!(Keepalive::parse(None, Some("often")).is_err());
+    }
+}
@@ -8,14 +8,19 @@
     unreachable_pub
 )]
 
-use futures::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
-use tokio_util::codec::{BytesCodec, Framed};
-
+use futures::StreamExt;
+pub mod codec;
 pub mod crypto;
 pub mod error;
 pub mod event;
+pub mod handshake;
+pub mod keepalive;
 pub mod prelude;
+pub mod protocol;
+pub mod quic;
+pub mod tls;
+pub mod transport;
+pub mod websocket;
 
 use prelude::*;
 
@@ -62,41 +67,8 @@ impl<T, E> OnErr for Result<T, E> {
     }
 }
 
-/// Creates and exchanges public keys, then computes shared keys.
-///
-/// # Errors
-///
-/// This function will return an error if:
-/// - Failed to send a public key.
-/// - Recieving is timeouted or channel is closed
-/// - Recieved data is not related to handshake
-pub async fn key_exchange<E, C>(
-    stream: &mut Framed<TcpStream, BytesCodec>,
-    event: E,
-    crypto: C,
-) -> Result<SharedSecret>
-where
-    E: EventSchema + Clone,
-    C: CryptoSchema,
-{
-    let key_pair = KeyPair::new_dh();
-    let handshake = event
-        .construct_handshake(key_pair.public())
-        .then(|entity| event.serialize(entity))
-        .then(|event| bytes::BytesMut::from(event.as_slice()));
-
-    stream.send(handshake).await.map_err(Error::io)?;
-    let recieved = recieve(stream).await?;
-
-    let deserialized = event.deserialize(&recieved)?;
-    let sender_public = deserialized.expect_handshake()?.pub_key();
-
-    let shared_secret = crypto.compute_dh(key_pair.secret(), sender_public);
-
-    Ok(shared_secret)
-}
-
-pub async fn recieve(stream: &mut Framed<TcpStream, BytesCodec>) -> Result<bytes::BytesMut> {
+/// Waits for the next frame, but no longer than [`TIMEOUT_MS`].
+pub async fn recieve<F: Frames>(stream: &mut F) -> Result<bytes::BytesMut> {
     use std::time::Duration;
     use tokio::time::timeout;
 
@@ -109,3 +81,29 @@ pub async fn recieve(stream: &mut Framed<TcpStream, BytesCodec>) -> Result<bytes
         None => Err(Error::io("The stream has been exhausted")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use tokio_util::codec::Framed;
+
+    /// Both ends of an in-memory connection.
+    fn connection() -> (
+        Framed<impl Transport, FrameCodec>,
+        Framed<impl Transport, FrameCodec>,
+    ) {
+        let (client, server) = duplex(4096);
+        (
+            Framed::new(client, FrameCodec::new()),
+            Framed::new(server, FrameCodec::new()),
+        )
+    }
+
+    #[tokio::test]
+    async fn recieve_from_closed() {
+        let (client, mut server) = connection();
+        drop(client);
+        assert!(matches!(recieve(&mut server).await, Err(Error::IO(_))));
+    }
+}
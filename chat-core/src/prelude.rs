@@ -1,8 +1,13 @@
 pub use crate::{
+    codec::FrameCodec,
     crypto::{
-        Crypto, CryptoKey, CryptoSchema, Encodable, KeyPair, PublicKey, SecretKey, SharedSecret,
+        Crypto, CryptoSchema, Encodable, KeyPair, PublicKey, SecretKey, SharedSecret, Signature,
     },
     error::{Error, Result},
-    event::{Capnp, Constructable, EventBuilder, EventKind, EventSchema, Protobuf, Serializable},
+    event::{
+        Capnp, Constructable, DynSchema, EventBuilder, EventHandler, EventKind, EventSchema, Json,
+        Protobuf, Serializable,
+    },
+    transport::{Frames, Transport},
     OnErr, Then,
 };
@@ -0,0 +1,189 @@
+//! Protocol versioning and the parameters negotiated during a handshake
+
+use std::{fmt, ops};
+
+use chat_macros::{Get, New};
+
+use crate::{
+    crypto::TransportKeys,
+    event::{DynSchema, Handshake},
+    prelude::*,
+};
+
+/// Version of the protocol spoken by this build.
+///
+/// - 2: timestamps are in milliseconds instead of seconds
+/// - 3: servers sign their handshake with a long-term identity key
+/// - 4: the handshake runs inside Noise, see [`crate::handshake`]
+/// - 5: frames are numbered and replayed ones are rejected, see
+///   [`crate::crypto::TransportKeys::seal`]
+/// - 6: frames are labeled with their event kind, which is authenticated
+///   along with the protocol version
+/// - 7: clients publish prekey bundles and start sessions from them, see
+///   [`crate::crypto::x3dh`]
+/// - 8: clients hand out sender keys for group messages and the server
+///   announces who's online, see [`crate::crypto::SenderKey`]
+/// - 9: handshakes and prekey bundles may mix in ML-KEM-768, see
+///   [`crate::crypto::ml_kem`]
+pub const PROTOCOL_VERSION: u32 = 9;
+/// The oldest version this build is still able to talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 9;
+
+/// A set of optional features a peer supports.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const EMPTY: Self = Self(0);
+    /// Events can be serialized with [`crate::event::Capnp`].
+    pub const CAPNP: Self = Self(1 << 0);
+    /// Events can be serialized with [`crate::event::Protobuf`].
+    pub const PROTOBUF: Self = Self(1 << 1);
+    /// Events can be serialized with [`crate::event::Json`].
+    pub const JSON: Self = Self(1 << 2);
+    /// Key exchanges mix X25519 with ML-KEM-768, see [`crate::crypto::ml_kem`].
+    pub const HYBRID: Self = Self(1 << 3);
+    /// Every serialization backend, the handshake picks one of them.
+    pub const SCHEMAS: Self = Self(Self::CAPNP.0 | Self::PROTOBUF.0 | Self::JSON.0);
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+    /// Features supported by this build.
+    pub const fn supported() -> Self {
+        Self(Self::SCHEMAS.0 | Self::HYBRID.0)
+    }
+    /// These capabilities with `schema` as the only serialization backend,
+    /// every other feature is kept.
+    pub const fn with_schema(self, schema: Self) -> Self {
+        Self(self.0 & !Self::SCHEMAS.0 | schema.0 & Self::SCHEMAS.0)
+    }
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl ops::BitOr for Capabilities {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl ops::BitAnd for Capabilities {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.intersection(rhs)
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Capabilities({:#x})", self.0)
+    }
+}
+
+/// Parameters both sides agreed on during the [`crate::handshake`].
+#[derive(New, Get, Debug)]
+pub struct Negotiated {
+    keys: TransportKeys,
+    version: u32,
+    capabilities: Capabilities,
+    event: DynSchema,
+    /// Long-term key the other side proved to own.
+    remote_identity: PublicKey,
+}
+
+/// Picks the highest version both sides speak and the features both support.
+///
+/// # Errors
+///
+/// This function will return an error if the remote side speaks only
+/// versions older than [`MIN_PROTOCOL_VERSION`].
+pub fn negotiate(local: &Handshake, remote: &Handshake) -> Result<(u32, Capabilities)> {
+    let version = (*local.version()).min(*remote.version());
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(Error::incompatible(format!(
+            "remote speaks protocol version {} but at least {} is required",
+            remote.version(),
+            MIN_PROTOCOL_VERSION
+        )));
+    }
+
+    let capabilities = local.capabilities().intersection(*remote.capabilities());
+    Ok((version, capabilities))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(version: u32, capabilities: u64) -> Handshake {
+        let pub_key = PublicKey::new(rand::random());
+        Handshake::new(
+            pub_key,
+            version,
+            Capabilities::from_bits(capabilities),
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn same_version() {
+        let local = handshake(PROTOCOL_VERSION, 0b011);
+        let remote = handshake(PROTOCOL_VERSION, 0b011);
+        let (version, capabilities) = negotiate(&local, &remote).unwrap();
+        assert_eq!(PROTOCOL_VERSION, version);
+        assert_eq!(Capabilities::from_bits(0b011), capabilities);
+    }
+
+    #[test]
+    fn downgrade() {
+        let local = handshake(MIN_PROTOCOL_VERSION + 1, 0b011);
+        let remote = handshake(MIN_PROTOCOL_VERSION, 0b110);
+        let (version, capabilities) = negotiate(&local, &remote).unwrap();
+        assert_eq!(MIN_PROTOCOL_VERSION, version);
+        assert_eq!(Capabilities::from_bits(0b010), capabilities);
+
+        // Negotiation is symmetric
+        let (version, capabilities) = negotiate(&remote, &local).unwrap();
+        assert_eq!(MIN_PROTOCOL_VERSION, version);
+        assert_eq!(Capabilities::from_bits(0b010), capabilities);
+    }
+
+    #[test]
+    fn reject() {
+        let local = handshake(PROTOCOL_VERSION, 0);
+        let remote = handshake(MIN_PROTOCOL_VERSION - 1, 0);
+        let err = negotiate(&local, &remote).unwrap_err();
+        assert!(matches!(err, Error::Incompatible(_)));
+    }
+
+    #[test]
+    fn capabilities() {
+        let a = Capabilities::from_bits(0b01);
+        let b = Capabilities::from_bits(0b10);
+        assert!((a | b).contains(a));
+        assert!(!a.contains(b));
+        assert!((a & b).is_empty());
+        assert!(Capabilities::supported().contains(Capabilities::EMPTY));
+        assert!(Capabilities::supported().contains(Capabilities::CAPNP | Capabilities::PROTOBUF));
+
+        let json = Capabilities::supported().with_schema(Capabilities::JSON);
+        assert_eq!(Capabilities::JSON | Capabilities::HYBRID, json);
+        assert_eq!(
+            json,
+            json.with_schema(Capabilities::JSON | Capabilities::HYBRID)
+        );
+    }
+}
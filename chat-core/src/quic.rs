@@ -0,0 +1,439 @@
+//! QUIC transport with a stream per [`Lane`]
+//!
+//! Control traffic travels on the bidirectional stream the client opens first.
+//! Every conversation gets a unidirectional stream of its own the first time
+//! an event is sent on it, so a large event only holds up its own conversation.
+//! Frames are delimited with [`FrameCodec`] on every stream.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use bytes::BytesMut;
+use futures::{stream::SelectAll, Sink, Stream, StreamExt};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ConnectionError, RecvStream, SendStream,
+};
+pub use quinn::{Endpoint, Incoming};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::{
+    prelude::*,
+    tls::{ClientConfig, ServerConfig},
+    transport::{FrameSink, FrameStream, Lane},
+};
+
+/// Application protocol negotiated during the QUIC handshake.
+pub const ALPN: &[u8] = b"chat";
+
+type Opening = Pin<Box<dyn Future<Output = Result<SendStream, ConnectionError>> + Send>>;
+type Accepting = Pin<Box<dyn Future<Output = Result<RecvStream, ConnectionError>> + Send>>;
+
+/// Binds a server endpoint at `addr` presenting the certificate of `tls`.
+///
+/// # Errors
+///
+/// This function will return an error if `tls` doesn't support TLS 1.3 or the
+/// address can't be bound.
+pub fn server(addr: SocketAddr, mut tls: ServerConfig) -> Result<Endpoint> {
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls).map_err(Error::crypto)?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Endpoint::server(config, addr).map_err(Error::io)
+}
+
+/// Completes an incoming connection and waits for its control stream.
+///
+/// # Errors
+///
+/// This function will return an error if the QUIC handshake fails.
+pub async fn accept(incoming: Incoming) -> Result<Quic> {
+    let connection = incoming.await.map_err(Error::io)?;
+    let (send, recv) = connection.accept_bi().await.map_err(Error::io)?;
+    Ok(Quic::new(connection, send, recv, None))
+}
+
+/// Connects to `addr` and opens the control stream.
+///
+/// `server_name` is checked against the server certificate.
+///
+/// # Errors
+///
+/// This function will return an error if the QUIC handshake fails.
+pub async fn connect(addr: SocketAddr, server_name: &str, mut tls: ClientConfig) -> Result<Quic> {
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(tls).map_err(Error::crypto)?;
+    let config = quinn::ClientConfig::new(Arc::new(crypto));
+
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let endpoint = Endpoint::client(local).map_err(Error::io)?;
+    let connection = endpoint
+        .connect_with(config, addr, server_name)
+        .map_err(Error::io)?
+        .await
+        .map_err(Error::io)?;
+    let (send, recv) = connection.open_bi().await.map_err(Error::io)?;
+    Ok(Quic::new(connection, send, recv, Some(endpoint)))
+}
+
+/// A QUIC connection seen as [`Frames`].
+#[derive(Debug)]
+pub struct Quic {
+    sink: QuicSink,
+    stream: QuicStream,
+}
+
+impl Quic {
+    fn new(
+        connection: quinn::Connection,
+        send: SendStream,
+        recv: RecvStream,
+        endpoint: Option<Endpoint>,
+    ) -> Self {
+        let sink = QuicSink {
+            connection: connection.clone(),
+            control: FramedWrite::new(send, FrameCodec::new()),
+            lanes: HashMap::new(),
+            opening: None,
+            route: Lane::Control,
+            _endpoint: endpoint,
+        };
+        let stream = QuicStream {
+            accepting: Some(accept_uni(&connection)),
+            connection,
+            control: FramedRead::new(recv, FrameCodec::new()),
+            lanes: SelectAll::new(),
+            control_turn: false,
+        };
+        Self { sink, stream }
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.sink.connection.remote_address()
+    }
+}
+
+impl Stream for Quic {
+    type Item = io::Result<BytesMut>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+impl Sink<BytesMut> for Quic {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.sink).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: BytesMut) -> io::Result<()> {
+        Pin::new(&mut self.sink).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.sink).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.sink).poll_close(cx)
+    }
+}
+
+impl FrameSink for Quic {
+    fn route(&mut self, lane: Lane) {
+        self.sink.route(lane);
+    }
+}
+
+impl Frames for Quic {
+    fn split(self: Box<Self>) -> (Box<dyn FrameSink>, Box<dyn FrameStream>) {
+        (Box::new(self.sink), Box::new(self.stream))
+    }
+}
+
+/// Sending half of [`Quic`].
+pub struct QuicSink {
+    connection: quinn::Connection,
+    control: FramedWrite<SendStream, FrameCodec>,
+    lanes: HashMap<String, FramedWrite<SendStream, FrameCodec>>,
+    /// Stream being opened for the lane of the next frame.
+    opening: Option<Opening>,
+    route: Lane,
+    /// A client endpoint lives as long as its only connection.
+    _endpoint: Option<Endpoint>,
+}
+
+impl QuicSink {
+    fn poll_open(&mut self, cx: &mut Context<'_>, name: &str) -> Poll<io::Result<()>> {
+        let connection = &self.connection;
+        let opening = self.opening.get_or_insert_with(|| {
+            let connection = connection.clone();
+            Box::pin(async move { connection.open_uni().await })
+        });
+        let stream = ready!(opening.as_mut().poll(cx));
+        self.opening = None;
+
+        let stream = FramedWrite::new(stream?, FrameCodec::new());
+        self.lanes.insert(name.to_owned(), stream);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl fmt::Debug for QuicSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicSink")
+            .field("connection", &self.connection)
+            .field("lanes", &self.lanes.keys())
+            .field("route", &self.route)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sink<BytesMut> for QuicSink {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Lane::Conversation(name) = this.route.clone() else {
+            return Sink::<BytesMut>::poll_ready(Pin::new(&mut this.control), cx);
+        };
+        if !this.lanes.contains_key(&name) {
+            ready!(this.poll_open(cx, &name))?;
+        }
+        match this.lanes.get_mut(&name) {
+            Some(lane) => Sink::<BytesMut>::poll_ready(Pin::new(lane), cx),
+            None => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: BytesMut) -> io::Result<()> {
+        let this = self.get_mut();
+        match std::mem::take(&mut this.route) {
+            Lane::Control => Sink::<BytesMut>::start_send(Pin::new(&mut this.control), item),
+            Lane::Conversation(name) => match this.lanes.get_mut(&name) {
+                Some(lane) => Sink::<BytesMut>::start_send(Pin::new(lane), item),
+                None => Err(io::ErrorKind::NotConnected.into()),
+            },
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Sink::<BytesMut>::poll_flush(
+            Pin::new(&mut this.control),
+            cx
+        ))?;
+        for lane in this.lanes.values_mut() {
+            ready!(Sink::<BytesMut>::poll_flush(Pin::new(lane), cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        for lane in this.lanes.values_mut() {
+            ready!(Sink::<BytesMut>::poll_close(Pin::new(lane), cx))?;
+        }
+        Sink::<BytesMut>::poll_close(Pin::new(&mut this.control), cx)
+    }
+}
+
+impl FrameSink for QuicSink {
+    fn route(&mut self, lane: Lane) {
+        self.route = lane;
+    }
+}
+
+/// Receiving half of [`Quic`], frames of every lane merged together.
+pub struct QuicStream {
+    connection: quinn::Connection,
+    control: FramedRead<RecvStream, FrameCodec>,
+    lanes: SelectAll<FramedRead<RecvStream, FrameCodec>>,
+    accepting: Option<Accepting>,
+    /// Whether the control stream goes first on the next poll.
+    control_turn: bool,
+}
+
+fn accept_uni(connection: &quinn::Connection) -> Accepting {
+    let connection = connection.clone();
+    Box::pin(async move { connection.accept_uni().await })
+}
+
+impl fmt::Debug for QuicStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicStream")
+            .field("connection", &self.connection)
+            .field("lanes", &self.lanes.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Stream for QuicStream {
+    type Item = io::Result<BytesMut>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while let Some(accepting) = this.accepting.as_mut() {
+            match accepting.as_mut().poll(cx) {
+                Poll::Ready(Ok(stream)) => {
+                    this.lanes.push(FramedRead::new(stream, FrameCodec::new()));
+                    this.accepting = Some(accept_uni(&this.connection));
+                }
+                // The connection is gone, the control stream tells why.
+                Poll::Ready(Err(_)) => this.accepting = None,
+                Poll::Pending => break,
+            }
+        }
+
+        // Take turns, so that busy lanes can't hold up keepalive and the like.
+        this.control_turn = !this.control_turn;
+        if this.control_turn {
+            if let Poll::Ready(frame) = this.control.poll_next_unpin(cx) {
+                return Poll::Ready(frame);
+            }
+        }
+        if let Poll::Ready(Some(frame)) = this.lanes.poll_next_unpin(cx) {
+            return Poll::Ready(Some(frame));
+        }
+        if this.control_turn {
+            Poll::Pending
+        } else {
+            this.control.poll_next_unpin(cx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Capabilities;
+    use futures::SinkExt;
+    use rcgen::CertifiedKey;
+    use tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Quic>();
+        assert_send::<QuicSink>();
+        assert_send::<QuicStream>();
+    }
+
+    /// Both ends of a connection over localhost.
+    ///
+    /// The server only sees the control stream once something is sent on it,
+    /// so the client greets it first.
+    async fn connection() -> (Quic, Quic) {
+        let CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+        let tls = crate::tls::server_config(vec![cert.der().clone()], key.into()).unwrap();
+        let endpoint = server(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), tls).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        let tls = crate::tls::client_config_with_pinned(cert.der().clone()).unwrap();
+        let server = tokio::spawn(async move {
+            let incoming = endpoint.accept().await.unwrap();
+            accept(incoming).await
+        });
+        let mut client = connect(addr, "localhost", tls).await.unwrap();
+        client.send(BytesMut::from("hello")).await.unwrap();
+
+        let mut server = server.await.unwrap().unwrap();
+        assert_eq!("hello", server.next().await.unwrap().unwrap());
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (mut client, mut server) = connection().await;
+        let (client_id, server_id) = (KeyPair::new_signing(), KeyPair::new_signing());
+        let (client, server) = tokio::join!(
+            crate::handshake::initiate(
+                &mut client,
+                Capabilities::supported(),
+                Crypto,
+                &client_id,
+                None,
+            ),
+            crate::handshake::respond(&mut server, Capabilities::supported(), Crypto, &server_id,),
+        );
+        assert_eq!(client.unwrap().keys().send(), server.unwrap().keys().recv());
+    }
+
+    #[tokio::test]
+    async fn lanes() {
+        let (mut client, mut server) = connection().await;
+        for (lane, text) in [("a", "a1"), ("b", "b1"), ("a", "a2")] {
+            client.route(Lane::Conversation(lane.to_owned()));
+            client.send(BytesMut::from(text)).await.unwrap();
+        }
+        client.send(BytesMut::from("control")).await.unwrap();
+        assert_eq!(2, client.sink.lanes.len());
+
+        let mut recieved = Vec::new();
+        for _ in 0..4 {
+            recieved.push(server.next().await.unwrap().unwrap());
+        }
+        // Every lane stays in order, lanes don't wait for each other.
+        let position = |text: &str| recieved.iter().position(|r| r == text).unwrap();
+        assert!(position("a1") < position("a2"));
+        assert!(recieved.iter().any(|r| r == "b1"));
+        assert!(recieved.iter().any(|r| r == "control"));
+    }
+
+    #[tokio::test]
+    async fn fair() {
+        let (mut client, mut server) = connection().await;
+        client.route(Lane::Conversation("busy".to_owned()));
+        client.send(BytesMut::from("first")).await.unwrap();
+        assert_eq!("first", server.next().await.unwrap().unwrap());
+
+        for _ in 0..16 {
+            client.route(Lane::Conversation("busy".to_owned()));
+            client.send(BytesMut::from("busy")).await.unwrap();
+        }
+        client.send(BytesMut::from("control")).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // Everything has arrived, the control frame still doesn't wait for the lane.
+        let mut recieved = Vec::new();
+        for _ in 0..17 {
+            recieved.push(server.next().await.unwrap().unwrap());
+        }
+        assert!(recieved.iter().position(|r| r == "control").unwrap() < 2);
+    }
+
+    #[tokio::test]
+    async fn split() {
+        let (client, mut server) = connection().await;
+        let (mut sink, mut stream) = Frames::split(Box::new(client));
+
+        sink.send(BytesMut::from("ping")).await.unwrap();
+        assert_eq!("ping", server.next().await.unwrap().unwrap());
+        server.route(Lane::Conversation(crate::transport::LOBBY.to_owned()));
+        server.send(BytesMut::from("pong")).await.unwrap();
+        assert_eq!("pong", stream.next().await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn closed() {
+        let (mut client, mut server) = connection().await;
+        client.close().await.unwrap();
+        assert!(server.next().await.is_none());
+    }
+}
@@ -0,0 +1,311 @@
+//! Optional TLS layer around a [`crate::transport::Transport`]
+//!
+//! The server presents a certificate chain, the client either verifies it
+//! against a CA or accepts exactly one pinned certificate.
+
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, PrivateKeyDer, UnixTime},
+    CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+pub use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, ServerConfig},
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::prelude::*;
+
+impl From<rustls::Error> for crate::error::Error {
+    fn from(value: rustls::Error) -> Self {
+        Self::crypto(value)
+    }
+}
+
+/// Reads every certificate from a PEM file.
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be read or holds no
+/// certificates.
+pub fn load_certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = File::open(path).map(BufReader::new).map_err(Error::io)?;
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(Error::decode)?;
+    if certs.is_empty() {
+        return Err(Error::decode("No certificates found"));
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key from a PEM file.
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be read or holds no
+/// private key.
+pub fn load_key(path: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = File::open(path).map(BufReader::new).map_err(Error::io)?;
+    rustls_pemfile::private_key(&mut reader)
+        .map_err(Error::decode)?
+        .ok_or_else(|| Error::decode("No private key found"))
+}
+
+/// Server side: presents `certs` signed with `key`.
+///
+/// # Errors
+///
+/// This function will return an error if the key doesn't match the certificate.
+pub fn acceptor(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(server_config(certs, key)?)))
+}
+
+/// Client side: trusts servers whose certificate is issued by one of `roots`.
+///
+/// # Errors
+///
+/// This function will return an error if one of `roots` isn't a valid
+/// certificate.
+pub fn connector_with_ca(roots: Vec<CertificateDer<'static>>) -> Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(client_config_with_ca(roots)?)))
+}
+
+/// Client side: trusts only a server presenting exactly `pinned`, e.g. a
+/// self-signed certificate. The server name isn't checked.
+///
+/// # Errors
+///
+/// This function will return an error if the crypto provider can't be set up.
+pub fn connector_with_pinned(pinned: CertificateDer<'static>) -> Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(client_config_with_pinned(
+        pinned,
+    )?)))
+}
+
+/// Configuration behind [`acceptor`], for transports which run TLS
+/// themselves, e.g. QUIC.
+///
+/// # Errors
+///
+/// This function will return an error if the key doesn't match the certificate.
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<ServerConfig> {
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(config)
+}
+
+/// Configuration behind [`connector_with_ca`].
+///
+/// # Errors
+///
+/// This function will return an error if one of `roots` isn't a valid
+/// certificate.
+pub fn client_config_with_ca(roots: Vec<CertificateDer<'static>>) -> Result<ClientConfig> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root)?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    Ok(config)
+}
+
+/// Configuration behind [`connector_with_pinned`].
+///
+/// # Errors
+///
+/// This function will return an error if the crypto provider can't be set up.
+pub fn client_config_with_pinned(pinned: CertificateDer<'static>) -> Result<ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let verifier = Pinned {
+        cert: pinned,
+        algorithms: provider.signature_verification_algorithms,
+    };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(config)
+}
+
+/// Accepts a single certificate, but still checks that the server owns its key.
+#[derive(Debug)]
+struct Pinned {
+    cert: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Capabilities;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use tokio::io::duplex;
+    use tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+    use tokio_util::codec::Framed;
+
+    fn self_signed() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+        (cert.der().clone(), key.into())
+    }
+
+    /// A CA along with a certificate for `localhost` it has issued.
+    fn issued() -> (
+        CertificateDer<'static>,
+        CertificateDer<'static>,
+        PrivateKeyDer<'static>,
+    ) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".into()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+        (ca.der().clone(), cert.der().clone(), key.into())
+    }
+
+    /// Runs the whole key exchange over TLS on top of an in-memory pipe.
+    async fn exchange(acceptor: TlsAcceptor, connector: TlsConnector) -> Result<()> {
+        let (client, server) = duplex(16 * 1024);
+        let name = ServerName::try_from("localhost").unwrap();
+
+        let (client, server) =
+            tokio::join!(connector.connect(name, client), acceptor.accept(server));
+        let client = client.map_err(Error::io)?;
+        let server = server.map_err(Error::io)?;
+
+        let mut client = Framed::new(client, FrameCodec::new());
+        let mut server = Framed::new(server, FrameCodec::new());
+        let (client_id, server_id) = (
+            crate::crypto::KeyPair::new_signing(),
+            crate::crypto::KeyPair::new_signing(),
+        );
+        let (client, server) = tokio::join!(
+            crate::handshake::initiate(
+                &mut client,
+                Capabilities::supported(),
+                Crypto,
+                &client_id,
+                None,
+            ),
+            crate::handshake::respond(&mut server, Capabilities::supported(), Crypto, &server_id,),
+        );
+        assert_eq!(client?.keys().send(), server?.keys().recv());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pinned() {
+        let (cert, key) = self_signed();
+        let acceptor = acceptor(vec![cert.clone()], key).unwrap();
+        let connector = connector_with_pinned(cert).unwrap();
+        exchange(acceptor, connector).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pinned_mismatch() {
+        let (cert, key) = self_signed();
+        let (other, _) = self_signed();
+        let acceptor = acceptor(vec![cert], key).unwrap();
+        let connector = connector_with_pinned(other).unwrap();
+        assert!(exchange(acceptor, connector).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ca() {
+        let (ca, cert, key) = issued();
+        let acceptor = acceptor(vec![cert], key).unwrap();
+        let connector = connector_with_ca(vec![ca]).unwrap();
+        exchange(acceptor, connector).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_ca() {
+        let (_, cert, key) = issued();
+        let (other_ca, _, _) = issued();
+        let acceptor = acceptor(vec![cert], key).unwrap();
+        let connector = connector_with_ca(vec![other_ca]).unwrap();
+        assert!(exchange(acceptor, connector).await.is_err());
+    }
+
+    #[test]
+    fn load_pem() {
+        let CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("chat-core-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+
+        assert_eq!(vec![cert.der().clone()], load_certs(&cert_path).unwrap());
+        assert!(load_key(&key_path).is_ok());
+        assert!(load_certs(&key_path).is_err());
+        assert!(load_key(&cert_path).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+//! Byte streams the protocol can run over
+
+use std::io;
+
+use bytes::BytesMut;
+use futures::{stream::SplitSink, Sink, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+use crate::{
+    codec::FrameCodec,
+    event::{Entity, EventKind},
+    websocket::WebSocket,
+};
+
+/// Any reliable, ordered byte stream, e.g. a TCP connection or an in-memory
+/// [`tokio::io::duplex`] pipe.
+///
+/// Frame it with [`crate::codec::FrameCodec`] to run the protocol over it.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// Messages don't name a room yet, so they all belong to this one.
+pub const LOBBY: &str = "lobby";
+
+/// Independent streams a connection may carry, so that a large event on one of
+/// them doesn't hold up the others.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum Lane {
+    /// Handshake, authentication, prekeys, presence, keepalive and errors.
+    ///
+    /// Pairwise handshakes stay here too, they are broadcast without naming
+    /// the peer they are meant for.
+    #[default]
+    Control,
+    /// Messages of a single room or direct conversation, in order.
+    Conversation(String),
+}
+
+impl Lane {
+    /// The lane `entity` belongs on.
+    pub fn of(entity: &Entity<'_>) -> Self {
+        match entity.kind() {
+            EventKind::Message(_) => Self::Conversation(LOBBY.to_owned()),
+            EventKind::SessionInit(init) => Self::direct(init.sender(), init.recipient()),
+            EventKind::KeyDistribution(distribution) => {
+                Self::direct(distribution.sender(), distribution.recipient())
+            }
+            _ => Self::Control,
+        }
+    }
+
+    /// The direct conversation between `one` and `other`, the same lane
+    /// whichever of them is sending.
+    pub fn direct(one: &str, other: &str) -> Self {
+        let (first, second) = if one <= other {
+            (one, other)
+        } else {
+            (other, one)
+        };
+        Self::Conversation(format!("direct:{first}:{second}"))
+    }
+}
+
+/// Receiving half of [`Frames`].
+pub trait FrameStream: Stream<Item = io::Result<BytesMut>> + Unpin + Send {}
+
+impl<T> FrameStream for T where T: Stream<Item = io::Result<BytesMut>> + Unpin + Send {}
+
+/// Sending half of [`Frames`].
+pub trait FrameSink: Sink<BytesMut, Error = io::Error> + Unpin + Send {
+    /// Sends the next frame on `lane`, every frame after it goes on
+    /// [`Lane::Control`] again.
+    ///
+    /// Transports with a single stream ignore it, everything stays in order
+    /// there anyway.
+    fn route(&mut self, _lane: Lane) {}
+}
+
+/// A connection carrying whole frames, one event each.
+///
+/// Either a [`Transport`] framed with [`FrameCodec`], a transport with
+/// message boundaries of its own, e.g. [`WebSocket`], or one with a stream per
+/// [`Lane`], e.g. [`crate::quic::Quic`].
+pub trait Frames: FrameStream + FrameSink {
+    /// Splits the connection, so it can be read and written concurrently.
+    fn split(self: Box<Self>) -> (Box<dyn FrameSink>, Box<dyn FrameStream>);
+}
+
+/// Frames a byte stream with the default [`FrameCodec`].
+pub fn framed<T: Transport + 'static>(transport: T) -> Box<dyn Frames> {
+    Box::new(Framed::new(transport, FrameCodec::new()))
+}
+
+/// Splits connections with a single stream.
+fn split<F>(frames: F) -> (Box<dyn FrameSink>, Box<dyn FrameStream>)
+where
+    F: FrameStream + Sink<BytesMut, Error = io::Error> + 'static,
+{
+    let (sink, stream) = StreamExt::split(frames);
+    (Box::new(sink), Box::new(stream))
+}
+
+impl<T: Transport + 'static> FrameSink for Framed<T, FrameCodec> {}
+
+impl<T: Transport + 'static> Frames for Framed<T, FrameCodec> {
+    fn split(self: Box<Self>) -> (Box<dyn FrameSink>, Box<dyn FrameStream>) {
+        split(*self)
+    }
+}
+
+impl<T: Transport + 'static> FrameSink for WebSocket<T> {}
+
+impl<T: Transport + 'static> Frames for WebSocket<T> {
+    fn split(self: Box<Self>) -> (Box<dyn FrameSink>, Box<dyn FrameStream>) {
+        split(*self)
+    }
+}
+
+impl<S> FrameSink for SplitSink<S, BytesMut> where S: FrameStream + Sink<BytesMut, Error = io::Error>
+{}
+
+impl<F: FrameSink + ?Sized> FrameSink for Box<F> {
+    fn route(&mut self, lane: Lane) {
+        (**self).route(lane);
+    }
+}
+
+impl<F: Frames + ?Sized> Frames for Box<F> {
+    fn split(self: Box<Self>) -> (Box<dyn FrameSink>, Box<dyn FrameStream>) {
+        F::split(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    #[test]
+    fn lane_of() {
+        let message = Capnp.construct_message("Meme", "Lorem ipsum");
+        assert_eq!(Lane::Conversation(LOBBY.to_owned()), Lane::of(&message));
+        assert_eq!(Lane::Control, Lane::of(&Capnp.construct_ping(1)));
+
+        let distribution = Capnp.construct_key_distribution("Meme", "Badum", "Lorem ipsum");
+        assert_eq!(Lane::direct("Badum", "Meme"), Lane::of(&distribution));
+        assert_ne!(Lane::direct("Meme", "Lorem"), Lane::of(&distribution));
+    }
+}
@@ -0,0 +1,200 @@
+//! WebSocket transport for clients behind HTTP(S)-only proxies
+//!
+//! Every event travels in its own binary message, so no [`FrameCodec`] is
+//! needed on top. Run it over TLS for `wss://`.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::BytesMut;
+use futures::{Sink, Stream};
+pub use tokio_tungstenite::tungstenite::http::Uri;
+use tokio_tungstenite::{
+    tungstenite::{self, protocol::WebSocketConfig, Message},
+    WebSocketStream,
+};
+
+use crate::{codec::DEFAULT_MAX_FRAME_LENGTH, prelude::*};
+
+/// A WebSocket connection seen as a stream of [`Frames`].
+///
+/// Text messages are rejected, control messages are handled by the WebSocket
+/// itself and never show up.
+#[derive(Debug)]
+pub struct WebSocket<T> {
+    inner: WebSocketStream<T>,
+}
+
+/// Performs the server side of the WebSocket upgrade.
+///
+/// # Errors
+///
+/// This function will return an error if the peer isn't a WebSocket client.
+pub async fn accept<T: Transport>(transport: T) -> Result<WebSocket<T>> {
+    let inner = tokio_tungstenite::accept_async_with_config(transport, Some(config()))
+        .await
+        .map_err(into_io)
+        .map_err(Error::io)?;
+    Ok(WebSocket { inner })
+}
+
+/// Performs the client side of the WebSocket upgrade at `uri`, e.g.
+/// `ws://127.0.0.1:6143`.
+///
+/// # Errors
+///
+/// This function will return an error if the server refused the upgrade.
+pub async fn connect<T: Transport>(uri: &Uri, transport: T) -> Result<WebSocket<T>> {
+    let (inner, _) =
+        tokio_tungstenite::client_async_with_config(uri.clone(), transport, Some(config()))
+            .await
+            .map_err(into_io)
+            .map_err(Error::io)?;
+    Ok(WebSocket { inner })
+}
+
+/// Messages are limited just like frames of [`FrameCodec`].
+fn config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(DEFAULT_MAX_FRAME_LENGTH),
+        max_frame_size: Some(DEFAULT_MAX_FRAME_LENGTH),
+        ..Default::default()
+    }
+}
+
+fn into_io(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+impl<T: Transport> Stream for WebSocket<T> {
+    type Item = io::Result<BytesMut>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(tungstenite::Error::ConnectionClosed)) | None => return Poll::Ready(None),
+                Some(Err(err)) => return Poll::Ready(Some(Err(into_io(err)))),
+            };
+            match message {
+                Message::Binary(data) => return Poll::Ready(Some(Ok(BytesMut::from(&data[..])))),
+                Message::Close(_) => return Poll::Ready(None),
+                Message::Text(_) => {
+                    let err = io::Error::new(io::ErrorKind::InvalidData, "unexpected text message");
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            }
+        }
+    }
+}
+
+impl<T: Transport> Sink<BytesMut> for WebSocket<T> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(cx).map_err(into_io)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: BytesMut) -> io::Result<()> {
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(item.to_vec()))
+            .map_err(into_io)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(into_io)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(into_io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Capabilities;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{duplex, DuplexStream};
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<WebSocket<DuplexStream>>();
+    }
+    #[test]
+    fn test_sync() {
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<WebSocket<DuplexStream>>();
+    }
+
+    async fn connection() -> (WebSocket<DuplexStream>, WebSocket<DuplexStream>) {
+        let (client, server) = duplex(16 * 1024);
+        let uri = Uri::from_static("ws://localhost/");
+        let (client, server) = tokio::join!(connect(&uri, client), accept(server));
+        (client.unwrap(), server.unwrap())
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (mut client, mut server) = connection().await;
+        let (client_id, server_id) = (KeyPair::new_signing(), KeyPair::new_signing());
+        let (client, server) = tokio::join!(
+            crate::handshake::initiate(
+                &mut client,
+                Capabilities::supported(),
+                Crypto,
+                &client_id,
+                None,
+            ),
+            crate::handshake::respond(&mut server, Capabilities::supported(), Crypto, &server_id,),
+        );
+        assert_eq!(client.unwrap().keys().send(), server.unwrap().keys().recv());
+    }
+
+    #[tokio::test]
+    async fn message_per_frame() {
+        let (client, server) = duplex(16 * 1024);
+        let uri = Uri::from_static("ws://localhost/");
+        let (client, server) = tokio::join!(
+            connect(&uri, client),
+            tokio_tungstenite::accept_async(server)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        client.send(BytesMut::from("one")).await.unwrap();
+        client.send(BytesMut::from("two")).await.unwrap();
+        let one = server.next().await.unwrap().unwrap();
+        let two = server.next().await.unwrap().unwrap();
+        assert_eq!(Message::Binary(b"one".to_vec()), one);
+        assert_eq!(Message::Binary(b"two".to_vec()), two);
+    }
+
+    #[tokio::test]
+    async fn text_rejected() {
+        let (client, server) = duplex(16 * 1024);
+        let (client, server) = tokio::join!(
+            tokio_tungstenite::client_async("ws://localhost/", client),
+            accept(server)
+        );
+        let ((mut client, _), mut server) = (client.unwrap(), server.unwrap());
+
+        client.send(Message::Text("meme".into())).await.unwrap();
+        let err = server.next().await.unwrap().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[tokio::test]
+    async fn closed() {
+        let (mut client, mut server) = connection().await;
+        client.close().await.unwrap();
+        assert!(server.next().await.is_none());
+    }
+}
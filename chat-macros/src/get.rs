@@ -68,6 +68,7 @@ fn named_fields_with_path(
             let last_arg = path_arg.args.last().unwrap();
             let ret_type = match last_segment.ident.to_string().as_str() {
                 "Vec" => quote! { & [ #last_arg ] },
+                "Option" => quote! { Option<& #last_arg> },
                 _ => quote! { & #last_arg },
             };
             let body = quote! { self.#name.as_ref() };
@@ -4,3 +4,33 @@ CREATE TABLE IF NOT EXISTS accounts (
     password VARCHAR ( 100 ) NOT NULL
 );
 
+
+-- Keys, base64-encoded, other users start sessions with while this one is offline.
+CREATE TABLE IF NOT EXISTS prekeys (
+    user_id INTEGER PRIMARY KEY REFERENCES accounts ( user_id ) ON DELETE CASCADE,
+    identity_key VARCHAR ( 64 ) NOT NULL,
+    signed_prekey VARCHAR ( 64 ) NOT NULL,
+    signature VARCHAR ( 128 ) NOT NULL,
+    -- Only there if the user supports hybrid key exchanges.
+    kem_prekey TEXT,
+    kem_signature VARCHAR ( 128 )
+);
+
+-- Each of them is handed out at most once.
+CREATE TABLE IF NOT EXISTS one_time_prekeys (
+    prekey_id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES accounts ( user_id ) ON DELETE CASCADE,
+    public_key VARCHAR ( 64 ) NOT NULL
+);
+
+-- Sessions started with users who weren't online, delivered once they are.
+CREATE TABLE IF NOT EXISTS session_inits (
+    init_id SERIAL PRIMARY KEY,
+    recipient_id INTEGER NOT NULL REFERENCES accounts ( user_id ) ON DELETE CASCADE,
+    sender VARCHAR ( 50 ) NOT NULL,
+    identity_key VARCHAR ( 64 ) NOT NULL,
+    ephemeral_key VARCHAR ( 64 ) NOT NULL,
+    signed_prekey VARCHAR ( 64 ) NOT NULL,
+    one_time_prekey VARCHAR ( 64 ),
+    kem_ciphertext TEXT
+);
@@ -1,90 +1,117 @@
 use futures::SinkExt;
-use tokio_stream::StreamExt;
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 
 use chat_core::{
-    event::{AuthenticationStatus, RegistrationStatus},
+    event::{
+        AuthenticationRequest, AuthenticationStatus, Entity, RegistrationRequest,
+        RegistrationStatus,
+    },
     prelude::*,
 };
 
 use crate::handle_connection::Peer;
 
-pub(crate) async fn main(server: &crate::types::Server, peer: &mut Peer) -> Result<()> {
-    let socker_addr = peer.stream_mut().get_ref().peer_addr().map_err(Error::io)?;
-    let recieved = match peer.stream_mut().next().await {
-        Some(Ok(bytes)) => bytes,
-        Some(Err(err)) => {
-            warn!(
-                "error occurred while processing income for {}; error = {:?}",
-                socker_addr, err
-            );
-            return Err(Error::io(err));
-        }
-        // The stream has been exhausted.
-        None => return Ok(()),
-    };
+/// Credentials a client has to present before it may chat.
+enum Request {
+    Registration(String, String),
+    Authentication(String, String),
+}
+
+/// Accepts only registration and authentication requests.
+struct Credentials;
+
+impl EventHandler for Credentials {
+    type Output = Request;
+
+    fn on_registration_request(
+        &mut self,
+        _: &Entity<'_>,
+        event: &RegistrationRequest<'_>,
+    ) -> Result<Request> {
+        let (username, password) = (event.username(), event.password());
+        Ok(Request::Registration(username.into(), password.into()))
+    }
+
+    fn on_authentication_request(
+        &mut self,
+        _: &Entity<'_>,
+        event: &AuthenticationRequest<'_>,
+    ) -> Result<Request> {
+        let (username, password) = (event.username(), event.password());
+        Ok(Request::Authentication(username.into(), password.into()))
+    }
+
+    chat_core::unexpected_events!(
+        on_handshake,
+        on_registration_response,
+        on_authentication_response,
+        on_message,
+        on_error,
+        on_ping,
+        on_pong,
+        on_prekey_upload,
+        on_prekey_request,
+        on_prekey_response,
+        on_session_init,
+        on_key_distribution,
+        on_presence,
+    );
+}
 
-    let event = server.event();
+/// Name of the user the peer logged in or registered as.
+///
+/// # Errors
+///
+/// This function will return [`Error::Timeout`] if the peer doesn't send its
+/// credentials in time.
+pub(crate) async fn main(server: &crate::types::Server, peer: &mut Peer) -> Result<String> {
+    let recieved = chat_core::recieve(peer.stream_mut())
+        .await
+        .on_err(|err| warn!("no credentials from {}; error = {}", peer.addr(), err))?;
+
+    let event = peer.event().clone();
     let crypto = server.crypto();
-    let decrypted = crypto.decrypt(peer.shared_key(), &recieved)?;
-    let deserialized = event.deserialize(&decrypted)?;
+    let deconstructed =
+        EventBuilder::deconstruct(event.clone(), crypto).open(peer.keys_mut(), &recieved)?;
+    let deserialized = deconstructed.deserialize()?;
 
-    match deserialized.kind() {
-        EventKind::Registration(regi) => {
+    let username = match Credentials.dispatch(&deserialized)? {
+        Request::Registration(username, password) => {
             trace!("Processing RegistrationRequest");
-            let req = match regi {
-                chat_core::event::Registration::Request(inner) => inner,
-                chat_core::event::Registration::Response(_) => {
-                    return Err(Error::generic(
-                        "Was expecting client to participate in authentication processs",
-                    ))
-                }
-            };
-            let status = register(server, req.username(), req.password()).await?;
-
-            let event = EventBuilder::construct(server.event().clone(), crypto)
+            let status = register(server, &username, &password).await?;
+
+            let event = EventBuilder::construct(event.clone(), crypto)
                 .registration_response(status)
-                .encrypt(peer.shared_key())?
+                .seal(peer.keys_mut())?
                 .then(|e| bytes::BytesMut::from(e.as_slice()));
 
             peer.stream_mut().send(event).await.map_err(Error::io)?;
 
             if status != RegistrationStatus::Success {
-                return Err(Error::generic(format!("Registration failure: {status}")));
+                return Err(Error::rejected(format!("Registration failure: {status}")));
             }
+            username
         }
-        EventKind::Authentication(auth) => {
+        Request::Authentication(username, password) => {
             trace!("Processing AuthenticationRequest");
-            let req = match auth {
-                chat_core::event::Authentication::Request(inner) => inner,
-                chat_core::event::Authentication::Response(_) => {
-                    return Err(Error::generic(
-                        "Was expecting client to participate in authentication processs",
-                    ))
-                }
-            };
-            let status = authenticate(server, req.username(), req.password()).await?;
-
-            let event = EventBuilder::construct(server.event().clone(), crypto)
+            let status = authenticate(server, &username, &password).await?;
+
+            let event = EventBuilder::construct(event.clone(), crypto)
                 .authentication_response(status)
-                .encrypt(peer.shared_key())?
+                .seal(peer.keys_mut())?
                 .then(|e| bytes::BytesMut::from(e.as_slice()));
 
             peer.stream_mut().send(event).await.map_err(Error::io)?;
 
             if status != AuthenticationStatus::Success {
-                return Err(Error::generic(format!("Authentication failure: {status}")));
+                return Err(Error::rejected(format!("Authentication failure: {status}")));
             }
+            username
         }
-        _ => {
-            return Err(Error::generic(
-                "Was expecting client to participate in authentication processs",
-            ))
-        }
-    }
+    };
 
-    Ok(())
+    Ok(username)
 }
 
 async fn register(
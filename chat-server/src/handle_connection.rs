@@ -1,19 +1,36 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, sync::Arc};
 
 use futures::SinkExt;
 use tokio::{
-    net::TcpStream,
     sync::{mpsc, Mutex},
+    time::{self, Instant},
 };
 use tokio_stream::StreamExt;
-use tokio_util::codec::{BytesCodec, Framed};
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 
-use chat_core::prelude::*;
+use chat_core::{
+    crypto::TransportKeys,
+    event::{
+        Entity, ErrorCode, Handshake, KeyDistribution, Message, Ping, Pong, PrekeyBundle,
+        PrekeyRequest, SessionInit,
+    },
+    keepalive::Keepalive,
+    prelude::*,
+    protocol::Capabilities,
+    transport::{FrameSink, Lane},
+};
+
+use crate::types::PeerAddr;
+
+/// A framed connection to a peer, regardless of the transport it came over.
+pub(crate) type Connection = Box<dyn Frames>;
 
-type Tx = mpsc::UnboundedSender<Vec<u8>>;
-type Rx = mpsc::UnboundedReceiver<Vec<u8>>;
+/// A decoded event relayed between peers. Every peer serializes it with its own
+/// schema.
+type Relayed = Entity<'static>;
+type Tx = mpsc::UnboundedSender<Relayed>;
+type Rx = mpsc::UnboundedReceiver<Relayed>;
 
 /// Data that is shared between all peers in the server.
 ///
@@ -22,30 +39,65 @@ type Rx = mpsc::UnboundedReceiver<Vec<u8>>;
 /// iterating over the `peers` entries and sending a copy of the message on each
 /// `Tx`.
 pub(crate) struct Shared {
-    peers: HashMap<SocketAddr, Tx>,
+    peers: HashMap<PeerAddr, Tx>,
+    /// Where every authenticated user is connected from.
+    users: HashMap<String, PeerAddr>,
 }
 
 impl Shared {
     pub(crate) fn new() -> Self {
         Self {
             peers: HashMap::new(),
+            users: HashMap::new(),
         }
     }
 
+    /// Starts relaying events to `peer` and remembers that `username` is
+    /// connected from it, a later login takes over the earlier one.
+    fn login(&mut self, username: String, peer: &Peer) {
+        self.peers.insert(peer.addr.clone(), peer.tx.clone());
+        self.users.insert(username, peer.addr.clone());
+    }
+
+    /// Forgets the peer at `addr` along with the user it logged in as, which
+    /// is returned unless it has logged in elsewhere since.
+    fn remove(&mut self, addr: &PeerAddr) -> Option<String> {
+        self.peers.remove(addr);
+        let username = self
+            .users
+            .iter()
+            .find_map(|(username, user)| (user == addr).then(|| username.clone()))?;
+        self.users.remove(&username);
+        Some(username)
+    }
+
+    /// Send a message to `username` only. Returns `false` if it isn't online.
+    fn send_to(&self, username: &str, entity: &Relayed) -> bool {
+        self.users
+            .get(username)
+            .and_then(|addr| self.peers.get(addr))
+            .is_some_and(|tx| tx.send(entity.clone()).is_ok())
+    }
+
     /// Send a message to every peer, except for the sender.
-    async fn broadcast(&mut self, sender: &SocketAddr, message: &[u8]) {
+    async fn broadcast(&mut self, sender: &PeerAddr, entity: &Relayed) {
         for peer in self.peers.iter_mut() {
             if peer.0 == sender {
                 continue;
             }
-            let _ = peer.1.send(message.into());
+            let _ = peer.1.send(entity.clone());
         }
     }
 }
 
 /// The state for each connected client.
 pub(crate) struct Peer {
-    stream: Framed<TcpStream, BytesCodec>,
+    stream: Connection,
+    addr: PeerAddr,
+
+    /// Send half of the message channel, handed to [`Shared`] once the peer
+    /// has logged in.
+    tx: Tx,
 
     /// Receive half of the message channel.
     ///
@@ -53,31 +105,47 @@ pub(crate) struct Peer {
     /// off of this `Rx`, it will be written to the socket.
     rx: Rx,
 
-    shared_key: SharedSecret,
+    /// Keys negotiated with the peer, one for each direction.
+    keys: TransportKeys,
+
+    /// Serialization backend negotiated with this peer.
+    event: DynSchema,
+
+    keepalive: Keepalive,
 }
 
 impl Peer {
-    async fn new(
-        state: &Arc<Mutex<Shared>>,
-        stream: Framed<TcpStream, BytesCodec>,
-        shared_key: SharedSecret,
-    ) -> Result<Self> {
-        let socker_addr = stream.get_ref().peer_addr().map_err(Error::io)?;
+    fn new(
+        stream: Connection,
+        addr: PeerAddr,
+        keys: TransportKeys,
+        event: DynSchema,
+        keepalive: Keepalive,
+    ) -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
-        state.lock().await.peers.insert(socker_addr, tx);
 
-        Ok(Self {
+        Self {
             stream,
+            addr,
+            tx,
             rx,
-            shared_key,
-        })
+            keys,
+            event,
+            keepalive,
+        }
     }
 
-    pub(crate) fn stream_mut(&mut self) -> &mut Framed<TcpStream, BytesCodec> {
+    pub(crate) fn stream_mut(&mut self) -> &mut Connection {
         &mut self.stream
     }
-    pub(crate) const fn shared_key(&self) -> &CryptoKey {
-        &self.shared_key
+    pub(crate) const fn addr(&self) -> &PeerAddr {
+        &self.addr
+    }
+    pub(crate) fn keys_mut(&mut self) -> &mut TransportKeys {
+        &mut self.keys
+    }
+    pub(crate) const fn event(&self) -> &DynSchema {
+        &self.event
     }
 }
 
@@ -85,37 +153,114 @@ impl Peer {
 pub(crate) async fn process(
     server: crate::types::Server,
     state: Arc<Mutex<Shared>>,
-    tcp_stream: TcpStream,
-    addr: SocketAddr,
+    mut stream: Connection,
+    addr: PeerAddr,
 ) -> Result<()> {
-    let mut stream = Framed::new(tcp_stream, BytesCodec::new());
-
-    let shared_key =
-        chat_core::key_exchange(&mut stream, server.event().clone(), server.crypto()).await?;
-    info!("Shared secret with {} was negotiated", addr);
+    let negotiated = chat_core::handshake::respond(
+        &mut stream,
+        Capabilities::supported(),
+        server.crypto(),
+        server.identity(),
+    )
+    .await?;
+    info!(
+        "Keys with {} were negotiated; protocol version = {}, event schema = {:?}",
+        addr,
+        negotiated.version(),
+        negotiated.event()
+    );
     debug!(
         address = addr.to_string(),
-        SharedSecret = chat_core::crypto::key_to_emojies(&shared_key)
+        capabilities = ?negotiated.capabilities(),
+        identity = %negotiated.remote_identity()
     );
 
-    let mut peer = Peer::new(&state, stream, shared_key).await?;
+    let mut peer = Peer::new(
+        stream,
+        addr.clone(),
+        negotiated.keys().clone(),
+        negotiated.event().clone(),
+        server.keepalive().clone(),
+    );
 
-    crate::authentication::main(&server, &mut peer).await?;
-    info!("{} authenticated", addr);
+    let result = serve(&server, &state, &mut peer).await;
+    if let Err(ref err) = result {
+        report(&server, &mut peer, err).await;
+    }
+
+    // If this section is reached it means that the client was disconnected!
+    {
+        let mut state = state.lock().await;
+        if let Some(username) = state.remove(&addr) {
+            let presence = peer.event().construct_presence(&username, false);
+            state.broadcast(&addr, &presence.into_owned()).await;
+        }
+    }
+
+    result
+}
+
+async fn serve(
+    server: &crate::types::Server,
+    state: &Arc<Mutex<Shared>>,
+    peer: &mut Peer,
+) -> Result<()> {
+    let addr = peer.addr().clone();
+    let username = crate::authentication::main(server, peer).await?;
+    info!("{} authenticated as {}", addr, username);
+    state.lock().await.login(username.clone(), peer);
+
+    for init in crate::prekeys::take_inits(server, &username).await? {
+        debug!(
+            "delivering a session init from {} to {}",
+            init.sender(),
+            addr
+        );
+        let lane = Lane::direct(init.sender(), init.recipient());
+        let event = EventBuilder::construct(peer.event().clone(), server.crypto())
+            .session_init(init)
+            .seal(peer.keys_mut())?;
+        peer.stream.route(lane);
+        send(peer, &event).await?;
+    }
+    let presence = peer.event().construct_presence(&username, true);
+    state
+        .lock()
+        .await
+        .broadcast(&addr, &presence.into_owned())
+        .await;
+
+    let period = peer.keepalive.interval();
+    let mut pings = time::interval_at(Instant::now() + period, period);
 
     // Process incoming messages until our stream is exhausted by a disconnect.
     loop {
         tokio::select! {
+            // Time to check whether the peer is still there.
+            _ = pings.tick() => {
+                let nonce = peer.keepalive.ping().on_err(|_| {
+                    warn!("{} stopped answering pings", addr);
+                })?;
+                let event = EventBuilder::construct(peer.event().clone(), server.crypto())
+                    .ping(nonce)
+                    .seal(peer.keys_mut())?;
+                send(peer, &event).await?;
+            }
             // A message was received from some peer. Send it to the current peer.
-            Some(msg) = peer.rx.recv() => {
-                let msg = CryptoSchema::encrypt(&server.crypto(), &peer.shared_key, &msg)?
+            Some(entity) = peer.rx.recv() => {
+                trace!("relaying event {} to {}", entity.id(), addr);
+                let lane = Lane::of(&entity);
+                let msg = EventBuilder::construct(peer.event().clone(), server.crypto())
+                    .entity(entity)
+                    .seal(&mut peer.keys)?
                     .then(|e| bytes::BytesMut::from(e.as_slice()));
+                peer.stream.route(lane);
                 peer.stream.send(msg).await.map_err(Error::io)?;
             }
             result = peer.stream.next() => match result {
                 // A message was received from the current peer.
                 Some(Ok(bytes)) => {
-                    if let Err(err) = on_recieve_from_curr_peer(&server, &state, &mut peer, bytes).await {
+                    if let Err(err) = on_recieve_from_curr_peer(server, state, peer, &username, bytes).await {
                         warn!("error occurred while working with recieved data for {}; error = {}", addr, err);
                     }
                 },
@@ -129,32 +274,203 @@ pub(crate) async fn process(
         }
     }
 
-    // If this section is reached it means that the client was disconnected!
-    {
-        let mut state = state.lock().await;
-        state.peers.remove(&addr);
+    Ok(())
+}
+
+/// Tells the peer why its connection is going to be closed. Only errors the
+/// peer caused are described, internal ones could give away database or
+/// driver details.
+///
+/// Nothing is sent if the connection itself is broken or the peer was already
+/// told why it was rejected.
+async fn report(server: &crate::types::Server, peer: &mut Peer, err: &Error) {
+    if matches!(err, Error::IO(_) | Error::Rejected(_) | Error::Shutdown) {
+        return;
     }
 
-    Ok(())
+    let code = ErrorCode::from(err);
+    let text = match code {
+        ErrorCode::Internal => "internal error".to_owned(),
+        _ => err.to_string(),
+    };
+    let event = EventBuilder::construct(peer.event().clone(), server.crypto())
+        .error(code, &text)
+        .seal(peer.keys_mut());
+    let sent = match event {
+        Ok(event) => send(peer, &event).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = sent {
+        debug!("failed to report an error to the peer; error = {}", err);
+    }
 }
 
 async fn on_recieve_from_curr_peer(
     server: &crate::types::Server,
     state: &Arc<Mutex<Shared>>,
     peer: &mut Peer,
+    username: &str,
     recieved: bytes::BytesMut,
 ) -> Result<()> {
-    let event = server.event();
-    let decrypted = server.crypto().decrypt(peer.shared_key(), &recieved)?;
-    let deserialized = event.deserialize(&decrypted)?;
-
-    match deserialized.kind() {
-        EventKind::Registration(_) | EventKind::Authentication(_) => (),
-        EventKind::Message(_) | EventKind::Handshake(_) => {
-            let socker_addr = peer.stream_mut().get_ref().peer_addr().map_err(Error::io)?;
-            state.lock().await.broadcast(&socker_addr, &decrypted).await;
+    let deserialized = EventBuilder::deconstruct(peer.event().clone(), server.crypto())
+        .open(&mut peer.keys, &recieved)?
+        .into_entity()?;
+    let id = *deserialized.id();
+
+    match Incoming.dispatch(&deserialized)? {
+        Action::Broadcast => {
+            debug!("broadcasting event {} from {}", id, peer.addr());
+            state
+                .lock()
+                .await
+                .broadcast(peer.addr(), &deserialized)
+                .await;
+        }
+        Action::Pong(nonce) => {
+            let event = EventBuilder::construct(peer.event().clone(), server.crypto())
+                .pong(nonce)
+                .seal(peer.keys_mut())?;
+            send(peer, &event).await?;
         }
+        Action::Alive(nonce) => {
+            if let Some(latency) = peer.keepalive.pong(nonce) {
+                trace!("pong {} in {:?}", nonce, latency);
+            }
+        }
+        Action::Publish(bundle) => {
+            bundle.verify(&server.crypto())?;
+            crate::prekeys::publish(server, username, &bundle).await?;
+            debug!(
+                "{} published {} one-time prekeys",
+                username,
+                bundle.one_time_prekeys().len()
+            );
+        }
+        Action::Fetch(owner) => {
+            let bundle = crate::prekeys::fetch(server, &owner).await?;
+            let event = EventBuilder::construct(peer.event().clone(), server.crypto())
+                .prekey_response(&owner, bundle)
+                .seal(peer.keys_mut())?;
+            send(peer, &event).await?;
+        }
+        Action::Forward(init) => {
+            if init.sender() != username {
+                return Err(Error::unexpected(format!(
+                    "session init on behalf of {}",
+                    init.sender()
+                )));
+            }
+            if !state.lock().await.send_to(init.recipient(), &deserialized) {
+                debug!("{} is offline, keeping the session init", init.recipient());
+                crate::prekeys::store_init(server, &init).await?;
+            }
+        }
+        Action::Deliver(distribution) => {
+            if distribution.sender() != username {
+                return Err(Error::unexpected(format!(
+                    "key distribution on behalf of {}",
+                    distribution.sender()
+                )));
+            }
+            if !state
+                .lock()
+                .await
+                .send_to(distribution.recipient(), &deserialized)
+            {
+                debug!(
+                    "{} is offline, dropping the key distribution",
+                    distribution.recipient()
+                );
+            }
+        }
+        Action::Ignore => (),
     }
 
     Ok(())
 }
+
+async fn send(peer: &mut Peer, event: &[u8]) -> Result<()> {
+    let event = bytes::BytesMut::from(event);
+    peer.stream_mut().send(event).await.map_err(Error::io)
+}
+
+/// What to do with an event received from an authenticated peer.
+enum Action {
+    /// Relay the event to every other peer.
+    Broadcast,
+    /// Answer a ping with the given nonce.
+    Pong(u64),
+    /// The peer answered the ping with the given nonce.
+    Alive(u64),
+    /// Replace the peer's prekey bundle.
+    Publish(PrekeyBundle),
+    /// Answer with the bundle of the given user.
+    Fetch(String),
+    /// Relay the event to its recipient, or keep it until they're online.
+    Forward(SessionInit<'static>),
+    /// Relay the event to its recipient if they're online.
+    Deliver(KeyDistribution<'static>),
+    Ignore,
+}
+
+/// Decides what to do with events received from an authenticated peer.
+struct Incoming;
+
+impl EventHandler for Incoming {
+    type Output = Action;
+
+    fn on_handshake(&mut self, _: &Entity<'_>, _: &Handshake) -> Result<Action> {
+        Ok(Action::Broadcast)
+    }
+
+    fn on_message(&mut self, _: &Entity<'_>, _: &Message<'_>) -> Result<Action> {
+        Ok(Action::Broadcast)
+    }
+
+    fn on_ping(&mut self, _: &Entity<'_>, event: &Ping) -> Result<Action> {
+        Ok(Action::Pong(*event.nonce()))
+    }
+
+    fn on_pong(&mut self, _: &Entity<'_>, event: &Pong) -> Result<Action> {
+        Ok(Action::Alive(*event.nonce()))
+    }
+
+    fn on_prekey_upload(&mut self, _: &Entity<'_>, event: &PrekeyBundle) -> Result<Action> {
+        Ok(Action::Publish(event.clone()))
+    }
+
+    fn on_prekey_request(&mut self, _: &Entity<'_>, event: &PrekeyRequest<'_>) -> Result<Action> {
+        Ok(Action::Fetch(event.username().to_owned()))
+    }
+
+    fn on_session_init(&mut self, _: &Entity<'_>, event: &SessionInit<'_>) -> Result<Action> {
+        Ok(Action::Forward(event.clone().into_owned()))
+    }
+
+    fn on_key_distribution(
+        &mut self,
+        _: &Entity<'_>,
+        event: &KeyDistribution<'_>,
+    ) -> Result<Action> {
+        Ok(Action::Deliver(event.clone().into_owned()))
+    }
+
+    chat_core::unexpected_events!(
+        on_registration_request,
+        on_registration_response,
+        on_authentication_request,
+        on_authentication_response,
+        on_error,
+        on_prekey_response,
+        on_presence,
+    );
+
+    fn unexpected(&mut self, entity: &Entity<'_>) -> Result<Action> {
+        debug!(
+            "ignoring {} {} outside of authentication",
+            entity.kind().name(),
+            entity.id()
+        );
+        Ok(Action::Ignore)
+    }
+}
@@ -8,7 +8,18 @@
     unreachable_pub
 )]
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{future::Future, net::SocketAddr, sync::Arc};
+
+use chat_core::{
+    crypto::{self, KeyPair},
+    error::Error,
+    keepalive::Keepalive,
+    quic,
+    tls::{self, ServerConfig, TlsAcceptor},
+    transport::{framed, Transport},
+    websocket,
+};
+use color_eyre::eyre::bail;
 
 use tokio::{net::TcpListener, sync::Mutex};
 #[allow(unused_imports)]
@@ -16,7 +27,12 @@ use tracing::{debug, error, info, trace, warn};
 
 mod authentication;
 mod handle_connection;
+mod prekeys;
 mod types;
+mod unix;
+
+use handle_connection::Connection;
+use types::PeerAddr;
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
@@ -24,30 +40,181 @@ async fn main() -> color_eyre::Result<()> {
 
     let state = Arc::new(Mutex::new(handle_connection::Shared::new()));
 
-    let addr: SocketAddr = std::env::var("ADDRESS")
-        .expect("Environment variable `ADDRESS` must be set.")
-        .parse()?;
-    let listener = TcpListener::bind(&addr).await?;
+    let tls = tls()?;
 
-    info!("Server is running on {}", addr);
+    // Every listener is optional, but at least one has to be configured.
+    let tcp = match std::env::var("ADDRESS") {
+        Ok(addr) => Some(bind(&addr, "TCP").await?),
+        Err(_) => None,
+    };
+    let websocket = match std::env::var("WS_ADDRESS") {
+        Ok(addr) => Some(bind(&addr, "WebSocket").await?),
+        Err(_) => None,
+    };
+    let quic = match (std::env::var("QUIC_ADDRESS"), &tls) {
+        (Ok(addr), Some(tls)) => {
+            let endpoint = quic::server(addr.parse()?, tls.clone())?;
+            info!("QUIC server is running on {}", addr);
+            Some(endpoint)
+        }
+        (Ok(_), None) => bail!("QUIC needs `TLS_CERT` and `TLS_KEY` to be set."),
+        (Err(_), _) => None,
+    };
+    let tls = tls.map(|config| TlsAcceptor::from(Arc::new(config)));
+    if tls.is_some() {
+        info!("TCP and WebSocket connections are wrapped in TLS");
+    }
+    let unix = match std::env::var("UNIX_SOCKET") {
+        Ok(path) => {
+            let mode = match std::env::var("UNIX_SOCKET_MODE") {
+                Ok(mode) => u32::from_str_radix(&mode, 8)?,
+                Err(_) => unix::DEFAULT_MODE,
+            };
+            let listener = unix::bind(&path, mode)?;
+            info!("Server is running on unix:{} (mode {:o})", path, mode);
+            Some(listener)
+        }
+        Err(_) => None,
+    };
+    if tcp.is_none() && websocket.is_none() && quic.is_none() && unix.is_none() {
+        bail!(
+            "Environment variable `ADDRESS`, `WS_ADDRESS`, `QUIC_ADDRESS` or `UNIX_SOCKET` must be set."
+        );
+    }
 
     let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set.");
     let db_pool = sqlx::PgPool::connect(&db_url).await?;
-    let server = types::Server::new(db_pool);
+    let server = types::Server::new(db_pool, Keepalive::from_env()?, identity()?);
+
+    tokio::try_join!(
+        accept_tcp(tcp, Listener::Tcp, tls.clone(), &server, &state),
+        accept_tcp(websocket, Listener::WebSocket, tls, &server, &state),
+        accept_quic(quic, &server, &state),
+        unix::accept(unix, &server, &state),
+    )?;
+    Ok(())
+}
+
+async fn bind(addr: &str, kind: &str) -> color_eyre::Result<TcpListener> {
+    let addr: SocketAddr = addr.parse()?;
+    let listener = TcpListener::bind(&addr).await?;
+    info!("{} server is running on {}", kind, addr);
+    Ok(listener)
+}
+
+/// What is spoken over an accepted TCP connection.
+#[derive(Debug, Clone, Copy)]
+enum Listener {
+    /// Events framed with [`chat_core::codec::FrameCodec`].
+    Tcp,
+    /// One event per binary WebSocket message.
+    WebSocket,
+}
 
+async fn accept_tcp(
+    listener: Option<TcpListener>,
+    kind: Listener,
+    tls: Option<TlsAcceptor>,
+    server: &types::Server,
+    state: &Arc<Mutex<handle_connection::Shared>>,
+) -> color_eyre::Result<()> {
+    let Some(listener) = listener else {
+        return Ok(());
+    };
     loop {
         let (stream, addr) = listener.accept().await?;
-        let server = server.clone();
-        let state = Arc::clone(&state);
+        let tls = tls.clone();
+        let connection = async move {
+            let transport: Box<dyn Transport> = match tls {
+                Some(acceptor) => Box::new(acceptor.accept(stream).await.map_err(Error::io)?),
+                None => Box::new(stream),
+            };
+            let connection: Connection = match kind {
+                Listener::Tcp => framed(transport),
+                Listener::WebSocket => Box::new(websocket::accept(transport).await?),
+            };
+            Ok(connection)
+        };
+        let addr = match kind {
+            Listener::Tcp => PeerAddr::Tcp(addr),
+            Listener::WebSocket => PeerAddr::WebSocket(addr),
+        };
+        spawn_peer(server, state, connection, addr);
+    }
+}
 
-        tokio::spawn(async move {
-            info!("Accepted connection from: {}", addr);
-            if let Err(e) = handle_connection::process(server, state, stream, addr).await {
-                warn!("error while processing {}; error = {:?}", addr, e);
-            }
-            info!("Connection closed: {}", &addr);
-        });
+async fn accept_quic(
+    endpoint: Option<quic::Endpoint>,
+    server: &types::Server,
+    state: &Arc<Mutex<handle_connection::Shared>>,
+) -> color_eyre::Result<()> {
+    let Some(endpoint) = endpoint else {
+        return Ok(());
+    };
+    while let Some(incoming) = endpoint.accept().await {
+        let addr = PeerAddr::Quic(incoming.remote_address());
+        let connection = async move {
+            let connection: Connection = Box::new(quic::accept(incoming).await?);
+            Ok(connection)
+        };
+        spawn_peer(server, state, connection, addr);
     }
+    Ok(())
+}
+
+/// Serves a freshly accepted connection in its own task.
+///
+/// `connection` finishes setting up the connection, e.g. the TLS handshake, so
+/// that a slow peer doesn't hold up the accept loop.
+fn spawn_peer<F>(
+    server: &types::Server,
+    state: &Arc<Mutex<handle_connection::Shared>>,
+    connection: F,
+    addr: PeerAddr,
+) where
+    F: Future<Output = chat_core::error::Result<Connection>> + Send + 'static,
+{
+    let server = server.clone();
+    let state = Arc::clone(state);
+
+    tokio::spawn(async move {
+        info!("Accepted connection from: {}", addr);
+        let result = match connection.await {
+            Ok(connection) => {
+                handle_connection::process(server, state, connection, addr.clone()).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("error while processing {}; error = {:?}", addr, e);
+        }
+        info!("Connection closed: {}", &addr);
+    });
+}
+
+/// Reads `TLS_CERT` and `TLS_KEY`. TLS is off if neither is set.
+fn tls() -> color_eyre::Result<Option<ServerConfig>> {
+    let (cert, key) = match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        (Ok(cert), Ok(key)) => (cert, key),
+        (Err(_), Err(_)) => return Ok(None),
+        _ => bail!("Both `TLS_CERT` and `TLS_KEY` have to be set for TLS."),
+    };
+    let config = tls::server_config(tls::load_certs(cert)?, tls::load_key(key)?)?;
+    Ok(Some(config))
+}
+
+/// Reads the identity key at `IDENTITY_KEY`, generates one on the first start.
+fn identity() -> color_eyre::Result<KeyPair> {
+    let path = std::env::var("IDENTITY_KEY").unwrap_or_else(|_| "server_identity.key".to_owned());
+    let identity = match crypto::load_identity(&path)? {
+        Some(identity) => identity,
+        None => {
+            warn!("Generating a new identity key at {}", path);
+            crypto::generate_identity(&path)?
+        }
+    };
+    info!("Server identity: {}", identity.public());
+    Ok(identity)
 }
 
 fn setup() -> color_eyre::Result<()> {
@@ -0,0 +1,175 @@
+//! Prekey bundles and the sessions started from them, kept until their owner
+//! is online
+
+use chat_core::{
+    crypto::{EncapsulationKey, KemCiphertext},
+    event::{KemPrekey, PrekeyBundle, SessionInit},
+    prelude::*,
+};
+
+/// Replaces the bundle of `login`.
+pub(crate) async fn publish(
+    server: &crate::types::Server,
+    login: &str,
+    bundle: &PrekeyBundle,
+) -> Result<()> {
+    let mut tx = server.db_pool().begin().await.map_err(Error::generic)?;
+
+    let user_id = sqlx::query!("SELECT user_id FROM accounts WHERE login = $1", login)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(Error::generic)?
+        .user_id;
+
+    sqlx::query!(
+        "INSERT INTO prekeys
+        ( user_id, identity_key, signed_prekey, signature, kem_prekey, kem_signature )
+        VALUES ( $1, $2, $3, $4, $5, $6 )
+        ON CONFLICT ( user_id ) DO UPDATE
+        SET identity_key = $2, signed_prekey = $3, signature = $4,
+            kem_prekey = $5, kem_signature = $6",
+        user_id,
+        bundle.identity_key().encode(),
+        bundle.signed_prekey().encode(),
+        bundle.signature().encode(),
+        bundle.kem_prekey().map(|prekey| prekey.key().encode()),
+        bundle
+            .kem_prekey()
+            .map(|prekey| prekey.signature().encode()),
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(Error::generic)?;
+
+    sqlx::query!("DELETE FROM one_time_prekeys WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::generic)?;
+    for prekey in bundle.one_time_prekeys() {
+        sqlx::query!(
+            "INSERT INTO one_time_prekeys ( user_id, public_key ) VALUES ( $1, $2 )",
+            user_id,
+            prekey.encode(),
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(Error::generic)?;
+    }
+
+    tx.commit().await.map_err(Error::generic)
+}
+
+/// Bundle of `login` with at most one one-time prekey, which is never handed
+/// out again. `None` if the user hasn't published a bundle.
+pub(crate) async fn fetch(
+    server: &crate::types::Server,
+    login: &str,
+) -> Result<Option<PrekeyBundle>> {
+    let mut tx = server.db_pool().begin().await.map_err(Error::generic)?;
+
+    let row = sqlx::query!(
+        "SELECT prekeys.user_id, identity_key, signed_prekey, signature,
+            kem_prekey, kem_signature
+        FROM prekeys JOIN accounts ON prekeys.user_id = accounts.user_id
+        WHERE login = $1",
+        login
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(Error::generic)?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let one_time_prekey = sqlx::query!(
+        "DELETE FROM one_time_prekeys WHERE prekey_id = (
+            SELECT prekey_id FROM one_time_prekeys WHERE user_id = $1
+            ORDER BY prekey_id LIMIT 1 FOR UPDATE SKIP LOCKED
+        ) RETURNING public_key",
+        row.user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(Error::generic)?
+    .map(|prekey| PublicKey::try_decode(prekey.public_key))
+    .transpose()?;
+
+    tx.commit().await.map_err(Error::generic)?;
+
+    let kem_prekey = match (row.kem_prekey, row.kem_signature) {
+        (Some(key), Some(signature)) => Some(KemPrekey::new(
+            EncapsulationKey::try_decode(key)?,
+            Signature::try_decode(signature)?,
+        )),
+        _ => None,
+    };
+    Ok(Some(PrekeyBundle::new(
+        PublicKey::try_decode(row.identity_key)?,
+        PublicKey::try_decode(row.signed_prekey)?,
+        Signature::try_decode(row.signature)?,
+        one_time_prekey.into_iter().collect(),
+        kem_prekey,
+    )))
+}
+
+/// Keeps `init` until its recipient is online.
+pub(crate) async fn store_init(
+    server: &crate::types::Server,
+    init: &SessionInit<'_>,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO session_inits
+        ( recipient_id, sender, identity_key, ephemeral_key, signed_prekey, one_time_prekey,
+          kem_ciphertext )
+        SELECT user_id, $2, $3, $4, $5, $6, $7 FROM accounts WHERE login = $1",
+        init.recipient(),
+        init.sender(),
+        init.identity_key().encode(),
+        init.ephemeral_key().encode(),
+        init.signed_prekey().encode(),
+        init.one_time_prekey().map(Encodable::encode),
+        init.kem_ciphertext().map(Encodable::encode),
+    )
+    .execute(server.db_pool())
+    .await
+    .map_err(Error::generic)?;
+    Ok(())
+}
+
+/// Sessions started with `login` while it was offline, oldest first. They are
+/// forgotten once taken.
+pub(crate) async fn take_inits(
+    server: &crate::types::Server,
+    login: &str,
+) -> Result<Vec<SessionInit<'static>>> {
+    let mut rows = sqlx::query!(
+        "DELETE FROM session_inits WHERE recipient_id = (
+            SELECT user_id FROM accounts WHERE login = $1
+        ) RETURNING init_id, sender, identity_key, ephemeral_key, signed_prekey, one_time_prekey,
+            kem_ciphertext",
+        login
+    )
+    .fetch_all(server.db_pool())
+    .await
+    .map_err(Error::generic)?;
+    rows.sort_by_key(|row| row.init_id);
+
+    rows.into_iter()
+        .map(|row| {
+            let one_time_prekey = row.one_time_prekey.map(PublicKey::try_decode).transpose()?;
+            let kem_ciphertext = row
+                .kem_ciphertext
+                .map(KemCiphertext::try_decode)
+                .transpose()?;
+            Ok(SessionInit::new(
+                row.sender.into(),
+                login.to_owned().into(),
+                PublicKey::try_decode(row.identity_key)?,
+                PublicKey::try_decode(row.ephemeral_key)?,
+                PublicKey::try_decode(row.signed_prekey)?,
+                one_time_prekey,
+                kem_ciphertext,
+            ))
+        })
+        .collect()
+}
@@ -1,28 +1,59 @@
-use chat_core::prelude::*;
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use chat_core::{keepalive::Keepalive, prelude::*};
 
 #[derive(Clone)]
 pub(crate) struct Server {
-    event: Capnp,
     crypto: Crypto,
     db_pool: sqlx::PgPool,
+    /// Settings every peer's keepalive starts with.
+    keepalive: Keepalive,
+    /// Signs every handshake, clients pin its public half.
+    identity: Arc<KeyPair>,
 }
 
 impl Server {
-    pub(crate) fn new(db_pool: sqlx::PgPool) -> Self {
+    pub(crate) fn new(db_pool: sqlx::PgPool, keepalive: Keepalive, identity: KeyPair) -> Self {
         Self {
-            event: Capnp::default(),
             crypto: Crypto::default(),
             db_pool,
+            keepalive,
+            identity: Arc::new(identity),
         }
     }
 
-    pub(crate) const fn event(&self) -> &Capnp {
-        &self.event
-    }
     pub(crate) const fn crypto(&self) -> Crypto {
         self.crypto
     }
     pub(crate) const fn db_pool(&self) -> &sqlx::PgPool {
         &self.db_pool
     }
+    pub(crate) const fn keepalive(&self) -> &Keepalive {
+        &self.keepalive
+    }
+    pub(crate) fn identity(&self) -> &KeyPair {
+        &self.identity
+    }
+}
+
+/// Where a peer is connected from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Unix socket peers are unnamed, so they are told apart by the order
+    /// they connected in.
+    Unix(u64),
+    WebSocket(SocketAddr),
+    Quic(SocketAddr),
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(n) => write!(f, "unix#{n}"),
+            Self::WebSocket(addr) => write!(f, "ws://{addr}"),
+            Self::Quic(addr) => write!(f, "quic://{addr}"),
+        }
+    }
 }
@@ -0,0 +1,80 @@
+//! Listening on a Unix domain socket
+//!
+//! Access is controlled with the permissions of the socket file, so only local
+//! users and groups allowed by its mode can connect.
+
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use chat_core::{transport::framed, OnErr};
+use tokio::{net::UnixListener, sync::Mutex};
+
+use crate::{handle_connection::Shared, types::PeerAddr};
+
+/// Owner and group may connect, others may not.
+pub(crate) const DEFAULT_MODE: u32 = 0o660;
+
+/// Binds a listener at `path` and restricts access to the socket with `mode`.
+///
+/// A socket left behind by a previous run is replaced, any other file at
+/// `path` is an error.
+///
+/// The socket is bound inside a private directory next to `path` and only
+/// moved into place once it has its final mode, so nobody can connect while
+/// it still has the default one.
+pub(crate) fn bind(path: impl AsRef<Path>, mode: u32) -> color_eyre::Result<UnixListener> {
+    let path = path.as_ref();
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            color_eyre::eyre::bail!("{} exists and is not a socket", path.display());
+        }
+        fs::remove_file(path)?;
+    }
+
+    let Some(name) = path.file_name() else {
+        color_eyre::eyre::bail!("{} is not a file path", path.display());
+    };
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("socket");
+    let bound = UnixListener::bind(&staged)
+        .and_then(|listener| {
+            fs::set_permissions(&staged, Permissions::from_mode(mode))?;
+            fs::rename(&staged, path)?;
+            Ok(listener)
+        })
+        .on_err(|_| {
+            let _ = fs::remove_file(&staged);
+        });
+    fs::remove_dir(&private)?;
+    Ok(bound?)
+}
+
+pub(crate) async fn accept(
+    listener: Option<UnixListener>,
+    server: &crate::types::Server,
+    state: &Arc<Mutex<Shared>>,
+) -> color_eyre::Result<()> {
+    static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+    let Some(listener) = listener else {
+        return Ok(());
+    };
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let addr = PeerAddr::Unix(CONNECTIONS.fetch_add(1, Ordering::Relaxed));
+        let connection = async move { Ok(framed(stream)) };
+        crate::spawn_peer(server, state, connection, addr);
+    }
+}